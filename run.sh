@@ -17,5 +17,6 @@ echo "Starting application..."
 docker-compose down --volumes
 docker-compose up -d
 
-echo "Running database migrations..."
-docker-compose exec app diesel migration run
+# Migrations run automatically when the app starts; this shows what was applied
+echo "Database migration status:"
+docker-compose exec app todo_backend_server migrate status
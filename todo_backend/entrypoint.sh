@@ -1,9 +1,9 @@
 #!/bin/sh
 set -e
 
-# Run database migrations
+# Run database migrations (embedded in the binary)
 echo "Running database migrations..."
-/usr/local/bin/diesel migration run
+/usr/local/bin/todo_backend_server migrate up
 
 # Start the main application
 echo "Starting application..."
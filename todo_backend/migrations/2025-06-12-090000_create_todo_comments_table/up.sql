@@ -0,0 +1,17 @@
+CREATE TABLE todo_comments (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    todo_item_id UUID NOT NULL REFERENCES todo_items(id) ON DELETE CASCADE,
+    author_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    body TEXT NOT NULL,
+    edited BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    deleted_at TIMESTAMP
+);
+
+CREATE INDEX idx_todo_comments_todo_item_id ON todo_comments(todo_item_id);
+
+CREATE TRIGGER update_todo_comments_updated_at
+BEFORE UPDATE ON todo_comments
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at_column();
@@ -0,0 +1,4 @@
+ALTER TABLE todo_items ADD COLUMN deleted_at TIMESTAMP;
+
+-- The purge job scans trashed items by age
+CREATE INDEX idx_todo_items_deleted_at ON todo_items(deleted_at) WHERE deleted_at IS NOT NULL;
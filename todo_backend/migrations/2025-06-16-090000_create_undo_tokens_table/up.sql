@@ -0,0 +1,13 @@
+-- No foreign key to todo_items: the item may be purged before the token expires.
+CREATE TABLE undo_tokens (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    todo_item_id UUID NOT NULL,
+    action VARCHAR(32) NOT NULL,
+    previous JSONB NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP
+);
+
+CREATE INDEX idx_undo_tokens_expires_at ON undo_tokens(expires_at);
@@ -0,0 +1,2 @@
+DROP TRIGGER IF EXISTS notify_todo_item_change ON todo_items;
+DROP FUNCTION IF EXISTS notify_todo_item_change();
@@ -0,0 +1,37 @@
+-- Announces every change to a todo item on the `todo_item_changes` channel so
+-- each app instance can forward it to its event streams. Notifications are
+-- only delivered once the writing transaction commits. Purges are not
+-- announced; the item already left the list when it was trashed.
+CREATE OR REPLACE FUNCTION notify_todo_item_change()
+RETURNS TRIGGER AS $$
+DECLARE
+    action TEXT;
+    payload TEXT;
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        action := 'created';
+    ELSIF OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN
+        action := 'deleted';
+    ELSIF OLD.deleted_at IS NOT NULL AND NEW.deleted_at IS NULL THEN
+        action := 'restored';
+    ELSIF NOT OLD.completed AND NEW.completed THEN
+        action := 'completed';
+    ELSE
+        action := 'updated';
+    END IF;
+
+    payload := json_build_object('action', action, 'item', row_to_json(NEW))::text;
+    -- NOTIFY payloads are limited to 8000 bytes; long items are sent by id
+    -- and loaded by the listener instead
+    IF octet_length(payload) >= 8000 THEN
+        payload := json_build_object('action', action, 'id', NEW.id)::text;
+    END IF;
+    PERFORM pg_notify('todo_item_changes', payload);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER notify_todo_item_change
+AFTER INSERT OR UPDATE ON todo_items
+FOR EACH ROW
+EXECUTE FUNCTION notify_todo_item_change();
@@ -0,0 +1,9 @@
+DROP TRIGGER IF EXISTS record_todo_item_tombstone ON todo_items;
+DROP FUNCTION IF EXISTS record_todo_item_tombstone();
+DROP TRIGGER IF EXISTS set_todo_item_change_seq ON todo_items;
+DROP FUNCTION IF EXISTS set_todo_item_change_seq();
+DROP FUNCTION IF EXISTS next_todo_item_change_seq(UUID);
+DROP TABLE IF EXISTS todo_item_tombstones;
+DROP INDEX IF EXISTS idx_todo_items_user_change_seq;
+ALTER TABLE todo_items DROP COLUMN IF EXISTS change_seq;
+DROP SEQUENCE IF EXISTS todo_item_change_seq;
@@ -0,0 +1,57 @@
+-- Every write to a todo item takes the next value of this sequence, which
+-- serves as the change token of the sync protocol (`GET /api/v1/sync`)
+CREATE SEQUENCE todo_item_change_seq;
+
+ALTER TABLE todo_items ADD COLUMN change_seq BIGINT NOT NULL DEFAULT nextval('todo_item_change_seq');
+CREATE INDEX idx_todo_items_user_change_seq ON todo_items(user_id, change_seq);
+
+-- Items removed for good, so clients learn about it on their next sync
+CREATE TABLE todo_item_tombstones (
+    todo_item_id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    change_seq BIGINT NOT NULL,
+    deleted_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX idx_todo_item_tombstones_user_change_seq ON todo_item_tombstones(user_id, change_seq);
+
+-- Sequence values are taken in statement order but become visible in commit
+-- order. Writes to one user's items are serialized until commit, so a sync
+-- that returned change N never misses a later commit of one below N.
+CREATE OR REPLACE FUNCTION next_todo_item_change_seq(owner UUID)
+RETURNS BIGINT AS $$
+BEGIN
+    PERFORM pg_advisory_xact_lock(hashtext('todo_item_changes'), hashtext(owner::text));
+    RETURN nextval('todo_item_change_seq');
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION set_todo_item_change_seq()
+RETURNS TRIGGER AS $$
+BEGIN
+    NEW.change_seq := next_todo_item_change_seq(NEW.user_id);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER set_todo_item_change_seq
+BEFORE INSERT OR UPDATE ON todo_items
+FOR EACH ROW
+EXECUTE FUNCTION set_todo_item_change_seq();
+
+-- Not for items deleted along with their owner, who has no one left to sync
+CREATE OR REPLACE FUNCTION record_todo_item_tombstone()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM users WHERE id = OLD.user_id) THEN
+        INSERT INTO todo_item_tombstones (todo_item_id, user_id, change_seq)
+        VALUES (OLD.id, OLD.user_id, next_todo_item_change_seq(OLD.user_id))
+        ON CONFLICT (todo_item_id) DO UPDATE SET change_seq = EXCLUDED.change_seq, deleted_at = EXCLUDED.deleted_at;
+    END IF;
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER record_todo_item_tombstone
+AFTER DELETE ON todo_items
+FOR EACH ROW
+EXECUTE FUNCTION record_todo_item_tombstone();
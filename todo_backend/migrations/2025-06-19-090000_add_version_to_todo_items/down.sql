@@ -0,0 +1,3 @@
+DROP TRIGGER IF EXISTS bump_todo_item_version ON todo_items;
+DROP FUNCTION IF EXISTS bump_todo_item_version();
+ALTER TABLE todo_items DROP COLUMN IF EXISTS version;
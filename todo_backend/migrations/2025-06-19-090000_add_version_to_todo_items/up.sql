@@ -0,0 +1,15 @@
+-- Counts the writes to an item; its ETag, checked by `If-Match`
+ALTER TABLE todo_items ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+
+CREATE OR REPLACE FUNCTION bump_todo_item_version()
+RETURNS TRIGGER AS $$
+BEGIN
+    NEW.version := OLD.version + 1;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER bump_todo_item_version
+BEFORE UPDATE ON todo_items
+FOR EACH ROW
+EXECUTE FUNCTION bump_todo_item_version();
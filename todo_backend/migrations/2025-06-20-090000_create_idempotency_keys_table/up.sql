@@ -0,0 +1,16 @@
+-- Writes sent with an `Idempotency-Key` header, and the responses to replay
+-- when the same key comes again
+CREATE TABLE idempotency_keys (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    idempotency_key VARCHAR(255) NOT NULL,
+    request_hash CHAR(64) NOT NULL,
+    -- NULL while the first request with the key is still being handled
+    status SMALLINT,
+    headers JSONB NOT NULL DEFAULT '[]',
+    body BYTEA NOT NULL DEFAULT '',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (user_id, idempotency_key)
+);
+
+-- The purge job removes keys past their retention window
+CREATE INDEX idx_idempotency_keys_created_at ON idempotency_keys(created_at);
@@ -0,0 +1,102 @@
+// todo_backend/src/assets.rs
+// The web frontend: index.html and the assets under /static. Assets are
+// linked with a digest of their contents (`/static/script.js?v=<digest>`),
+// so browsers can cache them for good and still pick up a new release.
+use crate::config::AppConfig;
+use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Request, Response};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Where the assets are served.
+pub const STATIC_PATH: &str = "/static";
+
+// The assets index.html links with their digest
+const VERSIONED_ASSETS: [&str; 2] = ["script.js", "style.css"];
+
+// For assets requested with their current digest
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+// For index.html and anything requested without one: browsers check back each time
+const REVALIDATE: &str = "no-cache";
+
+/// The frontend as read from `static_dir` at startup.
+pub struct StaticAssets {
+    /// index.html, linking each asset with its digest.
+    pub index: Option<String>,
+    // Digest of each of `VERSIONED_ASSETS`, by file name
+    digests: HashMap<&'static str, String>,
+}
+
+impl StaticAssets {
+    pub fn load(static_dir: &Path) -> Self {
+        let mut digests = HashMap::new();
+        for name in VERSIONED_ASSETS {
+            match std::fs::read(static_dir.join(name)) {
+                Ok(contents) => {
+                    digests.insert(name, hex::encode(&Sha256::digest(&contents)[..8]));
+                }
+                Err(e) => tracing::warn!(asset = name, error = %e, "could not read static asset"),
+            }
+        }
+
+        let index = std::fs::read_to_string(static_dir.join("index.html"))
+            .map(|index| {
+                digests.iter().fold(index, |index, (name, digest)| {
+                    let path = format!("\"{}/{}\"", STATIC_PATH, name);
+                    index.replace(&path, &format!("\"{}/{}?v={}\"", STATIC_PATH, name, digest))
+                })
+            })
+            .map_err(|e| tracing::warn!(error = %e, "could not read index.html"))
+            .ok();
+        StaticAssets { index, digests }
+    }
+
+    // Whether the request is for an asset by its current digest
+    fn is_current(&self, request: &Request<'_>) -> bool {
+        let path = request.uri().path().as_str();
+        let Some(name) = path.strip_prefix(STATIC_PATH).and_then(|path| path.strip_prefix('/')) else {
+            return false;
+        };
+        let requested = request.query_value::<&str>("v").and_then(Result::ok);
+        self.digests.get(name).is_some_and(|digest| requested == Some(digest.as_str()))
+    }
+}
+
+/// `Cache-Control` for the frontend: assets requested with their current
+/// digest are cached for a year, everything else is revalidated.
+pub struct CacheHeaders;
+
+#[rocket::async_trait]
+impl Fairing for CacheHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Static Cache Headers",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(assets) = request.rocket().state::<StaticAssets>() else {
+            return;
+        };
+        let path = request.uri().path().as_str();
+        if !response.status().class().is_success() || (path != "/" && !path.starts_with(STATIC_PATH)) {
+            return;
+        }
+        let cache_control = if assets.is_current(request) { IMMUTABLE } else { REVALIDATE };
+        response.set_header(Header::new("Cache-Control", cache_control));
+    }
+}
+
+// Fairing that reads the frontend from `static_dir`
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Static Assets", |rocket| async {
+        let Some(static_dir) = rocket.state::<AppConfig>().map(|config| config.static_dir.clone()) else {
+            return rocket;
+        };
+        rocket.manage(StaticAssets::load(&static_dir)).attach(CacheHeaders)
+    })
+}
@@ -0,0 +1,257 @@
+// todo_backend/src/config.rs
+use crate::events::EventsConfig;
+use crate::logging::LoggingConfig;
+use crate::metrics::MetricsConfig;
+use crate::cors::CorsConfig;
+use crate::idempotency::IdempotencyConfig;
+use crate::ratelimit::RateLimitConfig;
+use crate::security::SecurityHeadersConfig;
+use crate::services::trash::TrashConfig;
+use crate::services::undo::UndoConfig;
+use crate::storage::AttachmentsConfig;
+use crate::versioning::LegacyApiConfig;
+use rocket::fairing::AdHoc;
+use rocket::figment::providers::Env;
+use rocket::figment::Figment;
+use rocket::serde::Deserialize;
+use std::path::PathBuf;
+
+// Bounds accepted by the bcrypt crate
+const BCRYPT_COST_RANGE: std::ops::RangeInclusive<u32> = 4..=31;
+
+/// Connection pool settings, the `[pool]` table.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct PoolConfig {
+    /// Maximum number of open connections.
+    pub max_size: u32,
+    /// Idle connections kept open; defaults to `max_size`.
+    pub min_idle: Option<u32>,
+    /// Seconds to wait for a connection before giving up; requests that time
+    /// out get a 503.
+    pub connection_timeout_secs: u64,
+    /// Seconds before an idle connection is closed; 0 keeps them open.
+    pub idle_timeout_secs: u64,
+    /// Seconds before a connection is recycled; 0 keeps it forever.
+    pub max_lifetime_secs: u64,
+    /// Further attempts to reach the database at startup before giving up.
+    pub startup_retries: u32,
+    /// Delay before the first retry, doubled after each failed attempt.
+    pub startup_backoff_ms: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_size: 10,
+            min_idle: None,
+            connection_timeout_secs: 30,
+            idle_timeout_secs: 600,
+            max_lifetime_secs: 1800,
+            startup_retries: 5,
+            startup_backoff_ms: 500,
+        }
+    }
+}
+
+/// Login session settings, the `[session]` table.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Seconds a session token stays valid after login.
+    pub ttl_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig { ttl_secs: 24 * 60 * 60 }
+    }
+}
+
+/// Optional route groups, the `[features]` table. Disabled groups are not mounted.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct Features {
+    pub registration: bool,
+    pub comments: bool,
+    pub attachments: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            registration: true,
+            comments: true,
+            attachments: true,
+        }
+    }
+}
+
+/// Application settings, read from the top level of Rocket's configuration.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct AppConfig {
+    pub database_url: String,
+    /// Apply pending migrations when the server starts.
+    pub run_migrations: bool,
+    /// Directory holding index.html and the assets served under /static.
+    pub static_dir: PathBuf,
+    pub bcrypt_cost: u32,
+    pub pool: PoolConfig,
+    pub session: SessionConfig,
+    pub features: Features,
+    pub attachments: AttachmentsConfig,
+    pub trash: TrashConfig,
+    pub undo: UndoConfig,
+    pub idempotency: IdempotencyConfig,
+    pub rate_limit: RateLimitConfig,
+    pub cors: CorsConfig,
+    pub security_headers: SecurityHeadersConfig,
+    pub metrics: MetricsConfig,
+    pub logging: LoggingConfig,
+    pub legacy_api: LegacyApiConfig,
+    pub events: EventsConfig,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
+            database_url: String::new(),
+            run_migrations: true,
+            static_dir: PathBuf::from("static"),
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            pool: PoolConfig::default(),
+            session: SessionConfig::default(),
+            features: Features::default(),
+            attachments: AttachmentsConfig::default(),
+            trash: TrashConfig::default(),
+            undo: UndoConfig::default(),
+            idempotency: IdempotencyConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            cors: CorsConfig::default(),
+            security_headers: SecurityHeadersConfig::default(),
+            metrics: MetricsConfig::default(),
+            logging: LoggingConfig::default(),
+            legacy_api: LegacyApiConfig::default(),
+            events: EventsConfig::default(),
+        }
+    }
+}
+
+impl AppConfig {
+    /// Extracts and validates the configuration, describing every problem found.
+    pub fn from_figment(figment: &Figment) -> Result<AppConfig, Vec<String>> {
+        let config: AppConfig = figment
+            .extract()
+            .map_err(|e| e.into_iter().map(|error| error.to_string()).collect::<Vec<_>>())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The database URL, or why it cannot be used.
+    pub fn database_url(&self) -> Result<&str, String> {
+        if self.database_url.is_empty() {
+            return Err("database_url is not set (use DATABASE_URL or APP_DATABASE_URL)".to_string());
+        }
+        if !self.database_url.starts_with("postgres://") && !self.database_url.starts_with("postgresql://") {
+            return Err("database_url must be a postgres:// URL".to_string());
+        }
+        Ok(&self.database_url)
+    }
+
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if let Err(e) = self.database_url() {
+            errors.push(e);
+        }
+        if self.pool.max_size == 0 {
+            errors.push("pool.max_size must be at least 1".to_string());
+        }
+        if self.pool.min_idle.is_some_and(|min_idle| min_idle > self.pool.max_size) {
+            errors.push("pool.min_idle must not exceed pool.max_size".to_string());
+        }
+        if self.pool.connection_timeout_secs == 0 {
+            errors.push("pool.connection_timeout_secs must be at least 1".to_string());
+        }
+        if self.session.ttl_secs == 0 {
+            errors.push("session.ttl_secs must be at least 1".to_string());
+        }
+        if !BCRYPT_COST_RANGE.contains(&self.bcrypt_cost) {
+            errors.push(format!(
+                "bcrypt_cost must be between {} and {}, got {}",
+                BCRYPT_COST_RANGE.start(),
+                BCRYPT_COST_RANGE.end(),
+                self.bcrypt_cost
+            ));
+        }
+        if !self.static_dir.is_dir() {
+            errors.push(format!("static_dir `{}` is not a directory", self.static_dir.display()));
+        }
+        if self.metrics.token.as_deref().is_some_and(str::is_empty) {
+            errors.push("metrics.token must not be empty; leave it unset to disable auth".to_string());
+        }
+        if self.legacy_api.sunset_on.is_some_and(|sunset| sunset <= self.legacy_api.deprecated_on) {
+            errors.push("legacy_api.sunset_on must be after legacy_api.deprecated_on".to_string());
+        }
+        if self.events.replay_buffer == 0 {
+            errors.push("events.replay_buffer must be at least 1".to_string());
+        }
+        if self.events.listen && self.events.reconnect_backoff_ms == 0 {
+            errors.push("events.reconnect_backoff_ms must be at least 1".to_string());
+        }
+        if self.idempotency.retention_secs == 0 {
+            errors.push("idempotency.retention_secs must be at least 1".to_string());
+        }
+        if self.rate_limit.enabled {
+            for (name, bucket) in [("user", &self.rate_limit.user), ("anonymous", &self.rate_limit.anonymous)] {
+                if bucket.burst == 0 || bucket.per_minute == 0 {
+                    errors.push(format!("rate_limit.{0}.burst and rate_limit.{0}.per_minute must be at least 1", name));
+                }
+            }
+        }
+        errors.extend(self.cors.errors());
+        if self.security_headers.referrer().is_none() {
+            errors.push(format!(
+                "security_headers.referrer_policy `{}` is not a Referrer-Policy",
+                self.security_headers.referrer_policy
+            ));
+        }
+        if self.attachments.max_size == 0 {
+            errors.push("attachments.max_size must be greater than zero".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Rocket's own sources (Rocket.toml, `ROCKET_*`), overridden by `APP_*`
+/// variables. Nested keys use a double underscore: `APP_POOL__MAX_SIZE=20`.
+/// The conventional `DATABASE_URL` is honoured too, below `APP_DATABASE_URL`.
+pub fn figment() -> Figment {
+    rocket::Config::figment()
+        .merge(Env::raw().only(&["DATABASE_URL"]).global())
+        .merge(Env::prefixed("APP_").split("__").global())
+}
+
+// Fairing that validates the configuration and attaches it to Rocket's managed state
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Application Config", |rocket| async {
+        match AppConfig::from_figment(rocket.figment()) {
+            Ok(config) => Ok(rocket.manage(config)),
+            Err(errors) => {
+                for error in errors {
+                    tracing::error!(%error, "invalid configuration");
+                }
+                Err(rocket)
+            }
+        }
+    })
+}
@@ -0,0 +1,199 @@
+// todo_backend/src/cors.rs
+// Cross-origin resource sharing, for frontends served from another origin
+use crate::config::AppConfig;
+use crate::{ApiError, ErrorDetail};
+use rocket::data::Data;
+use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::route::{self, Handler, Route};
+use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
+use rocket::{Request, Response};
+
+// Response headers cross-origin pages may read, besides the CORS-safelisted ones
+const EXPOSED_HEADERS: [&str; 11] = [
+    "ETag",
+    "Location",
+    "Retry-After",
+    "X-RateLimit-Limit",
+    "X-RateLimit-Remaining",
+    "X-RateLimit-Reset",
+    crate::logging::REQUEST_ID_HEADER,
+    crate::idempotency::REPLAYED_HEADER,
+    "Deprecation",
+    "Sunset",
+    "Link",
+];
+
+/// The `[cors]` table in Rocket.toml (or `APP_CORS__*`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct CorsConfig {
+    /// Origins (like `https://app.example.com`) whose pages may call the API,
+    /// or `"*"` for any. Empty disables CORS.
+    pub allowed_origins: Vec<String>,
+    /// Methods cross-origin pages may use.
+    pub allowed_methods: Vec<String>,
+    /// Request headers cross-origin pages may send, besides the CORS-safelisted ones.
+    pub allowed_headers: Vec<String>,
+    /// Let cross-origin pages send the session cookie; not allowed with `"*"`.
+    pub allow_credentials: bool,
+    /// Seconds browsers may cache the answer to a preflight.
+    pub max_age_secs: u32,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: strings(&["GET", "POST", "PUT", "PATCH", "DELETE"]),
+            allowed_headers: strings(&[
+                "Authorization",
+                "Content-Type",
+                "If-Match",
+                "If-None-Match",
+                crate::idempotency::KEY_HEADER,
+                "Last-Event-ID",
+                crate::logging::REQUEST_ID_HEADER,
+            ]),
+            allow_credentials: false,
+            max_age_secs: 600,
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn enabled(&self) -> bool {
+        !self.allowed_origins.is_empty()
+    }
+
+    fn any_origin(&self) -> bool {
+        self.allowed_origins.iter().any(|allowed| allowed == "*")
+    }
+
+    /// Whether `origin` is named in `allowed_origins`, rather than let in by `"*"`.
+    pub fn lists_origin(&self, origin: &str) -> bool {
+        self.allowed_origins.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.any_origin() || self.lists_origin(origin)
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        self.allowed_methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+
+    fn allows_header(&self, header: &str) -> bool {
+        self.allowed_headers.iter().any(|allowed| allowed.eq_ignore_ascii_case(header))
+    }
+
+    /// Problems with the table, for [`AppConfig::validate`].
+    pub fn errors(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.allow_credentials && self.any_origin() {
+            errors.push("cors.allow_credentials cannot be used with the \"*\" origin".to_string());
+        }
+        for origin in self.allowed_origins.iter().filter(|origin| *origin != "*") {
+            let host = origin.strip_prefix("https://").or_else(|| origin.strip_prefix("http://"));
+            if host.is_none_or(|host| host.is_empty() || host.contains('/')) {
+                errors.push(format!("cors.allowed_origins: `{}` is not an origin like https://app.example.com", origin));
+            }
+        }
+        errors
+    }
+}
+
+/// Adds the `Access-Control-*` headers to responses for allowed origins.
+/// Responses for other origins get none, so browsers keep them from the page.
+pub struct Cors;
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(config) = request.rocket().state::<AppConfig>().map(|config| &config.cors) else {
+            return;
+        };
+        // Which origin is allowed depends on the request's
+        response.adjoin_raw_header("Vary", "Origin");
+        let Some(origin) = request.headers().get_one("Origin") else {
+            return;
+        };
+        if !config.allows_origin(origin) {
+            return;
+        }
+
+        let allowed_origin = if config.any_origin() { "*" } else { origin };
+        response.set_header(Header::new("Access-Control-Allow-Origin", allowed_origin.to_string()));
+        if config.allow_credentials {
+            response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
+        response.set_header(Header::new("Access-Control-Expose-Headers", EXPOSED_HEADERS.join(", ")));
+    }
+}
+
+// Answers preflight requests for every path; whether the path exists is left
+// to the request that follows
+#[derive(Clone)]
+struct Preflight;
+
+#[rocket::async_trait]
+impl Handler for Preflight {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let headers = request.headers();
+        let (Some(config), Some(origin), Some(method)) = (
+            request.rocket().state::<AppConfig>().map(|config| &config.cors),
+            headers.get_one("Origin"),
+            headers.get_one("Access-Control-Request-Method"),
+        ) else {
+            return route::Outcome::forward(data, Status::NotFound);
+        };
+
+        let mut requested_headers = headers
+            .get_one("Access-Control-Request-Headers")
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|header| !header.is_empty());
+        if !config.allows_origin(origin)
+            || !config.allows_method(method)
+            || !requested_headers.all(|header| config.allows_header(header))
+        {
+            let error = ApiError::Forbidden(Json(ErrorDetail::new(
+                "cors_not_allowed",
+                "The origin, method or headers of this cross-origin request are not allowed",
+            )));
+            return route::Outcome::from(request, error);
+        }
+
+        let response = Response::build()
+            .status(Status::NoContent)
+            .raw_header("Access-Control-Allow-Methods", config.allowed_methods.join(", "))
+            .raw_header("Access-Control-Allow-Headers", config.allowed_headers.join(", "))
+            .raw_header("Access-Control-Max-Age", config.max_age_secs.to_string())
+            .finalize();
+        route::Outcome::Success(response)
+    }
+}
+
+// Fairing that answers preflights and adds CORS headers, when enabled
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("CORS", |rocket| async {
+        let enabled = rocket.state::<AppConfig>().is_some_and(|config| config.cors.enabled());
+        if !enabled {
+            return rocket;
+        }
+        rocket
+            .attach(Cors)
+            .mount("/", vec![Route::new(Method::Options, "/<_..>", Preflight)])
+    })
+}
@@ -1,40 +1,320 @@
 // todo_backend/src/db.rs
-use diesel::pg::PgConnection;
+use diesel::pg::{Pg, PgConnection};
+use diesel::prelude::*;
 use diesel::r2d2::{self, ConnectionManager};
+use diesel::sql_types::BigInt;
+use diesel::migration::MigrationSource;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use prometheus::{IntCounter, Registry};
 use rocket::fairing::AdHoc;
-use std::env;
-use once_cell::sync::Lazy; // Add this if not already used
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use std::error::Error;
+use std::time::Duration;
+use crate::config::{AppConfig, PoolConfig};
+use crate::logging::{route_of, RequestId};
+use crate::{ApiError, ErrorDetail, InternalErrorDetail};
+use tracing::Span;
 
 // an R2D2 connection pool
 pub type PgPool = r2d2::Pool<ConnectionManager<PgConnection>>;
+pub type PgPooledConnection = r2d2::PooledConnection<ConnectionManager<PgConnection>>;
 
-// DATABASE_URL static variable using once_cell
-static DATABASE_URL: Lazy<String> = Lazy::new(|| {
-    env::var("DATABASE_URL").expect("DATABASE_URL must be set")
-});
+// Errors returned by diesel_migrations
+pub type MigrationError = Box<dyn Error + Send + Sync>;
 
-/// Initialize the database pool.
-pub fn init_pool() -> PgPool {
-    let manager = ConnectionManager::<PgConnection>::new(DATABASE_URL.as_str());
+/// The `migrations/` directory, compiled into the binary.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+// Arbitrary key for the advisory lock serializing migration runs, so several
+// instances (or test clients) starting at once don't race each other.
+const MIGRATION_LOCK_KEY: i64 = 0x7764_746f_646f; // "wdtodo"
+
+// Zero disables the corresponding pool timeout
+fn optional_secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Initialize the database pool. Fails if the initial connections cannot be
+/// opened within `connection_timeout_secs`.
+pub fn init_pool(database_url: &str, config: &PoolConfig) -> Result<PgPool, r2d2::PoolError> {
+    let manager = ConnectionManager::<PgConnection>::new(database_url);
     r2d2::Pool::builder()
+        .max_size(config.max_size)
+        .min_idle(config.min_idle)
+        .connection_timeout(Duration::from_secs(config.connection_timeout_secs))
+        .idle_timeout(optional_secs(config.idle_timeout_secs))
+        .max_lifetime(optional_secs(config.max_lifetime_secs))
         .build(manager)
-        .expect("Failed to create database pool")
+}
+
+// Builds the pool, retrying with exponential backoff while the database is
+// unreachable so the server can start before (or while) it comes up.
+async fn connect_with_retry(database_url: &str, config: &PoolConfig) -> Result<PgPool, String> {
+    let mut backoff = Duration::from_millis(config.startup_backoff_ms);
+    let mut attempt = 0;
+    loop {
+        let (url, pool_config) = (database_url.to_string(), config.clone());
+        let result = rocket::tokio::task::spawn_blocking(move || init_pool(&url, &pool_config))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|built| built.map_err(|e| e.to_string()));
+
+        match result {
+            Ok(pool) => return Ok(pool),
+            Err(e) if attempt < config.startup_retries => {
+                attempt += 1;
+                tracing::warn!(
+                    error = %e,
+                    attempt,
+                    max_attempts = config.startup_retries,
+                    retry_in = ?backoff,
+                    "database unavailable, retrying"
+                );
+                rocket::tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Counters about connection checkouts made by [`DbConn`].
+pub struct PoolMetrics {
+    checkouts: IntCounter,
+    checkout_failures: IntCounter,
+}
+
+impl PoolMetrics {
+    pub fn new() -> prometheus::Result<Self> {
+        Ok(PoolMetrics {
+            checkouts: IntCounter::new("db_pool_checkouts_total", "Connections checked out by requests")?,
+            checkout_failures: IntCounter::new(
+                "db_pool_checkout_failures_total",
+                "Requests that got no connection in time",
+            )?,
+        })
+    }
+
+    /// Exposes these counters through `registry` as well.
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.checkouts.clone()))?;
+        registry.register(Box::new(self.checkout_failures.clone()))
+    }
+}
+
+/// A snapshot of the pool for monitoring, served at `/status/pool`.
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct PoolStatus {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub checkouts: u64,
+    pub checkout_failures: u64,
+}
+
+pub fn pool_status(pool: &PgPool, metrics: &PoolMetrics) -> PoolStatus {
+    let state = pool.state();
+    PoolStatus {
+        max_size: pool.max_size(),
+        connections: state.connections,
+        idle_connections: state.idle_connections,
+        checkouts: metrics.checkouts.get(),
+        checkout_failures: metrics.checkout_failures.get(),
+    }
+}
+
+/// A pooled connection held for the rest of the request. Fails with a 503 when
+/// none frees up within `pool.connection_timeout_secs`. Declare it after
+/// `AuthenticatedUser`, which briefly checks out a connection of its own.
+///
+/// Diesel and bcrypt block, so the connection is only reachable through
+/// [`DbConn::run`], which does the work on Tokio's blocking thread pool.
+pub struct DbConn {
+    conn: Option<PgPooledConnection>,
+    // Carries the request id and route into logs written on the blocking thread
+    span: Span,
+}
+
+impl DbConn {
+    /// The span of the request this connection was checked out for.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Runs `f` with the connection on a blocking thread and waits for it
+    /// without holding up the async workers. Panics in `f` are propagated.
+    /// `f` runs in the caller's current span, or else the request's span.
+    pub async fn run<F, T>(&mut self, f: F) -> T
+    where
+        F: FnOnce(&mut PgConnection) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut conn = self.conn.take().expect("DbConn used after a panic in DbConn::run");
+        let current = Span::current();
+        let span = if current.is_none() { self.span.clone() } else { current };
+        let result = rocket::tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let value = f(&mut conn);
+            (conn, value)
+        })
+        .await;
+
+        match result {
+            Ok((conn, value)) => {
+                self.conn = Some(conn);
+                value
+            }
+            Err(e) => match e.try_into_panic() {
+                Ok(payload) => std::panic::resume_unwind(payload),
+                Err(e) => panic!("blocking database task failed: {}", e),
+            },
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DbConn {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let (Some(pool), Some(metrics)) = (request.rocket().state::<PgPool>(), request.rocket().state::<PoolMetrics>()) else {
+            return ApiError::InternalError(InternalErrorDetail("Failed to retrieve database pool".to_string()))
+                .fail_guard(request);
+        };
+
+        // Waiting for a free connection blocks, so keep it off the async workers
+        let pool = pool.clone();
+        match rocket::tokio::task::spawn_blocking(move || pool.get()).await {
+            Ok(Ok(conn)) => {
+                metrics.checkouts.inc();
+                let span = tracing::info_span!(
+                    "request",
+                    request_id = %RequestId::of(request),
+                    method = %request.method(),
+                    route = %route_of(request),
+                );
+                request::Outcome::Success(DbConn { conn: Some(conn), span })
+            }
+            _ => {
+                metrics.checkout_failures.inc();
+                ApiError::ServiceUnavailable(Json(ErrorDetail::new(
+                    "database_unavailable",
+                    "The database is temporarily unavailable",
+                )))
+                .fail_guard(request)
+            }
+        }
+    }
+}
+
+/// Open a single connection outside of the pool (used by the CLI).
+pub fn establish_connection(database_url: &str) -> ConnectionResult<PgConnection> {
+    PgConnection::establish(database_url)
+}
+
+fn with_migration_lock<T>(
+    conn: &mut PgConnection,
+    f: impl FnOnce(&mut PgConnection) -> Result<T, MigrationError>,
+) -> Result<T, MigrationError> {
+    diesel::sql_query("SELECT pg_advisory_lock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_KEY)
+        .execute(conn)?;
+    let result = f(conn);
+    diesel::sql_query("SELECT pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_KEY)
+        .execute(conn)?;
+    result
+}
+
+/// Apply all pending migrations, returning the versions that were run.
+pub fn run_pending_migrations(conn: &mut PgConnection) -> Result<Vec<String>, MigrationError> {
+    with_migration_lock(conn, |conn| {
+        let applied = conn.run_pending_migrations(MIGRATIONS)?;
+        Ok(applied.iter().map(|version| version.to_string()).collect())
+    })
+}
+
+/// Revert the most recently applied migration, returning its version.
+pub fn revert_last_migration(conn: &mut PgConnection) -> Result<String, MigrationError> {
+    with_migration_lock(conn, |conn| {
+        let reverted = conn.revert_last_migration(MIGRATIONS)?;
+        Ok(reverted.to_string())
+    })
+}
+
+/// Every embedded migration by name, paired with whether it has been applied.
+pub fn migration_status(conn: &mut PgConnection) -> Result<Vec<(String, bool)>, MigrationError> {
+    let applied: Vec<String> = conn
+        .applied_migrations()?
+        .iter()
+        .map(|version| version.to_string())
+        .collect();
+    let migrations = MigrationSource::<Pg>::migrations(&MIGRATIONS)?;
+
+    Ok(migrations
+        .iter()
+        .map(|migration| {
+            let name = migration.name();
+            let is_applied = applied.contains(&name.version().to_string());
+            (name.to_string(), is_applied)
+        })
+        .collect())
 }
 
 // Fairing for attaching the pool to Rocket's managed state
 pub fn stage() -> AdHoc {
-    AdHoc::on_ignite("Diesel PostgreSQL Pool", |rocket| async {
-        let pool = init_pool();
-        // Run migrations on startup
-        // Note: In a production app, you might want to handle this differently,
-        // e.g., running migrations as a separate step during deployment.
-        // For simplicity here, we run them on application start.
-        // This requires `diesel_migrations` feature to be enabled for diesel CLI and accessible.
-        // However, diesel_migrations crate itself isn't directly used here in code for running them.
-        // The `embed_migrations!` macro and `run_pending_migrations` are part of `diesel_migrations` crate.
-        // We will need to add `diesel_migrations` to `lib.rs` or `main.rs` to run them.
-        // For now, we'll assume migrations are run manually or by a script before app start in production.
-        // For testing and CI, we will manage this.
-        rocket.manage(pool)
+    AdHoc::try_on_ignite("Diesel PostgreSQL Pool", |rocket| async {
+        // Without a valid configuration there is nothing to connect to; the
+        // config fairing has already reported why.
+        let Some(config) = rocket.state::<AppConfig>() else {
+            return Err(rocket);
+        };
+
+        let pool = match connect_with_retry(&config.database_url, &config.pool).await {
+            Ok(pool) => pool,
+            Err(e) => {
+                tracing::error!(error = %e, "failed to create database pool");
+                return Err(rocket);
+            }
+        };
+
+        // Pending migrations run on startup unless `run_migrations = false`
+        // (e.g. when they are applied as a separate deployment step with
+        // `todo_backend_server migrate up`).
+        if config.run_migrations {
+            let migration_pool = pool.clone();
+            let result = rocket::tokio::task::spawn_blocking(move || {
+                let mut conn = migration_pool.get()?;
+                run_pending_migrations(&mut conn)
+            })
+            .await;
+
+            match result {
+                Ok(Ok(applied)) => {
+                    for version in applied {
+                        tracing::info!(%version, "applied migration");
+                    }
+                }
+                Ok(Err(e)) => {
+                    tracing::error!(error = %e, "failed to run database migrations");
+                    return Err(rocket);
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "migration task panicked");
+                    return Err(rocket);
+                }
+            }
+        }
+        let pool_metrics = match PoolMetrics::new() {
+            Ok(pool_metrics) => pool_metrics,
+            Err(e) => {
+                tracing::error!(error = %e, "failed to set up pool metrics");
+                return Err(rocket);
+            }
+        };
+        Ok(rocket.manage(pool).manage(pool_metrics))
     })
 }
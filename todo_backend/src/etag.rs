@@ -0,0 +1,109 @@
+// todo_backend/src/etag.rs
+// Conditional requests (RFC 9110, section 13): `ETag` on item and list
+// responses, `If-None-Match` on reads and `If-Match` on writes
+use crate::services::error::ServiceError;
+use rocket::http::{Header, Method, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+
+/// The entity tag of a todo item at `version`.
+pub fn item_tag(version: i32) -> String {
+    format!("\"{}\"", version)
+}
+
+// The tags listed in an `If-Match` or `If-None-Match` header, or `None` for `*`
+fn listed_tags(header: &str) -> Option<Vec<&str>> {
+    let header = header.trim();
+    if header == "*" {
+        return None;
+    }
+    Some(header.split(',').map(str::trim).filter(|tag| !tag.is_empty()).collect())
+}
+
+/// The `If-Match` header of a write. The write only goes ahead if the item
+/// is still at one of the listed versions.
+#[derive(Debug, Default)]
+pub struct IfMatch(Option<String>);
+
+impl IfMatch {
+    /// The precondition that the item is still at `version`, for writes that
+    /// name the version they expect other than in a header.
+    pub fn version(version: i32) -> Self {
+        IfMatch(Some(item_tag(version)))
+    }
+
+    /// Fails with `412 precondition_failed` unless an `If-Match` tag matches
+    /// the item at `version`. Weak tags never match.
+    pub fn check(&self, version: i32) -> Result<(), ServiceError> {
+        let Some(ref header) = self.0 else {
+            return Ok(());
+        };
+        let current = item_tag(version);
+        match listed_tags(header) {
+            None => Ok(()),
+            Some(tags) if tags.contains(&current.as_str()) => Ok(()),
+            Some(_) => Err(ServiceError::PreconditionFailed(
+                "precondition_failed",
+                "The item has changed since it was read".to_string(),
+            )),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(IfMatch(request.headers().get_one("If-Match").map(str::to_string)))
+    }
+}
+
+/// A response with an `ETag`. Reads whose `If-None-Match` lists that tag
+/// get `304 Not Modified` instead.
+pub struct Tagged<R> {
+    etag: String,
+    inner: R,
+}
+
+impl<R> Tagged<R> {
+    pub fn new(etag: String, inner: R) -> Self {
+        Tagged { etag, inner }
+    }
+}
+
+impl<T: Serialize> Tagged<Json<T>> {
+    /// Tags a JSON body with a digest of its serialized form, for responses
+    /// (like lists) that have no version of their own.
+    pub fn hashed(body: Json<T>) -> Self {
+        let serialized = serde_json::to_vec(&body.0).unwrap_or_default();
+        let digest = hex::encode(&Sha256::digest(&serialized)[..16]);
+        Tagged::new(format!("\"{}\"", digest), body)
+    }
+}
+
+// Weak comparison, as RFC 9110 prescribes for If-None-Match
+fn none_match(request: &Request<'_>, etag: &str) -> bool {
+    let Some(header) = request.headers().get_one("If-None-Match") else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.strip_prefix("W/").unwrap_or(tag).to_string();
+    match listed_tags(header) {
+        None => true,
+        Some(tags) => tags.into_iter().any(|tag| opaque(tag) == opaque(etag)),
+    }
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Tagged<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let etag = Header::new("ETag", self.etag.clone());
+        if matches!(request.method(), Method::Get | Method::Head) && none_match(request, &self.etag) {
+            return Response::build().status(Status::NotModified).header(etag).ok();
+        }
+        Response::build_from(self.inner.respond_to(request)?).header(etag).ok()
+    }
+}
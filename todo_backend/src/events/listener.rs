@@ -0,0 +1,198 @@
+// todo_backend/src/events/listener.rs
+use super::{EventBus, EventsConfig};
+use crate::db::PgPool;
+use crate::models::TodoItem;
+use crate::services::history::ItemAction;
+use diesel::prelude::*;
+use rocket::serde::Deserialize;
+use rocket::tokio::select;
+use rocket::tokio::sync::mpsc;
+use rocket::tokio::time::{self, timeout};
+use rocket::Shutdown;
+use std::future::poll_fn;
+use std::task::Poll;
+use std::time::Duration;
+use tokio_postgres::{AsyncMessage, Client, NoTls};
+use uuid::Uuid;
+
+// The channel the `notify_todo_item_change` trigger announces changes on.
+const CHANNEL: &str = "todo_item_changes";
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// An unreachable database fails the attempt rather than leaving it hanging
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// An idle connection is checked this often, so that one dropped without the
+// socket noticing is replaced too
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The trigger's payload: the item, or only its id when that was too large
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Notification {
+    action: String,
+    item: Option<TodoItem>,
+    id: Option<Uuid>,
+}
+
+struct Listener {
+    client: Client,
+    payloads: mpsc::UnboundedReceiver<String>,
+}
+
+async fn connect(database_url: &str, application_name: &str) -> Result<Listener, tokio_postgres::Error> {
+    let mut config: tokio_postgres::Config = database_url.parse()?;
+    config.application_name(application_name).connect_timeout(CONNECT_TIMEOUT);
+    let (client, mut connection) = config.connect(NoTls).await?;
+
+    // The connection performs the I/O and hands over notifications as they
+    // come in. It ends, closing `payloads`, once the connection is lost.
+    let (sender, payloads) = mpsc::unbounded_channel();
+    rocket::tokio::spawn(async move {
+        let result = poll_fn(|cx| loop {
+            match connection.poll_message(cx) {
+                Poll::Ready(Some(Ok(AsyncMessage::Notification(notification)))) => {
+                    let _ = sender.send(notification.payload().to_string());
+                }
+                Poll::Ready(Some(Ok(_))) => {}
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        })
+        .await;
+        if let Err(e) = result {
+            tracing::warn!(error = %e, "event listener connection failed");
+        }
+    });
+
+    client.batch_execute(&format!("LISTEN {}", CHANNEL)).await?;
+    Ok(Listener { client, payloads })
+}
+
+/// Connects the listener and leaves it running in the background, feeding
+/// `bus` with the changes committed through any instance until `shutdown`.
+/// If the database cannot be reached now, the background task keeps trying.
+pub(super) async fn start(database_url: &str, config: &EventsConfig, pool: PgPool, bus: EventBus, shutdown: Shutdown) {
+    let name = bus.listener_name();
+    let listener = match connect(database_url, &name).await {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            tracing::warn!(error = %e, "event listener could not connect, retrying in the background");
+            None
+        }
+    };
+
+    let database_url = database_url.to_string();
+    let backoff = Duration::from_millis(config.reconnect_backoff_ms);
+    rocket::tokio::spawn(async move {
+        let mut listener = listener;
+        let run = async {
+            loop {
+                let connected = match listener.take() {
+                    Some(listener) => listener,
+                    None => {
+                        let listener = reconnect(&database_url, &name, backoff).await;
+                        // Whatever changed while disconnected was not announced to us
+                        bus.resync();
+                        listener
+                    }
+                };
+                listen(connected, &pool, &bus).await;
+                tracing::warn!("event listener disconnected");
+            }
+        };
+        // Dropping the listener closes its connection
+        select! {
+            _ = run => {}
+            _ = shutdown => {}
+        }
+    });
+}
+
+// Retries with exponential backoff until the connection succeeds
+async fn reconnect(database_url: &str, name: &str, initial_backoff: Duration) -> Listener {
+    let mut backoff = initial_backoff;
+    loop {
+        time::sleep(backoff).await;
+        match connect(database_url, name).await {
+            Ok(listener) => {
+                tracing::info!("event listener reconnected");
+                return listener;
+            }
+            Err(e) => {
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                tracing::warn!(error = %e, retry_in = ?backoff, "event listener could not reconnect");
+            }
+        }
+    }
+}
+
+// Forwards notifications until the connection is lost
+async fn listen(mut listener: Listener, pool: &PgPool, bus: &EventBus) {
+    let mut ping = time::interval(PING_INTERVAL);
+    ping.tick().await;
+    loop {
+        select! {
+            payload = listener.payloads.recv() => match payload {
+                Some(payload) => forward(&payload, pool, bus).await,
+                None => return,
+            },
+            _ = ping.tick() => {
+                if !matches!(timeout(PING_TIMEOUT, listener.client.simple_query("")).await, Ok(Ok(_))) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn forward(payload: &str, pool: &PgPool, bus: &EventBus) {
+    let notification: Notification = match serde_json::from_str(payload) {
+        Ok(notification) => notification,
+        Err(e) => {
+            tracing::warn!(error = %e, payload, "unreadable change notification");
+            return;
+        }
+    };
+    let Some(action) = ItemAction::from_name(&notification.action) else {
+        tracing::warn!(action = notification.action, "unknown action in change notification");
+        return;
+    };
+
+    let item = match (notification.item, notification.id) {
+        (Some(item), _) => item,
+        (None, Some(item_id)) => match load_item(pool, item_id).await {
+            Ok(Some(item)) => item,
+            // Purged before we got to it; there is nothing left to show
+            Ok(None) => return,
+            Err(e) => {
+                tracing::warn!(error = %e, %item_id, "could not load the changed item");
+                return;
+            }
+        },
+        (None, None) => {
+            tracing::warn!(payload, "change notification without an item");
+            return;
+        }
+    };
+    bus.dispatch(action, item);
+}
+
+async fn load_item(pool: &PgPool, item_id: Uuid) -> Result<Option<TodoItem>, String> {
+    use crate::schema::todo_items::dsl::*;
+    let pool = pool.clone();
+    rocket::tokio::task::spawn_blocking(move || {
+        let mut conn = pool.get().map_err(|e| e.to_string())?;
+        todo_items
+            .filter(id.eq(item_id))
+            .select(TodoItem::as_select())
+            .first::<TodoItem>(&mut conn)
+            .optional()
+            .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
@@ -0,0 +1,277 @@
+// todo_backend/src/events/mod.rs
+mod listener;
+
+use crate::config::AppConfig;
+use crate::db::PgPool;
+use crate::models::TodoItem;
+use crate::services::history::ItemAction;
+use rocket::fairing::AdHoc;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::Deserialize;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::Shutdown;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Sent instead of the missed events when they can no longer be replayed;
+/// clients should reload the list.
+pub const RESYNC_EVENT: &str = "resync";
+
+/// The `[events]` table in Rocket.toml (or `APP_EVENTS__*`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct EventsConfig {
+    /// Seconds between keep-alive comments on idle streams; 0 disables them.
+    pub heartbeat_secs: u64,
+    /// Recent events kept for clients resuming with `Last-Event-ID`.
+    pub replay_buffer: usize,
+    /// Receive changes through Postgres `LISTEN`, so that streams see writes
+    /// made through every instance; otherwise only this instance's own.
+    pub listen: bool,
+    /// Delay before reconnecting a lost listener connection, doubled after
+    /// each failed attempt up to a minute.
+    pub reconnect_backoff_ms: u64,
+}
+
+impl EventsConfig {
+    pub fn heartbeat(&self) -> Option<Duration> {
+        (self.heartbeat_secs > 0).then(|| Duration::from_secs(self.heartbeat_secs))
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        EventsConfig {
+            heartbeat_secs: 15,
+            replay_buffer: 1024,
+            listen: true,
+            reconnect_backoff_ms: 500,
+        }
+    }
+}
+
+/// A change to a todo item, delivered to the streams of its owner.
+#[derive(Debug, Clone)]
+pub struct TodoEvent {
+    pub id: u64,
+    pub action: ItemAction,
+    pub item: TodoItem,
+}
+
+impl TodoEvent {
+    fn to_sse(&self) -> Event {
+        Event::json(&self.item)
+            .id(self.id.to_string())
+            .event(self.action.as_str())
+    }
+}
+
+/// What a [`Subscription`] delivers.
+#[derive(Debug, Clone)]
+pub enum Update {
+    Change(TodoEvent),
+    /// Changes may have been missed; the subscriber has to reload the list.
+    Resync,
+}
+
+struct Recent {
+    next_id: u64,
+    events: VecDeque<TodoEvent>,
+}
+
+struct Inner {
+    sender: broadcast::Sender<Update>,
+    // Held while sending too, so subscribers see events in id order and a
+    // replay never overlaps with or misses what the receiver gets afterwards
+    recent: Mutex<Recent>,
+    capacity: usize,
+    // Whether `publish` delivers, rather than the database listener
+    publishes: bool,
+    instance: Uuid,
+}
+
+/// In-process fan-out of todo item changes to the `/api/v1/events` streams
+/// and WebSocket subscriptions.
+/// Cloning is cheap; all clones publish to the same subscribers.
+#[derive(Clone)]
+pub struct EventBus {
+    inner: Arc<Inner>,
+}
+
+impl EventBus {
+    pub fn new(config: &EventsConfig) -> Self {
+        let capacity = config.replay_buffer;
+        let (sender, _) = broadcast::channel(capacity);
+        EventBus {
+            inner: Arc::new(Inner {
+                sender,
+                recent: Mutex::new(Recent { next_id: 1, events: VecDeque::with_capacity(capacity) }),
+                capacity,
+                publishes: !config.listen,
+                instance: Uuid::new_v4(),
+            }),
+        }
+    }
+
+    /// The `application_name` of this instance's listener connection, to
+    /// find it in `pg_stat_activity`.
+    pub fn listener_name(&self) -> String {
+        format!("webtodo-events-{}", self.inner.instance)
+    }
+
+    /// Announces `action` on `item` to this instance's streams. Call this once
+    /// the change is committed. Does nothing when `events.listen` is set, as
+    /// the change then arrives through the listener like everyone else's.
+    pub fn publish(&self, action: ItemAction, item: &TodoItem) {
+        if self.inner.publishes {
+            self.dispatch(action, item.clone());
+        }
+    }
+
+    fn dispatch(&self, action: ItemAction, item: TodoItem) {
+        let mut recent = self.inner.recent.lock().unwrap_or_else(|e| e.into_inner());
+        let event = TodoEvent { id: recent.next_id, action, item };
+        recent.next_id += 1;
+        if recent.events.len() == self.inner.capacity {
+            recent.events.pop_front();
+        }
+        recent.events.push_back(event.clone());
+        // Having no subscribers is not an error
+        let _ = self.inner.sender.send(Update::Change(event));
+    }
+
+    // Tells every stream to start over, and makes the buffered events
+    // unavailable for resuming as they may be followed by a gap
+    fn resync(&self) {
+        let mut recent = self.inner.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent.events.clear();
+        recent.next_id += 1;
+        let _ = self.inner.sender.send(Update::Resync);
+    }
+
+    /// Subscribes to the changes to `user_id`'s items, starting with the
+    /// buffered ones after `last_event_id` when given.
+    pub fn subscribe(&self, user_id: Uuid, last_event_id: Option<u64>) -> Subscription {
+        let recent = self.inner.recent.lock().unwrap_or_else(|e| e.into_inner());
+        let mut subscription = Subscription {
+            user_id,
+            receiver: self.inner.sender.subscribe(),
+            pending: VecDeque::new(),
+        };
+        let Some(last_id) = last_event_id else {
+            return subscription;
+        };
+
+        let oldest = recent.events.front().map_or(recent.next_id, |event| event.id);
+        // Ids from before a restart (or made up) cannot be resumed from either
+        if last_id >= recent.next_id || last_id + 1 < oldest {
+            subscription.pending.push_back(Update::Resync);
+        } else {
+            subscription.pending.extend(
+                recent.events.iter()
+                    .filter(|event| event.id > last_id && event.item.user_id == user_id)
+                    .cloned()
+                    .map(Update::Change),
+            );
+        }
+        subscription
+    }
+
+    /// The stream of changes to `user_id`'s items, starting after
+    /// `last_event_id` when given. Ends when the server shuts down.
+    pub fn stream(
+        &self,
+        user_id: Uuid,
+        last_event_id: LastEventId,
+        heartbeat: Option<Duration>,
+        mut shutdown: Shutdown,
+    ) -> EventStream![Event + 'static] {
+        let mut subscription = self.subscribe(user_id, last_event_id.0);
+        let stream = EventStream! {
+            loop {
+                let update = select! {
+                    update = subscription.next() => update,
+                    _ = &mut shutdown => break,
+                };
+                match update {
+                    Some(Update::Change(event)) => yield event.to_sse(),
+                    Some(Update::Resync) => yield Event::empty().event(RESYNC_EVENT),
+                    None => break,
+                }
+            }
+        };
+        stream.heartbeat(heartbeat)
+    }
+}
+
+/// One subscriber's view of an [`EventBus`].
+pub struct Subscription {
+    user_id: Uuid,
+    receiver: broadcast::Receiver<Update>,
+    // Replayed before anything new
+    pending: VecDeque<Update>,
+}
+
+impl Subscription {
+    /// The next update for this subscriber, or `None` once the bus is gone.
+    /// Cancel safe, so it can be raced against other futures.
+    pub async fn next(&mut self) -> Option<Update> {
+        if let Some(update) = self.pending.pop_front() {
+            return Some(update);
+        }
+        loop {
+            match self.receiver.recv().await {
+                Ok(Update::Change(event)) if event.item.user_id != self.user_id => {}
+                Ok(update) => return Some(update),
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(user_id = %self.user_id, skipped, "event subscriber fell behind");
+                    return Some(Update::Resync);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// The `Last-Event-ID` header a reconnecting `EventSource` sends. Values that
+/// are not event ids are ignored.
+pub struct LastEventId(pub Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let id = request
+            .headers()
+            .get_one("Last-Event-ID")
+            .and_then(|value| value.trim().parse().ok());
+        request::Outcome::Success(LastEventId(id))
+    }
+}
+
+// Fairing that attaches the event bus to Rocket's managed state and, with
+// `events.listen`, starts the task feeding it from the database once the
+// server is up; the task ends with the server
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Todo Events", |rocket| async {
+        let (Some(config), Some(pool)) = (rocket.state::<AppConfig>(), rocket.state::<PgPool>().cloned()) else {
+            return Err(rocket);
+        };
+
+        let bus = EventBus::new(&config.events);
+        let listening = config.events.listen.then(|| (config.database_url.clone(), config.events.clone(), bus.clone()));
+        let rocket = rocket.manage(bus);
+        let Some((database_url, events, bus)) = listening else {
+            return Ok(rocket);
+        };
+        Ok(rocket.attach(AdHoc::on_liftoff("Todo Event Listener", move |rocket| {
+            Box::pin(async move { listener::start(&database_url, &events, pool, bus, rocket.shutdown()).await })
+        })))
+    })
+}
@@ -0,0 +1,94 @@
+// todo_backend/src/health.rs
+use crate::db::{PgPool, MIGRATIONS};
+use diesel::prelude::*;
+use diesel_migrations::MigrationHarness;
+use rocket::serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+// Readiness probes should fail fast rather than queue behind busy requests
+const CHECKOUT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The outcome of one readiness check. Why a check failed is only logged, as
+/// the probe is unauthenticated and errors can name hosts and users.
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ComponentHealth {
+    pub status: &'static str,
+    pub latency_ms: f64,
+}
+
+/// Body of `/healthz` and `/readyz`; `status` is "ok" only if every check passed.
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct HealthReport {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub checks: BTreeMap<&'static str, ComponentHealth>,
+}
+
+impl HealthReport {
+    pub fn alive() -> Self {
+        HealthReport {
+            status: "ok",
+            checks: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+// Runs one check, recording how long it took and logging why it failed
+fn timed<T, E: Display>(component: &'static str, check: impl FnOnce() -> Result<T, E>) -> (ComponentHealth, Option<T>) {
+    let started = Instant::now();
+    let result = check();
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    match result {
+        Ok(value) => (ComponentHealth { status: "ok", latency_ms }, Some(value)),
+        Err(e) => {
+            tracing::warn!(component, error = %e, "readiness check failed");
+            (ComponentHealth { status: "unavailable", latency_ms }, None)
+        }
+    }
+}
+
+// For the checks that need the connection the pool could not hand out
+fn skipped() -> ComponentHealth {
+    ComponentHealth {
+        status: "unavailable",
+        latency_ms: 0.0,
+    }
+}
+
+/// Checks that a connection can be checked out, answers `SELECT 1`, and that no
+/// embedded migration is pending. Blocks; call it from a blocking thread.
+pub fn check_readiness(pool: &PgPool) -> HealthReport {
+    let mut checks = BTreeMap::new();
+
+    let (pool_health, conn) = timed("pool", || pool.get_timeout(CHECKOUT_TIMEOUT));
+    checks.insert("pool", pool_health);
+
+    match conn {
+        Some(mut conn) => {
+            let (database, _) = timed("database", || diesel::sql_query("SELECT 1").execute(&mut conn));
+            checks.insert("database", database);
+
+            let (migrations, _) = timed("migrations", || match conn.has_pending_migration(MIGRATIONS) {
+                Ok(false) => Ok(()),
+                Ok(true) => Err("pending migrations".into()),
+                Err(e) => Err(e),
+            });
+            checks.insert("migrations", migrations);
+        }
+        None => {
+            checks.insert("database", skipped());
+            checks.insert("migrations", skipped());
+        }
+    }
+
+    let status = if checks.values().all(|check| check.status == "ok") { "ok" } else { "unavailable" };
+    HealthReport { status, checks }
+}
@@ -0,0 +1,535 @@
+// todo_backend/src/idempotency.rs
+// `Idempotency-Key` on writes: the first response to a key is stored, and
+// retries with the same key and body get it replayed instead of running again
+use crate::config::AppConfig;
+use crate::db::PgPool;
+use crate::models::{AttachmentUpload, IdempotencyRecord, NewIdempotencyRecord};
+use crate::schema::idempotency_keys;
+use crate::services::error::ServiceError;
+use crate::{ApiError, AuthenticatedUser, ErrorDetail};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use rocket::data::{self, Data, FromData, Limits};
+use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+use rocket::form::Form;
+use rocket::http::{Method, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder, Response};
+use rocket::route::{self, Handler, Route};
+use rocket::serde::json::Json;
+use rocket::serde::{de::DeserializeOwned, Deserialize};
+use rocket::tokio::io::AsyncBufReadExt;
+use rocket::Request;
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+pub const KEY_HEADER: &str = "Idempotency-Key";
+
+/// Set on responses that were replayed rather than produced by the handler.
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+const MAX_KEY_LENGTH: usize = 255;
+
+// The most of a body a fairing can look at; longer ones are hashed by
+// `Fingerprinted` as their handler reads them
+const PEEK_LENGTH: usize = 512;
+
+// Response headers stored and replayed along with the status and body
+const STORED_HEADERS: [&str; 3] = ["Content-Type", "ETag", "Location"];
+
+// A key still pending after this long belongs to a request that never
+// finished (the server went down mid-request) and may be claimed again
+const ABANDONED_AFTER_SECS: i64 = 300;
+
+// Ahead of every other route, so stored responses are answered before a handler runs
+const REPLAY_RANK: isize = -100;
+
+/// The `[idempotency]` table in Rocket.toml (or `APP_IDEMPOTENCY__*`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct IdempotencyConfig {
+    /// Seconds a key is remembered; a key reused after that runs again.
+    pub retention_secs: u64,
+    /// How often expired keys are deleted.
+    pub purge_interval_secs: u64,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig {
+            retention_secs: 86400,
+            purge_interval_secs: 3600,
+        }
+    }
+}
+
+/// A stored response, sent again for every retry with its key.
+#[derive(Debug, Clone)]
+pub struct StoredResponse {
+    status: Status,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl<'r> Responder<'r, 'static> for StoredResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .status(self.status)
+            .sized_body(self.body.len(), Cursor::new(self.body))
+            .raw_header(REPLAYED_HEADER, "true");
+        for (name, value) in self.headers {
+            response.raw_header(name, value);
+        }
+        response.ok()
+    }
+}
+
+// What `on_request` made of a request's key, for the replay route and `on_response`
+enum Decision {
+    // No key, not a write, or not signed in: handled as usual
+    Pass,
+    // The body is longer than the fairing can see, so the key is claimed by the
+    // route: once its `Fingerprinted` body has been hashed, or by the method,
+    // path and headers alone for an `IdempotentWrite` without a body
+    Deferred(Deferred),
+    // First use of the key: handled as usual, then the response is stored
+    Record { user_id: Uuid, key: String },
+    Replay(StoredResponse),
+    Reject(ApiError),
+}
+
+struct Deferred {
+    user_id: Uuid,
+    key: String,
+    // The fingerprint so far: the method, path and headers
+    hasher: Sha256,
+    cutoff: NaiveDateTime,
+}
+
+// A request's decision; a deferred one is settled by its body
+struct KeyState(Mutex<Decision>);
+
+fn decision<'a>(request: &'a Request<'_>) -> &'a Mutex<Decision> {
+    &request.local_cache(|| KeyState(Mutex::new(Decision::Pass))).0
+}
+
+/// The stored response a request's body turned out to be a retry for. The
+/// catcher sends it, as the body's guard can only fail the request.
+pub fn replayed(request: &Request<'_>) -> Option<StoredResponse> {
+    match &*decision(request).lock().unwrap() {
+        Decision::Replay(stored) => Some(stored.clone()),
+        _ => None,
+    }
+}
+
+fn is_write(method: Method) -> bool {
+    matches!(method, Method::Post | Method::Put | Method::Patch | Method::Delete)
+}
+
+fn valid_key(key: &str) -> bool {
+    (1..=MAX_KEY_LENGTH).contains(&key.len()) && key.bytes().all(|b| b.is_ascii_graphic())
+}
+
+// Tells requests apart by method, URI and body; the body is added by the caller
+fn fingerprint(request: &Request<'_>) -> Sha256 {
+    let mut hasher = Sha256::new();
+    for part in [
+        request.method().as_str(),
+        &request.uri().to_string(),
+        request.headers().get_one("Content-Type").unwrap_or(""),
+        request.headers().get_one("Content-Length").unwrap_or(""),
+    ] {
+        hasher.update(part.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher
+}
+
+async fn run<F, T>(pool: PgPool, f: F) -> Result<T, ServiceError>
+where
+    F: FnOnce(&mut PgConnection) -> Result<T, ServiceError> + Send + 'static,
+    T: Send + 'static,
+{
+    rocket::tokio::task::spawn_blocking(move || {
+        let mut conn = pool.get()?;
+        f(&mut conn)
+    })
+    .await
+    .map_err(|e| ServiceError::InternalError(format!("blocking database task failed: {}", e)))?
+}
+
+// Claims the key for this request, or finds what an earlier request with it got
+fn claim(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    key: String,
+    request_hash: String,
+    cutoff: NaiveDateTime,
+) -> Result<Decision, ServiceError> {
+    conn.transaction(|conn| {
+        let this_key = idempotency_keys::table.find((user_id, &key));
+        // Expired and abandoned keys count as unused, even before the purge job gets to them
+        let abandoned = Utc::now().naive_utc() - Duration::seconds(ABANDONED_AFTER_SECS);
+        diesel::delete(this_key.filter(
+            idempotency_keys::created_at
+                .lt(cutoff)
+                .or(idempotency_keys::status.is_null().and(idempotency_keys::created_at.lt(abandoned))),
+        ))
+        .execute(conn)?;
+
+        let claimed = diesel::insert_into(idempotency_keys::table)
+            .values(&NewIdempotencyRecord { user_id, idempotency_key: &key, request_hash: &request_hash })
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        if claimed == 1 {
+            return Ok(Decision::Record { user_id, key });
+        }
+
+        let record = this_key.select(IdempotencyRecord::as_select()).first::<IdempotencyRecord>(conn)?;
+        let Some(status) = record.status else {
+            return Err(ServiceError::Conflict(
+                "idempotency_key_in_use",
+                "A request with this Idempotency-Key is still being processed".to_string(),
+            ));
+        };
+        if record.request_hash != request_hash {
+            return Err(ServiceError::Unprocessable(
+                "idempotency_key_reused",
+                "This Idempotency-Key was already used for a different request".to_string(),
+            ));
+        }
+        Ok(Decision::Replay(StoredResponse {
+            status: Status::new(status as u16),
+            headers: serde_json::from_value(record.headers).unwrap_or_default(),
+            body: record.body,
+        }))
+    })
+}
+
+/// Handles `Idempotency-Key` on POST, PUT, PATCH and DELETE requests from
+/// signed-in users. Keys are scoped to the user; anonymous requests (like
+/// logging in) are never replayed, as that would hand out the same session.
+pub struct Idempotency;
+
+#[rocket::async_trait]
+impl Fairing for Idempotency {
+    fn info(&self) -> Info {
+        Info {
+            name: "Idempotency Keys",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, data: &mut Data<'_>) {
+        if !is_write(request.method()) || crate::ratelimit::is_limited(request) {
+            return;
+        }
+        let Some(key) = request.headers().get_one(KEY_HEADER).map(str::to_string) else {
+            return;
+        };
+        let Outcome::Success(user) = request.guard::<AuthenticatedUser>().await else {
+            return;
+        };
+        let (Some(pool), Some(config)) = (request.rocket().state::<PgPool>().cloned(), request.rocket().state::<AppConfig>())
+        else {
+            return;
+        };
+
+        let decision = if valid_key(&key) {
+            let mut hasher = fingerprint(request);
+            let cutoff = Utc::now().naive_utc() - Duration::seconds(config.idempotency.retention_secs as i64);
+            let peeked = data.peek(PEEK_LENGTH).await.to_vec();
+            // `peek_complete` alone also holds for local requests, whose
+            // whole body is buffered but only the start is returned
+            if peeked.len() < PEEK_LENGTH && data.peek_complete() {
+                hasher.update(&peeked);
+                let request_hash = hex::encode(hasher.finalize());
+                run(pool, move |conn| claim(conn, user.user_id, key, request_hash, cutoff))
+                    .await
+                    .unwrap_or_else(|e| Decision::Reject(e.into()))
+            } else {
+                Decision::Deferred(Deferred { user_id: user.user_id, key, hasher, cutoff })
+            }
+        } else {
+            Decision::Reject(ApiError::BadRequest(Json(ErrorDetail::new(
+                "invalid_idempotency_key",
+                "Idempotency-Key must be 1 to 255 visible ASCII characters",
+            ))))
+        };
+        request.local_cache(|| KeyState(Mutex::new(decision)));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let (user_id, key) = match &*decision(request).lock().unwrap() {
+            Decision::Record { user_id, key } => (*user_id, key.clone()),
+            // Neither a `Fingerprinted` body nor an `IdempotentWrite` got to it
+            Decision::Deferred(_) if response.status().class().is_success() => {
+                tracing::warn!(route = %crate::logging::route_of(request), "Idempotency-Key not claimed by the route");
+                return;
+            }
+            _ => return,
+        };
+        let Some(pool) = request.rocket().state::<PgPool>().cloned() else {
+            return;
+        };
+
+        // Server faults are not stored, so the request can be retried with its key
+        let stored = if response.status().class().is_server_error() {
+            None
+        } else {
+            match response.body_mut().to_bytes().await {
+                Ok(body) => {
+                    response.set_sized_body(body.len(), Cursor::new(body.clone()));
+                    let headers: Vec<(String, String)> = STORED_HEADERS
+                        .iter()
+                        .filter_map(|name| response.headers().get_one(name).map(|value| (name.to_string(), value.to_string())))
+                        .collect();
+                    Some((response.status().code as i16, serde_json::json!(headers), body))
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, "could not read the response to store for an idempotency key");
+                    None
+                }
+            }
+        };
+
+        let result = run(pool, move |conn| {
+            let this_key = idempotency_keys::table.find((user_id, &key));
+            match stored {
+                Some((status, headers, body)) => diesel::update(this_key)
+                    .set((
+                        idempotency_keys::status.eq(status),
+                        idempotency_keys::headers.eq(headers),
+                        idempotency_keys::body.eq(body),
+                    ))
+                    .execute(conn)?,
+                None => diesel::delete(this_key).execute(conn)?,
+            };
+            Ok(())
+        })
+        .await;
+        if let Err(e) = result {
+            tracing::error!(error = %e, "failed to store the response for an idempotency key");
+        }
+    }
+}
+
+// Answers requests `on_request` decided to replay or reject, and forwards the rest
+#[derive(Clone)]
+struct ReplayHandler;
+
+#[rocket::async_trait]
+impl Handler for ReplayHandler {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let answer = match &*decision(request).lock().unwrap() {
+            Decision::Replay(stored) => Ok(stored.clone()),
+            Decision::Reject(error) => Err(error.clone()),
+            Decision::Pass | Decision::Deferred(_) | Decision::Record { .. } => {
+                return route::Outcome::forward(data, Status::NotFound);
+            }
+        };
+        route::Outcome::from(request, answer)
+    }
+}
+
+/// The body of a write, taken by handlers in place of their usual body type.
+/// Bodies too long for [`Idempotency`] to look at are hashed in full as they
+/// are read, and only then is the request's `Idempotency-Key` claimed.
+///
+/// Fails with `None` when the request is a retry and its stored response is
+/// sent instead, or when the body could not be read (the catcher then goes
+/// by the status).
+pub struct Fingerprinted<T>(T);
+
+impl<T> Fingerprinted<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+// Takes the decision left for the body to settle, if there is one
+fn take_deferred(request: &Request<'_>) -> Option<Deferred> {
+    let mut decision = decision(request).lock().unwrap();
+    match std::mem::replace(&mut *decision, Decision::Pass) {
+        Decision::Deferred(deferred) => Some(deferred),
+        other => {
+            *decision = other;
+            None
+        }
+    }
+}
+
+// Claims the key of a request once its whole body has been added to the
+// fingerprint, and hands the body on unless the request is replayed or
+// rejected instead
+async fn settle(request: &Request<'_>, deferred: Deferred) -> Result<(), Status> {
+    let Deferred { user_id, key, hasher, cutoff } = deferred;
+    let Some(pool) = request.rocket().state::<PgPool>().cloned() else {
+        return Ok(());
+    };
+    let request_hash = hex::encode(hasher.finalize());
+    let settled = run(pool, move |conn| claim(conn, user_id, key, request_hash, cutoff))
+        .await
+        .unwrap_or_else(|e| Decision::Reject(e.into()));
+    let failed = match &settled {
+        Decision::Replay(stored) => Some(stored.status),
+        Decision::Reject(error) => Some(error.keep_for_catcher(request)),
+        _ => None,
+    };
+    *decision(request).lock().unwrap() = settled;
+    failed.map_or(Ok(()), Err)
+}
+
+// Hands a body on once its request's key is settled
+fn settled<'r, T>(result: Result<(), Status>, body: T) -> data::Outcome<'r, Fingerprinted<T>, Option<ApiError>> {
+    match result {
+        Ok(()) => data::Outcome::Success(Fingerprinted(body)),
+        Err(status) => data::Outcome::Error((status, None)),
+    }
+}
+
+/// Taken by writes without a body. Their `Idempotency-Key` is claimed by the
+/// method, path and headers alone, even when a (long) body was sent anyway.
+///
+/// Fails with `None` when the request is a retry and its stored response is
+/// sent instead.
+pub struct IdempotentWrite;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotentWrite {
+    type Error = Option<ApiError>;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Some(deferred) = take_deferred(request) else {
+            return Outcome::Success(IdempotentWrite);
+        };
+        match settle(request, deferred).await {
+            Ok(()) => Outcome::Success(IdempotentWrite),
+            Err(status) => Outcome::Error((status, None)),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned + Send> FromData<'r> for Fingerprinted<Json<T>> {
+    type Error = Option<ApiError>;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let Some(mut deferred) = take_deferred(request) else {
+            return match Json::<T>::from_data(request, data).await {
+                data::Outcome::Success(body) => data::Outcome::Success(Fingerprinted(body)),
+                data::Outcome::Error((status, _)) => data::Outcome::Error((status, None)),
+                data::Outcome::Forward(forward) => data::Outcome::Forward(forward),
+            };
+        };
+
+        // Read as `Json` does, keeping the text to hash
+        let limit = request.limits().get("json").unwrap_or(Limits::JSON);
+        let text = match data.open(limit).into_string().await {
+            Ok(text) if text.is_complete() => text.into_inner(),
+            Ok(_) => return data::Outcome::Error((Status::PayloadTooLarge, None)),
+            Err(_) => return data::Outcome::Error((Status::BadRequest, None)),
+        };
+        let body = match serde_json::from_str::<T>(&text) {
+            Ok(body) => Json(body),
+            Err(e) if e.classify() == serde_json::error::Category::Data => {
+                return data::Outcome::Error((Status::UnprocessableEntity, None));
+            }
+            Err(_) => return data::Outcome::Error((Status::BadRequest, None)),
+        };
+
+        deferred.hasher.update(text.as_bytes());
+        settled(settle(request, deferred).await, body)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for Fingerprinted<Form<AttachmentUpload<'r>>> {
+    type Error = Option<ApiError>;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let deferred = take_deferred(request);
+        let upload = match Form::<AttachmentUpload<'r>>::from_data(request, data).await {
+            data::Outcome::Success(upload) => upload,
+            data::Outcome::Error((status, _)) => return data::Outcome::Error((status, None)),
+            data::Outcome::Forward(forward) => return data::Outcome::Forward(forward),
+        };
+        let Some(mut deferred) = deferred else {
+            return data::Outcome::Success(Fingerprinted(upload));
+        };
+
+        // The file as the form parsed it, with its name and type
+        let (file, hasher) = (&upload.file, &mut deferred.hasher);
+        hasher.update(file.raw_name().map(|name| name.dangerous_unsafe_unsanitized_raw().as_str()).unwrap_or(""));
+        hasher.update(b"\n");
+        hasher.update(file.content_type().map(ToString::to_string).unwrap_or_default());
+        hasher.update(b"\n");
+        hasher.update(if file.is_complete() { b"complete\n".as_slice() } else { b"capped\n".as_slice() });
+        let hashed: std::io::Result<()> = async {
+            let mut reader = Box::pin(file.open().await?);
+            loop {
+                let chunk = reader.fill_buf().await?;
+                if chunk.is_empty() {
+                    return Ok(());
+                }
+                let read = chunk.len();
+                hasher.update(chunk);
+                reader.consume(read);
+            }
+        }
+        .await;
+        if let Err(e) = hashed {
+            let error = ApiError::InternalError(crate::InternalErrorDetail(format!("Failed to hash the upload: {}", e)));
+            return data::Outcome::Error((error.keep_for_catcher(request), Some(error)));
+        }
+        settled(settle(request, deferred).await, upload)
+    }
+}
+
+/// The replay routes, to mount at `/`.
+pub fn routes() -> Vec<Route> {
+    [Method::Post, Method::Put, Method::Patch, Method::Delete]
+        .into_iter()
+        .map(|method| Route::ranked(REPLAY_RANK, method, "/<_..>", ReplayHandler))
+        .collect()
+}
+
+// Fairing that periodically deletes keys past their retention window
+pub fn stage() -> AdHoc {
+    AdHoc::on_liftoff("Idempotency Key Purge", |rocket| Box::pin(async move {
+        let (Some(config), Some(pool)) = (
+            rocket.state::<AppConfig>().map(|config| config.idempotency.clone()),
+            rocket.state::<PgPool>().cloned(),
+        ) else {
+            return;
+        };
+
+        let retention = Duration::seconds(config.retention_secs as i64);
+        let interval = std::time::Duration::from_secs(config.purge_interval_secs.max(1));
+        let mut shutdown = rocket.shutdown();
+        rocket::tokio::spawn(async move {
+            let mut ticker = rocket::tokio::time::interval(interval);
+            loop {
+                rocket::tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = &mut shutdown => return,
+                }
+                let cutoff = Utc::now().naive_utc() - retention;
+                let purged = run(pool.clone(), move |conn| {
+                    Ok(diesel::delete(idempotency_keys::table.filter(idempotency_keys::created_at.lt(cutoff)))
+                        .execute(conn)?)
+                })
+                .await;
+                match purged {
+                    Ok(0) => {}
+                    Ok(purged) => tracing::info!(purged, "purged expired idempotency keys"),
+                    Err(e) => tracing::error!(error = %e, "idempotency key purge failed"),
+                }
+            }
+        });
+    }))
+}
@@ -6,53 +6,349 @@ extern crate rocket; // Ensure rocket macros are available
 pub mod schema; // Generated by Diesel CLI
 pub mod models;
 pub mod db; // Our new db module
+pub mod storage;
+pub mod config;
+pub mod health;
+pub mod logging;
+pub mod metrics;
+pub mod openapi;
+pub mod versioning;
+pub mod events;
+pub mod ws;
+pub mod etag;
+pub mod idempotency;
+pub mod ratelimit;
+pub mod cors;
+pub mod security;
+pub mod assets;
 
-use db::PgPool;
-use diesel::prelude::*;
-use rocket::fs::{FileServer, NamedFile};
-use std::path::Path;
+use assets::StaticAssets;
+use config::AppConfig;
+use etag::{IfMatch, Tagged};
+use idempotency::{Fingerprinted, IdempotentWrite};
+use events::{EventBus, LastEventId};
+use logging::RequestId;
+use ws::{SocketState, TodoSocket, WebSocketResponse, WebSocketUpgrade};
+use db::{DbConn, PgPool, PoolMetrics, PoolStatus};
+use metrics::{Metrics, MetricsAccess};
+use storage::AttachmentStore;
+use services::attachments::AttachmentDownload;
+use rocket::form::Form;
+use rocket::fairing::AdHoc;
+use rocket::fs::FileServer;
 use models::*;
-use rocket::http::{CookieJar, Status};
+use rocket::http::{ContentType, Cookie, CookieJar, Status};
+use rocket::response::content::RawHtml;
+use rocket::response::stream::{Event, EventStream};
+use rocket::response::Responder;
 use rocket::serde::json::Json;
 use rocket::serde::{Deserialize, Serialize};
-use rocket::{Build, Rocket, State}; // Import State
+use rocket::{Build, Rocket, Shutdown, State}; // Import State
+use utoipa::ToSchema;
 use uuid::Uuid;
-use dotenvy;
-use crate::services::auth::Session;
-use chrono::Utc;
 
 // Re-export AppUuid if it's used elsewhere, or remove if not needed
 // For simplicity, assuming Uuid directly from the uuid crate is fine.
 // pub type AppUuid = Uuid; // If you had a type alias
 
 // --- Error Types ---
-#[derive(Serialize, Debug)]
+/// The error-specific members of a problem-details response. `error` is a
+/// stable code (e.g. `todo_not_found`) that clients can match on; `detail` is
+/// for people and may change.
+#[derive(Serialize, Debug, Clone, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct ErrorDetail {
-    error: String, // Changed field name from detail to error
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// The same text as `detail`, under the name it had before problem details.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(deprecated)]
+    pub message: Option<String>,
+    /// Set for `validation_failed`: what was wrong with each rejected field.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
 }
 
-#[derive(Responder, Debug)]
+impl ErrorDetail {
+    pub fn new(code: impl Into<String>, detail: impl Into<String>) -> Self {
+        let detail = detail.into();
+        ErrorDetail {
+            error: code.into(),
+            message: Some(detail.clone()),
+            detail: Some(detail),
+            fields: Vec::new(),
+        }
+    }
+}
+
+/// One rejected request field, e.g. `{ "field": "description", "code": "required", ... }`.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, code: &str, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.to_string(),
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+/// An RFC 7807 problem-details body, served as `application/problem+json`.
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: &'static str,
+    pub title: &'static str,
+    pub status: u16,
+    pub instance: String,
+    #[serde(flatten)]
+    pub detail: ErrorDetail,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl Problem {
+    pub fn new(status: Status, request: &rocket::Request<'_>, detail: ErrorDetail) -> Self {
+        Problem {
+            problem_type: "about:blank",
+            title: status.reason_lossy(),
+            status: status.code,
+            instance: request.uri().path().to_string(),
+            detail,
+            request_id: None,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Problem {
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        let status = Status::from_code(self.status).unwrap_or(Status::InternalServerError);
+        rocket::Response::build_from(Json(self).respond_to(request)?)
+            .status(status)
+            .header(ContentType::new("application", "problem+json"))
+            .ok()
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum ApiError {
-    #[response(status = 400, content_type = "json")]
     BadRequest(Json<ErrorDetail>),
-    #[response(status = 404, content_type = "json")]
     NotFound(Json<ErrorDetail>),
-    #[response(status = 401, content_type = "json")]
     Unauthorized(Json<ErrorDetail>),
-    #[response(status = 409, content_type = "json")]
+    Forbidden(Json<ErrorDetail>),
     Conflict(Json<ErrorDetail>),
-    #[response(status = 500, content_type = "json")]
-    InternalError(Json<ErrorDetail>),
+    PreconditionFailed(Json<ErrorDetail>),
+    UnprocessableEntity(Json<ErrorDetail>),
+    PayloadTooLarge(Json<ErrorDetail>),
+    TooManyRequests(Json<ErrorDetail>),
+    InternalError(InternalErrorDetail),
+    ServiceUnavailable(Json<ErrorDetail>),
+}
+
+impl ApiError {
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::BadRequest(_) => Status::BadRequest,
+            ApiError::NotFound(_) => Status::NotFound,
+            ApiError::Unauthorized(_) => Status::Unauthorized,
+            ApiError::Forbidden(_) => Status::Forbidden,
+            ApiError::Conflict(_) => Status::Conflict,
+            ApiError::PreconditionFailed(_) => Status::PreconditionFailed,
+            ApiError::UnprocessableEntity(_) => Status::UnprocessableEntity,
+            ApiError::PayloadTooLarge(_) => Status::PayloadTooLarge,
+            ApiError::TooManyRequests(_) => Status::TooManyRequests,
+            ApiError::InternalError(_) => Status::InternalServerError,
+            ApiError::ServiceUnavailable(_) => Status::ServiceUnavailable,
+        }
+    }
+
+    /// The problem details to send, or for a server fault, what to log instead.
+    pub fn into_detail(self) -> Result<ErrorDetail, InternalErrorDetail> {
+        match self {
+            ApiError::InternalError(internal) => Err(internal),
+            ApiError::BadRequest(detail)
+            | ApiError::NotFound(detail)
+            | ApiError::Unauthorized(detail)
+            | ApiError::Forbidden(detail)
+            | ApiError::Conflict(detail)
+            | ApiError::PreconditionFailed(detail)
+            | ApiError::UnprocessableEntity(detail)
+            | ApiError::PayloadTooLarge(detail)
+            | ApiError::TooManyRequests(detail)
+            | ApiError::ServiceUnavailable(detail) => Ok(detail.into_inner()),
+        }
+    }
+
+    /// The problem details to send where there is no response to log a server
+    /// fault for (a WebSocket reply, one result of a batch). Faults are logged
+    /// here and reported as `internal_error`.
+    pub fn into_reported_detail(self) -> ErrorDetail {
+        self.into_detail().unwrap_or_else(|InternalErrorDetail(message)| {
+            tracing::error!(error = %message, "internal error");
+            ErrorDetail::new("internal_error", "An unexpected error occurred")
+        })
+    }
+
+    /// Fails a request guard with this error. Rocket hands catchers only the
+    /// status, so the error is kept in the request for [`default_catcher`],
+    /// along with the route whose guard failed.
+    pub fn fail_guard<T>(self, request: &rocket::Request<'_>) -> rocket::request::Outcome<T, ApiError> {
+        let status = self.keep_for_catcher(request);
+        rocket::request::Outcome::Error((status, self))
+    }
+
+    /// Keeps the error for [`default_catcher`] like [`ApiError::fail_guard`],
+    /// for data guards to fail with the status it returns.
+    pub fn keep_for_catcher(&self, request: &rocket::Request<'_>) -> Status {
+        let failed = request.local_cache(|| GuardFailure(std::sync::Mutex::new(None)));
+        *failed.0.lock().unwrap() = Some((route_key(request), self.clone()));
+        self.status()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        let status = self.status();
+        match self.into_detail() {
+            Ok(detail) => Problem::new(status, request, detail).respond_to(request),
+            Err(internal) => internal.respond_to(request),
+        }
+    }
+}
+
+// The error a request guard failed with and the route it was run for, for
+// the catcher that handles it
+struct GuardFailure(std::sync::Mutex<Option<(Option<String>, ApiError)>>);
+
+// Tells routes apart. Guards that fairings run before routing have none.
+fn route_key(request: &rocket::Request<'_>) -> Option<String> {
+    request.route().map(|route| format!("{} {} {}", route.method, route.uri, route.rank))
+}
+
+/// A server-side failure. The message is logged together with the request id;
+/// the client only gets the request id to quote when reporting the problem.
+#[derive(Debug, Clone)]
+pub struct InternalErrorDetail(pub String);
+
+impl<'r> Responder<'r, 'static> for InternalErrorDetail {
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        let request_id = logging::RequestId::of(request);
+        tracing::error!(
+            request_id,
+            method = %request.method(),
+            route = %logging::route_of(request),
+            error = %self.0,
+            "internal error"
+        );
+        let detail = ErrorDetail::new("internal_error", "An unexpected error occurred");
+        Problem {
+            request_id: Some(request_id.to_string()),
+            ..Problem::new(Status::InternalServerError, request, detail)
+        }
+        .respond_to(request)
+    }
 }
 
 // --- Request Guards / Authentication ---
+/// Where services report the changes they make: the event bus and the metrics.
+#[derive(Clone)]
+pub struct ChangeSinks {
+    pub events: EventBus,
+    pub metrics: Metrics,
+}
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for ChangeSinks {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> rocket::request::Outcome<Self, Self::Error> {
+        let rocket = request.rocket();
+        match (rocket.state::<EventBus>(), rocket.state::<Metrics>()) {
+            (Some(events), Some(metrics)) => {
+                rocket::request::Outcome::Success(ChangeSinks { events: events.clone(), metrics: metrics.clone() })
+            }
+            _ => ApiError::InternalError(InternalErrorDetail("The event bus or metrics are not managed".to_string()))
+                .fail_guard(request),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct AuthenticatedUser {
     pub user_id: Uuid,
+    pub session_id: Uuid,
 }
 
 const SESSION_COOKIE_NAME: &str = "session_token";
 
+// The session lookup, done once per request: fairings (rate limiting,
+// idempotency keys) ask for the user before the handler's guards do
+struct Authentication(rocket::request::Outcome<AuthenticatedUser, ApiError>);
+
+fn unauthorized(code: &str, detail: &str) -> rocket::request::Outcome<AuthenticatedUser, ApiError> {
+    rocket::request::Outcome::Error((Status::Unauthorized, ApiError::Unauthorized(Json(ErrorDetail::new(code, detail)))))
+}
+
+// The session token a request carries: its bearer token, or without an
+// `Authorization` header, the session cookie
+pub(crate) fn session_token(request: &rocket::Request<'_>) -> Option<String> {
+    if let Some(header_val) = request.headers().get_one("Authorization") {
+        if header_val.starts_with("Bearer ") {
+            Some(header_val.trim_start_matches("Bearer ").to_string())
+        } else {
+            None
+        }
+    } else {
+        request
+            .cookies()
+            .get(SESSION_COOKIE_NAME)
+            .map(|cookie| cookie.value().to_string())
+    }
+}
+
+async fn authenticate(request: &rocket::Request<'_>) -> rocket::request::Outcome<AuthenticatedUser, ApiError> {
+    match session_token(request) {
+        Some(token) => {
+            let session_uuid = match Uuid::parse_str(&token) {
+                Ok(uuid) => uuid,
+                Err(_) => {
+                    return unauthorized("invalid_token_format", "Session tokens are UUIDs");
+                }
+            };
+
+            // Dropped at the end of this guard, before the handler's own DbConn
+            let mut conn = match request.guard::<DbConn>().await {
+                rocket::request::Outcome::Success(conn) => conn,
+                rocket::request::Outcome::Error(e) => return rocket::request::Outcome::Error(e),
+                rocket::request::Outcome::Forward(status) => return rocket::request::Outcome::Forward(status),
+            };
+
+            let session = conn
+                .run(move |conn| services::auth::find_active_session(conn, session_uuid).unwrap_or(None))
+                .await;
+
+            if let Some(session) = session {
+                rocket::request::Outcome::Success(AuthenticatedUser {
+                    user_id: session.user_id,
+                    session_id: session.id,
+                })
+            } else {
+                unauthorized("invalid_token", "The session token is invalid or has expired")
+            }
+        }
+        None => unauthorized("missing_token", "Authentication required"),
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> rocket::request::FromRequest<'r> for AuthenticatedUser {
     type Error = ApiError;
@@ -60,86 +356,13 @@ impl<'r> rocket::request::FromRequest<'r> for AuthenticatedUser {
     async fn from_request(
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
-        let pool = match request.guard::<&State<PgPool>>().await {
-            rocket::request::Outcome::Success(pool) => pool,
-            _ => {
-                return rocket::request::Outcome::Error((
-                    Status::InternalServerError,
-                    ApiError::InternalError(Json(ErrorDetail {
-                        error: "Failed to retrieve database pool".to_string(),
-                    })),
-                ));
-            }
-        };
-
-        let cookies = request.cookies();
-        let auth_header = request.headers().get_one("Authorization");
-
-        let token_str = if let Some(header_val) = auth_header {
-            if header_val.starts_with("Bearer ") {
-                Some(header_val.trim_start_matches("Bearer ").to_string())
-            } else {
-                None
-            }
-        } else {
-            cookies
-                .get(SESSION_COOKIE_NAME)
-                .map(|cookie| cookie.value().to_string())
-        };
-
-        match token_str {
-            Some(token) => {
-                let session_uuid = match Uuid::parse_str(&token) {
-                    Ok(uuid) => uuid,
-                    Err(_) => {
-                        return rocket::request::Outcome::Error((
-                            Status::Unauthorized,
-                            ApiError::Unauthorized(Json(ErrorDetail {
-                                error: "invalid_token_format".to_string(),
-                            })),
-                        ));
-                    }
-                };
-
-                let mut conn = match pool.get() {
-                    Ok(conn) => conn,
-                    Err(_) => {
-                        return rocket::request::Outcome::Error((
-                            Status::InternalServerError,
-                            ApiError::InternalError(Json(ErrorDetail {
-                                error: "db_connection_error".to_string(),
-                            })),
-                        ));
-                    }
-                };
-
-                use crate::schema::sessions::dsl::*;
-                let session = sessions
-                    .filter(id.eq(session_uuid))
-                    .filter(expires_at.gt(Utc::now().naive_utc()))
-                    .first::<Session>(&mut conn)
-                    .optional()
-                    .unwrap_or(None);
-
-                if let Some(session) = session {
-                    rocket::request::Outcome::Success(AuthenticatedUser {
-                        user_id: session.user_id,
-                    })
-                } else {
-                    rocket::request::Outcome::Error((
-                        Status::Unauthorized,
-                        ApiError::Unauthorized(Json(ErrorDetail {
-                            error: "invalid_or_expired_token".to_string(),
-                        })),
-                    ))
-                }
-            }
-            None => rocket::request::Outcome::Error((
-                Status::Unauthorized,
-                ApiError::Unauthorized(Json(ErrorDetail {
-                    error: "missing_token".to_string(),
-                })),
-            )),
+        let authentication = request
+            .local_cache_async(async { Authentication(authenticate(request).await) })
+            .await;
+        // Failed for each route that asks, so the catcher knows it was this one
+        match authentication.0.clone() {
+            rocket::request::Outcome::Error((_, error)) => error.fail_guard(request),
+            outcome => outcome,
         }
     }
 }
@@ -148,14 +371,14 @@ impl<'r> rocket::request::FromRequest<'r> for AuthenticatedUser {
 // --- Route Handlers ---
 
 // Auth routes
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct AuthRequest {
     username: String,
     password: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct LoginResponse {
     pub session_token: String,
@@ -164,145 +387,924 @@ pub struct LoginResponse {
 }
 
 
-#[post("/auth/register", data = "<auth_req>")]
+/// Create an account
+#[utoipa::path(
+    context_path = "/auth",
+    tag = "auth",
+    request_body = AuthRequest,
+    responses(
+        (status = 200, description = "The new user", body = UserInfo),
+        (status = 400, description = "Invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "Username already taken", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/register", data = "<auth_req>")]
 async fn register_user(
-    pool: &State<PgPool>,
+    mut conn: DbConn,
+    config: &State<AppConfig>,
     auth_req: Json<AuthRequest>,
 ) -> Result<Json<UserInfo>, ApiError> {
-    Ok(services::auth::register_user(pool, auth_req)?)
+    let bcrypt_cost = config.bcrypt_cost;
+    Ok(conn.run(move |conn| services::auth::register_user(conn, bcrypt_cost, auth_req)).await?)
 }
 
-#[post("/auth/login", data = "<auth_req>")]
+/// Log in; sets the `session_token` cookie and returns the token for bearer auth
+#[utoipa::path(
+    context_path = "/auth",
+    tag = "auth",
+    request_body = AuthRequest,
+    responses(
+        (status = 200, description = "Session opened", body = LoginResponse),
+        (status = 401, description = "Wrong password", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such user", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/login", data = "<auth_req>")]
 async fn login_user(
-    pool: &State<PgPool>,
+    mut conn: DbConn,
+    config: &State<AppConfig>,
+    metrics: &State<Metrics>,
     cookies: &CookieJar<'_>,
     auth_req: Json<AuthRequest>,
 ) -> Result<Json<LoginResponse>, ApiError> {
-    Ok(services::auth::login_user(pool, cookies, auth_req)?)
+    let (session_config, metrics) = (config.session.clone(), metrics.inner().clone());
+    let response = conn.run(move |conn| services::auth::login_user(conn, &session_config, &metrics, auth_req)).await?;
+    cookies.add(Cookie::new(SESSION_COOKIE_NAME, response.session_token.clone()));
+    Ok(response)
 }
 
-#[post("/auth/logout")]
-async fn logout_user(pool: &State<PgPool>, cookies: &CookieJar<'_>) -> Result<Status, ApiError> {
-    services::auth::logout_user(pool, cookies)?;
+/// Log out the session in the `session_token` cookie
+#[utoipa::path(
+    context_path = "/auth",
+    tag = "auth",
+    responses(
+        (status = 204, description = "Logged out"),
+    )
+)]
+#[post("/logout")]
+async fn logout_user(mut conn: DbConn, cookies: &CookieJar<'_>, _write: IdempotentWrite) -> Result<Status, ApiError> {
+    if let Some(cookie) = cookies.get(SESSION_COOKIE_NAME) {
+        let session_token = cookie.value().to_string();
+        conn.run(move |conn| services::auth::logout_user(conn, session_token)).await?;
+        cookies.remove(Cookie::from(SESSION_COOKIE_NAME));
+    }
     Ok(Status::NoContent)
 }
 
 
 // Todo item routes
-#[post("/api/todos", data = "<create_req>")]
+/// Create a todo item
+#[utoipa::path(
+    tag = "todos",
+    request_body = CreateTodoRequest,
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The new item", body = TodoItem),
+        (status = 400, description = "Invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/todos", data = "<create_req>")]
 async fn add_todo_item(
-    pool: &State<PgPool>,
+    sinks: ChangeSinks,
     auth_user: AuthenticatedUser,
-    create_req: Json<CreateTodoRequest>,
+    mut conn: DbConn,
+    create_req: Fingerprinted<Json<CreateTodoRequest>>,
 ) -> Result<Json<TodoItem>, ApiError> {
-    Ok(services::todos::add_todo_item(pool, auth_user, create_req)?)
+    let (ChangeSinks { events, metrics }, create_req) = (sinks, create_req.into_inner());
+    Ok(conn.run(move |conn| services::todos::add_todo_item(conn, &events, &metrics, auth_user, create_req)).await?)
 }
 
-#[get("/api/todos/<item_id_str>")]
+/// Get a todo item
+#[utoipa::path(
+    tag = "todos",
+    params(
+        ("item_id_str" = Uuid, Path, description = "Todo item id"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of the copy the client has, to get `304` if it is current"),
+    ),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The item", body = TodoItem, headers(("ETag" = String, description = "The item's `version`, quoted"))),
+        (status = 304, description = "The item is unchanged since the `If-None-Match` ETag"),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such todo item", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/todos/<item_id_str>")]
 async fn get_todo_item(
-    pool: &State<PgPool>,
     auth_user: AuthenticatedUser,
+    mut conn: DbConn,
     item_id_str: String,
-) -> Result<Json<TodoItem>, ApiError> {
-    Ok(services::todos::get_todo_item(pool, auth_user, item_id_str)?)
+) -> Result<Tagged<Json<TodoItem>>, ApiError> {
+    let item = conn.run(move |conn| services::todos::get_todo_item(conn, auth_user, item_id_str)).await?;
+    Ok(Tagged::new(etag::item_tag(item.version), item))
 }
 
-#[put("/api/todos/<item_id_str>/complete")]
+/// Mark a todo item completed; the response carries an undo token
+#[utoipa::path(
+    tag = "todos",
+    params(
+        ("item_id_str" = Uuid, Path, description = "Todo item id"),
+        ("If-Match" = Option<String>, Header, description = "ETag the item must still have; `412` otherwise"),
+    ),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The completed item", body = Undoable<TodoItem>, headers(("ETag" = String, description = "The item's new `version`, quoted"))),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such todo item", body = Problem, content_type = "application/problem+json"),
+        (status = 412, description = "The item no longer matches `If-Match`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[put("/todos/<item_id_str>/complete")]
 async fn complete_todo_item(
-    pool: &State<PgPool>,
+    config: &State<AppConfig>,
+    sinks: ChangeSinks,
     auth_user: AuthenticatedUser,
+    mut conn: DbConn,
     item_id_str: String,
-) -> Result<Json<TodoItem>, ApiError> {
-    Ok(services::todos::complete_todo_item(pool, auth_user, item_id_str)?)
+    if_match: IfMatch,
+    _write: IdempotentWrite,
+) -> Result<Tagged<Json<Undoable<TodoItem>>>, ApiError> {
+    let (undo_config, ChangeSinks { events, metrics }) = (config.undo.clone(), sinks);
+    let completed = conn
+        .run(move |conn| {
+            services::todos::complete_todo_item(conn, &events, &metrics, &undo_config, auth_user, item_id_str, &if_match)
+        })
+        .await?;
+    Ok(Tagged::new(etag::item_tag(completed.item.version), completed))
+}
+
+/// Change the description or completion of a todo item
+#[utoipa::path(
+    tag = "todos",
+    params(
+        ("item_id_str" = Uuid, Path, description = "Todo item id"),
+        ("If-Match" = Option<String>, Header, description = "ETag the item must still have; `412` otherwise"),
+    ),
+    request_body = UpdateTodoRequest,
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The updated item", body = TodoItem, headers(("ETag" = String, description = "The item's new `version`, quoted"))),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such todo item", body = Problem, content_type = "application/problem+json"),
+        (status = 412, description = "The item no longer matches `If-Match`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[put("/todos/<item_id_str>", data = "<update_req>")]
+async fn update_todo_item(
+    sinks: ChangeSinks,
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    item_id_str: String,
+    update_req: Fingerprinted<Json<UpdateTodoRequest>>,
+    if_match: IfMatch,
+) -> Result<Tagged<Json<TodoItem>>, ApiError> {
+    let (ChangeSinks { events, metrics }, update_req) = (sinks, update_req.into_inner());
+    let updated = conn
+        .run(move |conn| {
+            services::todos::update_todo_item(conn, &events, &metrics, auth_user, item_id_str, update_req, &if_match)
+        })
+        .await?;
+    Ok(Tagged::new(etag::item_tag(updated.version), updated))
+}
+
+/// List the changes made to a todo item, oldest first
+#[utoipa::path(
+    tag = "todos",
+    params(("item_id_str" = Uuid, Path, description = "Todo item id")),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The item history", body = Vec<TodoItemEvent>),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such todo item", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/todos/<item_id_str>/history")]
+async fn get_item_history(
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    item_id_str: String,
+) -> Result<Json<Vec<TodoItemEvent>>, ApiError> {
+    Ok(conn.run(move |conn| services::history::get_item_history(conn, auth_user, item_id_str)).await?)
 }
 
-#[delete("/api/todos/<item_id_str>")]
+/// Move a todo item to the trash; the response carries an undo token
+#[utoipa::path(
+    tag = "todos",
+    params(
+        ("item_id_str" = Uuid, Path, description = "Todo item id"),
+        ("If-Match" = Option<String>, Header, description = "ETag the item must still have; `412` otherwise"),
+    ),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The deleted item", body = Undoable<TodoItem>),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such todo item", body = Problem, content_type = "application/problem+json"),
+        (status = 412, description = "The item no longer matches `If-Match`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/todos/<item_id_str>")]
 async fn delete_todo_item(
-    pool: &State<PgPool>,
+    config: &State<AppConfig>,
+    events: &State<EventBus>,
     auth_user: AuthenticatedUser,
+    mut conn: DbConn,
     item_id_str: String,
+    if_match: IfMatch,
+    _write: IdempotentWrite,
+) -> Result<Json<Undoable<TodoItem>>, ApiError> {
+    let (undo_config, events) = (config.undo.clone(), events.inner().clone());
+    Ok(conn
+        .run(move |conn| services::todos::delete_todo_item(conn, &events, &undo_config, auth_user, item_id_str, &if_match))
+        .await?)
+}
+
+/// Revert the complete or delete that issued `token` (valid for `undo.window_secs`)
+#[utoipa::path(
+    tag = "todos",
+    params(("token" = Uuid, Path, description = "Undo token from the complete or delete response")),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The restored item", body = TodoItem),
+        (status = 400, description = "Malformed token", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Unknown, used or expired token", body = Problem, content_type = "application/problem+json"),
+        (status = 409, description = "The item changed since", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/undo/<token>")]
+async fn undo(
+    events: &State<EventBus>,
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    token: String,
+    _write: IdempotentWrite,
+) -> Result<Json<TodoItem>, ApiError> {
+    let events = events.inner().clone();
+    Ok(conn.run(move |conn| services::undo::undo(conn, &events, auth_user, token)).await?)
+}
+
+// Trash routes (DELETE /api/todos/<id> only moves an item here)
+/// List the todo items in the trash
+#[utoipa::path(
+    tag = "trash",
+    params(
+        TodoSearchQuery,
+        ("If-None-Match" = Option<String>, Header, description = "ETag of the list the client has, to get `304` if it is current"),
+    ),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "Trashed items", body = Vec<TodoItem>, headers(("ETag" = String, description = "Digest of the list"))),
+        (status = 304, description = "The list is unchanged since the `If-None-Match` ETag"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/trash?<search_query..>")]
+async fn list_trash(
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    search_query: TodoSearchQuery,
+) -> Result<Tagged<Json<Vec<TodoItem>>>, ApiError> {
+    Ok(Tagged::hashed(conn.run(move |conn| services::trash::list_trash(conn, auth_user, search_query)).await?))
+}
+
+/// Restore a todo item from the trash
+#[utoipa::path(
+    tag = "trash",
+    params(("item_id_str" = Uuid, Path, description = "Todo item id")),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The restored item", body = TodoItem),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such item in the trash", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/todos/<item_id_str>/restore")]
+async fn restore_todo_item(
+    events: &State<EventBus>,
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    item_id_str: String,
+    _write: IdempotentWrite,
+) -> Result<Json<TodoItem>, ApiError> {
+    let events = events.inner().clone();
+    Ok(conn.run(move |conn| services::trash::restore_todo_item(conn, &events, auth_user, item_id_str)).await?)
+}
+
+/// Permanently delete a todo item from the trash
+#[utoipa::path(
+    tag = "trash",
+    params(("item_id_str" = Uuid, Path, description = "Todo item id")),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such item in the trash", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/trash/<item_id_str>")]
+async fn purge_todo_item(
+    store: &State<AttachmentStore>,
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    item_id_str: String,
+    _write: IdempotentWrite,
 ) -> Result<Status, ApiError> {
-    services::todos::delete_todo_item(pool, auth_user, item_id_str)?;
+    services::trash::purge_todo_item(&mut conn, store, auth_user, item_id_str).await?;
     Ok(Status::NoContent)
 }
 
-// GET /api/todos (list all) and /api/todos/search?description=... (search by description)
-// Combined into one handler, also handling /api/todos?completed=true/false
-#[get("/api/todos?<search_query..>")]
+/// Permanently delete everything in the trash
+#[utoipa::path(
+    tag = "trash",
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/trash")]
+async fn empty_trash(
+    store: &State<AttachmentStore>,
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    _write: IdempotentWrite,
+) -> Result<Status, ApiError> {
+    services::trash::empty_trash(&mut conn, store, auth_user).await?;
+    Ok(Status::NoContent)
+}
+
+// GET /todos lists everything, or filters by ?description=... and ?completed=true/false
+/// List todo items, optionally filtered
+#[utoipa::path(
+    tag = "todos",
+    params(
+        TodoSearchQuery,
+        ("If-None-Match" = Option<String>, Header, description = "ETag of the list the client has, to get `304` if it is current"),
+    ),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "Matching items with their comment counts", body = Vec<TodoListItem>, headers(("ETag" = String, description = "Digest of the list"))),
+        (status = 304, description = "The list is unchanged since the `If-None-Match` ETag"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/todos?<search_query..>")]
 async fn list_or_search_todos(
-    pool: &State<PgPool>,
     auth_user: AuthenticatedUser,
+    mut conn: DbConn,
     search_query: TodoSearchQuery,
-) -> Result<Json<Vec<TodoItem>>, ApiError> {
-    Ok(services::todos::list_or_search_todos(pool, auth_user, search_query)?)
+) -> Result<Tagged<Json<Vec<TodoListItem>>>, ApiError> {
+    Ok(Tagged::hashed(conn.run(move |conn| services::todos::list_or_search_todos(conn, auth_user, search_query)).await?))
 }
 
 
-// GET /api/todos/search (this specific path is now covered by /api/todos?params...)
-// For backward compatibility or specific endpoint, keep or remove.
-// The tests seem to use /api/todos/search?description=
-// The previous list_or_search_todos should handle this.
-// If /api/todos/search without query params should list all, it's also handled.
+/// Same as `GET /todos`, kept for clients written against the original search endpoint
+#[utoipa::path(
+    tag = "todos",
+    params(
+        TodoSearchQuery,
+        ("If-None-Match" = Option<String>, Header, description = "ETag of the list the client has, to get `304` if it is current"),
+    ),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "Matching items with their comment counts", body = Vec<TodoListItem>, headers(("ETag" = String, description = "Digest of the list"))),
+        (status = 304, description = "The list is unchanged since the `If-None-Match` ETag"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/todos/search?<search_query..>")]
+async fn search_todos(
+    auth_user: AuthenticatedUser,
+    conn: DbConn,
+    search_query: TodoSearchQuery,
+) -> Result<Tagged<Json<Vec<TodoListItem>>>, ApiError> {
+    list_or_search_todos(auth_user, conn, search_query).await
+}
 
-#[get("/api/todos/count?<search_query..>")]
+/// Count todo items, optionally filtered
+#[utoipa::path(
+    tag = "todos",
+    params(TodoSearchQuery),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "Number of matching items", body = i64),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/todos/count?<search_query..>")]
 async fn get_todos_count(
-    pool: &State<PgPool>,
     auth_user: AuthenticatedUser,
+    mut conn: DbConn,
     search_query: TodoSearchQuery, // Re-use TodoSearchQuery for consistency
 ) -> Result<Json<i64>, ApiError> { // Diesel count returns i64
-    Ok(services::todos::get_todos_count(pool, auth_user, search_query)?)
+    Ok(conn.run(move |conn| services::todos::get_todos_count(conn, auth_user, search_query)).await?)
+}
+
+/// Fetch the changes to the caller's items since a sync token
+#[utoipa::path(
+    tag = "sync",
+    params(SyncQuery),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "Changed and deleted items, oldest change first, and the token to sync from next", body = SyncChanges),
+        (status = 400, description = "Invalid `since` or `limit`", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/sync?<query..>")]
+async fn get_sync_changes(
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    query: SyncQuery,
+) -> Result<Json<SyncChanges>, ApiError> {
+    Ok(conn.run(move |conn| services::sync::get_changes(conn, auth_user, query)).await?)
+}
+
+/// Apply a batch of changes made offline, reporting conflicts per item
+#[utoipa::path(
+    tag = "sync",
+    request_body = SyncRequest,
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "One result per mutation, in order: `applied`, `conflict` (with the server's item) or `rejected`", body = SyncResponse),
+        (status = 400, description = "Malformed body or too many mutations", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/sync", data = "<sync_req>")]
+async fn apply_sync_mutations(
+    sinks: ChangeSinks,
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    sync_req: Fingerprinted<Json<SyncRequest>>,
+) -> Result<Json<SyncResponse>, ApiError> {
+    let (ChangeSinks { events, metrics }, sync_req) = (sinks, sync_req.into_inner());
+    Ok(conn.run(move |conn| services::sync::apply_mutations(conn, &events, &metrics, auth_user, sync_req)).await?)
+}
+
+/// Stream changes to the caller's todo items as server-sent events
+#[utoipa::path(
+    tag = "events",
+    params(("Last-Event-ID" = Option<u64>, Header, description = "Id of the last event received, to resume after it")),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "Events named after the change (`created`, `updated`, `completed`, `deleted`, `restored`) \
+            with the item as data, or `resync` when missed events cannot be replayed", body = TodoItem, content_type = "text/event-stream"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/events")]
+fn todo_events(
+    config: &State<AppConfig>,
+    bus: &State<EventBus>,
+    auth_user: AuthenticatedUser,
+    last_event_id: LastEventId,
+    shutdown: Shutdown,
+) -> EventStream![Event + 'static] {
+    bus.stream(auth_user.user_id, last_event_id, config.events.heartbeat(), shutdown)
+}
+
+/// Open a WebSocket for subscribing to changes and issuing todo commands
+#[utoipa::path(
+    tag = "events",
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 101, description = "Switched to the WebSocket protocol. Clients send JSON commands \
+            (`subscribe`, `unsubscribe`, `create`, `complete`, `delete`) with an optional `id`, which is echoed \
+            in the `result` or `error` reply; `complete` and `delete` take an optional `version` that must match \
+            the item's, like `If-Match`. Each command counts towards the rate limit. Subscribers are also sent \
+            `event` and `resync` messages"),
+        (status = 400, description = "Not a WebSocket handshake", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Opened from a page on another site", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/ws")]
+fn todo_socket<'r>(
+    state: SocketState<'r>,
+    auth_user: AuthenticatedUser,
+    upgrade: WebSocketUpgrade,
+    request_id: RequestId,
+    shutdown: Shutdown,
+) -> WebSocketResponse<TodoSocket<'r>> {
+    upgrade.accept(TodoSocket { state, user: auth_user, request_id, shutdown })
+}
+
+// Comment routes (access follows the ownership of the parent todo item)
+/// Comment on a todo item
+#[utoipa::path(
+    tag = "comments",
+    params(("item_id_str" = Uuid, Path, description = "Todo item id")),
+    request_body = CommentRequest,
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The new comment", body = TodoComment),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such todo item", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/todos/<item_id_str>/comments", data = "<comment_req>")]
+async fn add_comment(
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    item_id_str: String,
+    comment_req: Fingerprinted<Json<CommentRequest>>,
+) -> Result<Json<TodoComment>, ApiError> {
+    let comment_req = comment_req.into_inner();
+    Ok(conn.run(move |conn| services::comments::add_comment(conn, auth_user, item_id_str, comment_req)).await?)
+}
+
+/// List the comments on a todo item, oldest first
+#[utoipa::path(
+    tag = "comments",
+    params(("item_id_str" = Uuid, Path, description = "Todo item id")),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The comments", body = Vec<TodoComment>),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such todo item", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/todos/<item_id_str>/comments")]
+async fn list_comments(
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    item_id_str: String,
+) -> Result<Json<Vec<TodoComment>>, ApiError> {
+    Ok(conn.run(move |conn| services::comments::list_comments(conn, auth_user, item_id_str)).await?)
+}
+
+/// Edit a comment
+#[utoipa::path(
+    tag = "comments",
+    params(
+        ("item_id_str" = Uuid, Path, description = "Todo item id"),
+        ("comment_id_str" = Uuid, Path, description = "Comment id"),
+    ),
+    request_body = CommentRequest,
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The edited comment", body = TodoComment),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such item or comment", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[put("/todos/<item_id_str>/comments/<comment_id_str>", data = "<comment_req>")]
+async fn update_comment(
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    item_id_str: String,
+    comment_id_str: String,
+    comment_req: Fingerprinted<Json<CommentRequest>>,
+) -> Result<Json<TodoComment>, ApiError> {
+    let comment_req = comment_req.into_inner();
+    Ok(conn.run(move |conn| services::comments::update_comment(conn, auth_user, item_id_str, comment_id_str, comment_req)).await?)
+}
+
+/// Delete a comment
+#[utoipa::path(
+    tag = "comments",
+    params(
+        ("item_id_str" = Uuid, Path, description = "Todo item id"),
+        ("comment_id_str" = Uuid, Path, description = "Comment id"),
+    ),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such item or comment", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/todos/<item_id_str>/comments/<comment_id_str>")]
+async fn delete_comment(
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    item_id_str: String,
+    comment_id_str: String,
+    _write: IdempotentWrite,
+) -> Result<Status, ApiError> {
+    conn.run(move |conn| services::comments::delete_comment(conn, auth_user, item_id_str, comment_id_str)).await?;
+    Ok(Status::NoContent)
+}
+
+// Attachment routes (multipart upload, contents kept in the configured storage backend)
+/// Attach a file to a todo item
+#[utoipa::path(
+    tag = "attachments",
+    params(("item_id_str" = Uuid, Path, description = "Todo item id")),
+    request_body(content = openapi::AttachmentUploadForm, content_type = "multipart/form-data"),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The stored attachment", body = TodoAttachment),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such todo item", body = Problem, content_type = "application/problem+json"),
+        (status = 413, description = "File exceeds `attachments.max_size`", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[post("/todos/<item_id_str>/attachments", data = "<upload>")]
+async fn add_attachment(
+    store: &State<AttachmentStore>,
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    item_id_str: String,
+    upload: Fingerprinted<Form<AttachmentUpload<'_>>>,
+) -> Result<Json<TodoAttachment>, ApiError> {
+    Ok(services::attachments::add_attachment(&mut conn, store, auth_user, item_id_str, upload.into_inner()).await?)
+}
+
+/// List the attachments of a todo item
+#[utoipa::path(
+    tag = "attachments",
+    params(("item_id_str" = Uuid, Path, description = "Todo item id")),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The attachments", body = Vec<TodoAttachment>),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such todo item", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/todos/<item_id_str>/attachments")]
+async fn list_attachments(
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    item_id_str: String,
+) -> Result<Json<Vec<TodoAttachment>>, ApiError> {
+    Ok(conn.run(move |conn| services::attachments::list_attachments(conn, auth_user, item_id_str)).await?)
+}
+
+/// Download an attachment
+#[utoipa::path(
+    tag = "attachments",
+    params(
+        ("item_id_str" = Uuid, Path, description = "Todo item id"),
+        ("attachment_id_str" = Uuid, Path, description = "Attachment id"),
+    ),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 200, description = "The file, with its recorded content type", content_type = "application/octet-stream"),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such item or attachment", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[get("/todos/<item_id_str>/attachments/<attachment_id_str>")]
+async fn download_attachment(
+    store: &State<AttachmentStore>,
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    item_id_str: String,
+    attachment_id_str: String,
+) -> Result<AttachmentDownload, ApiError> {
+    Ok(services::attachments::download_attachment(&mut conn, store, auth_user, item_id_str, attachment_id_str).await?)
+}
+
+/// Delete an attachment
+#[utoipa::path(
+    tag = "attachments",
+    params(
+        ("item_id_str" = Uuid, Path, description = "Todo item id"),
+        ("attachment_id_str" = Uuid, Path, description = "Attachment id"),
+    ),
+    security(("bearer" = []), ("session_cookie" = [])),
+    responses(
+        (status = 204, description = "Deleted"),
+        (status = 400, description = "Malformed id or invalid fields", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Missing, malformed or expired session token", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "No such item or attachment", body = Problem, content_type = "application/problem+json"),
+    )
+)]
+#[delete("/todos/<item_id_str>/attachments/<attachment_id_str>")]
+async fn delete_attachment(
+    store: &State<AttachmentStore>,
+    auth_user: AuthenticatedUser,
+    mut conn: DbConn,
+    item_id_str: String,
+    attachment_id_str: String,
+    _write: IdempotentWrite,
+) -> Result<Status, ApiError> {
+    services::attachments::delete_attachment(&mut conn, store, auth_user, item_id_str, attachment_id_str).await?;
+    Ok(Status::NoContent)
+}
+
+// Liveness: the process is up and serving requests
+#[get("/healthz")]
+fn healthz() -> Json<health::HealthReport> {
+    Json(health::HealthReport::alive())
+}
+
+// Readiness: the database is reachable and the schema is current. 503 otherwise.
+#[get("/readyz")]
+async fn readyz(pool: &State<PgPool>) -> Result<(Status, Json<health::HealthReport>), ApiError> {
+    let pool = pool.inner().clone();
+    let report = rocket::tokio::task::spawn_blocking(move || health::check_readiness(&pool))
+        .await
+        .map_err(|e| ApiError::InternalError(InternalErrorDetail(format!("Readiness check failed: {}", e))))?;
+    let status = if report.is_ok() { Status::Ok } else { Status::ServiceUnavailable };
+    Ok((status, Json(report)))
+}
+
+// Pool usage for monitoring (no authentication, like a health check)
+#[get("/status/pool")]
+fn pool_status(pool: &State<PgPool>, metrics: &State<PoolMetrics>) -> Json<PoolStatus> {
+    Json(db::pool_status(pool, metrics))
+}
+
+// Prometheus scrape endpoint; requires `metrics.token` as a bearer token if one is set
+#[get("/metrics")]
+fn metrics_endpoint(
+    _access: MetricsAccess,
+    pool: &State<PgPool>,
+    metrics: &State<Metrics>,
+) -> Result<(ContentType, String), ApiError> {
+    let body = metrics
+        .render(pool)
+        .map_err(|e| ApiError::InternalError(InternalErrorDetail(format!("Failed to render metrics: {}", e))))?;
+    Ok((ContentType::new("text", "plain").with_params(("version", "0.0.4")), body))
 }
 
 #[get("/")]
-async fn index() -> Option<NamedFile> {
-    NamedFile::open(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/static/index.html"))).await.ok()
+fn index(assets: &State<StaticAssets>) -> Option<RawHtml<String>> {
+    assets.index.clone().map(RawHtml)
 }
 
 
 // --- Rocket instance setup ---
 
-use rocket::serde::json::{json, Value};
 
-#[catch(401)]
-fn unauthorized_catcher(_req: &rocket::Request<'_>) -> Json<Value> {
-    // This catcher will be invoked for any 401 Unauthorized error.
-    // The test `test_logout_and_attempt_access` specifically checks for
-    // the JSON body `{"error": "invalid_token"}` after a logout
-    // and subsequent access attempt.
-    Json(json!({ "error": "invalid_token" }))
+// Paths served to API clients, which get problem details instead of HTML pages
+const API_PREFIXES: &[&str] = &["/api", "/auth"];
+
+fn wants_json(request: &rocket::Request<'_>) -> bool {
+    let path = request.uri().path();
+    API_PREFIXES
+        .iter()
+        .any(|prefix| path == *prefix || path.as_str().starts_with(&format!("{}/", prefix)))
+        || request.accept().is_some_and(|accept| accept.preferred().is_json())
 }
 
-#[catch(404)]
-fn not_found_catcher(_: &rocket::Request) -> rocket::response::content::RawHtml<String> {
-    rocket::response::content::RawHtml(format!(
-        "<p>Sorry, but the page you were looking for could not be found.</p>"
-    ))
+// What `default_catcher` sends back
+#[derive(Responder)]
+enum CaughtError {
+    Replayed(idempotency::StoredResponse),
+    Api(ApiError),
+    Problem(Problem),
+    Page(RawHtml<String>),
+}
+
+// Problem details for errors raised by Rocket itself (no route, unparseable
+// body or query) rather than by one of our guards
+fn generic_problem(status: Status) -> ErrorDetail {
+    let (code, detail) = match status.code {
+        400 => ("bad_request", "The request body or query string is malformed"),
+        401 => ("unauthorized", "Authentication required"),
+        404 => ("not_found", "Nothing exists at this path"),
+        413 => ("payload_too_large", "The request body is too large"),
+        422 => ("invalid_body", "The request body is missing fields or is not valid JSON"),
+        503 => ("service_unavailable", "The service is temporarily unavailable"),
+        _ => {
+            let reason = status.reason_lossy();
+            return ErrorDetail::new(reason.to_lowercase().replace([' ', '-'], "_"), reason);
+        }
+    };
+    ErrorDetail::new(code, detail)
+}
+
+#[catch(default)]
+fn default_catcher(status: Status, request: &rocket::Request<'_>) -> CaughtError {
+    if let Some(stored) = idempotency::replayed(request) {
+        return CaughtError::Replayed(stored);
+    }
+
+    // A guard of the route that failed with an `ApiError` left it behind;
+    // send its reason. One left by a fairing, or by a route that forwarded
+    // to the one that failed, says nothing about this failure.
+    let failed = request
+        .local_cache(|| GuardFailure(std::sync::Mutex::new(None)))
+        .0
+        .lock()
+        .unwrap()
+        .take();
+    let route = route_key(request);
+    if let Some((_, error)) = failed.filter(|(failed_route, error)| {
+        route.is_some() && *failed_route == route && error.status() == status
+    }) {
+        return CaughtError::Api(error);
+    }
+
+    if !wants_json(request) {
+        let page = match status.code {
+            404 => "<p>Sorry, but the page you were looking for could not be found.</p>".to_string(),
+            _ => format!("<p>{}</p>", status),
+        };
+        return CaughtError::Page(RawHtml(page));
+    }
+    if status.code >= 500 && status != Status::ServiceUnavailable {
+        let message = format!("request failed with {}", status);
+        return CaughtError::Api(ApiError::InternalError(InternalErrorDetail(message)));
+    }
+    CaughtError::Problem(Problem::new(status, request, generic_problem(status)))
+}
+
+// The auth handlers, mounted under `/api/v1/auth` (and the legacy `/auth`)
+fn auth_routes(features: &config::Features) -> Vec<rocket::Route> {
+    let mut routes = routes![login_user, logout_user];
+    if features.registration {
+        routes.extend(routes![register_user]);
+    }
+    routes
+}
+
+// The todo handlers that predate versioning, mounted under `/api/v1` (and the legacy `/api`)
+fn api_routes(features: &config::Features) -> Vec<rocket::Route> {
+    let mut routes = routes![
+        add_todo_item,
+        get_todo_item,
+        complete_todo_item,
+        update_todo_item,
+        get_item_history,
+        delete_todo_item,
+        list_trash,
+        restore_todo_item,
+        purge_todo_item,
+        empty_trash,
+        undo,
+        list_or_search_todos,
+        search_todos,
+        get_todos_count,
+    ];
+    if features.comments {
+        routes.extend(routes![add_comment, list_comments, update_comment, delete_comment]);
+    }
+    if features.attachments {
+        routes.extend(routes![add_attachment, list_attachments, download_attachment, delete_attachment]);
+    }
+    routes
+}
+
+// Handlers added since, only under `/api/v1`
+fn v1_routes() -> Vec<rocket::Route> {
+    routes![get_sync_changes, apply_sync_mutations, todo_events, todo_socket]
+}
+
+// Mounts the API at its versioned and (unless disabled) legacy paths, with the
+// route groups enabled in `features`, and the static files
+fn configured_routes() -> AdHoc {
+    AdHoc::on_ignite("Configured Routes", |rocket| async {
+        let Some(config) = rocket.state::<AppConfig>().cloned() else {
+            return rocket;
+        };
+
+        let mut rocket = rocket
+            .mount(assets::STATIC_PATH, FileServer::from(&config.static_dir))
+            .mount(versioning::API_V1_AUTH, auth_routes(&config.features))
+            .mount(versioning::API_V1, api_routes(&config.features))
+            .mount(versioning::API_V1, v1_routes());
+        if config.legacy_api.enabled {
+            rocket = rocket
+                .mount(versioning::LEGACY_AUTH, auth_routes(&config.features))
+                .mount(versioning::LEGACY_API, api_routes(&config.features))
+                .attach(versioning::LegacyApiHeaders::new(&config.legacy_api));
+        }
+        rocket
+    })
 }
 
 pub fn rocket_instance() -> Rocket<Build> {
     dotenvy::dotenv().ok(); // Load .env file
-    rocket::build()
+    rocket::custom(config::figment())
+        .attach(config::stage()) // Validate the configuration first; the other stages read it
         .attach(db::stage()) // Attach the DB pool fairing
-        .register("/", catchers![unauthorized_catcher, not_found_catcher]) // Register the catcher
-        .mount(
-            "/",
-            routes![
-                register_user,
-                login_user,
-                logout_user,
-                add_todo_item,
-                get_todo_item,
-                complete_todo_item,
-                delete_todo_item,
-                list_or_search_todos, // This handles /api/todos and /api/todos?params
-                get_todos_count,
-                index,
-                // Static file serving (if you had it before)
-                // e.g. rocket_contrib::serve::StaticFiles::from(concat!(env!("CARGO_MANIFEST_DIR"), "/static"))
-            ],
-        )
-        // Potentially add static file serving if it was part of the original app
-        .mount("/static", FileServer::from(concat!(env!("CARGO_MANIFEST_DIR"), "/static")))
+        .attach(storage::stage()) // Attach the attachment storage backend
+        .attach(events::stage()) // Fan out todo changes to event streams
+        .attach(services::trash::stage()) // Purge expired items from the trash
+        .attach(idempotency::stage()) // Purge expired idempotency keys
+        .attach(ratelimit::stage()) // Per-user and per-IP request limits
+        .attach(cors::stage()) // Cross-origin access for separately hosted frontends
+        .attach(assets::stage()) // index.html and content-hashed asset URLs
+        .attach(security::stage()) // CSP, nosniff, Referrer-Policy and HSTS
+        .attach(metrics::stage()) // Prometheus metrics at /metrics
+        .attach(logging::RequestLogger) // X-Request-Id and access log
+        .attach(ratelimit::RateLimit) // 429 for clients over their limit; before anything else does work
+        .attach(idempotency::Idempotency) // Replay writes retried with an Idempotency-Key
+        .attach(configured_routes())
+        .register("/", catchers![default_catcher])
+        .mount("/", routes![healthz, readyz, pool_status, metrics_endpoint, index])
+        .mount("/", openapi::routes()) // OpenAPI document and Swagger UI
+        .mount("/", idempotency::routes())
 }
 
 // Add any necessary `use` statements at the top of lib.rs for new modules like `schema` and `models`.
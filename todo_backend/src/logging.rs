@@ -0,0 +1,138 @@
+// todo_backend/src/logging.rs
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::Deserialize;
+use rocket::{Data, Response};
+use std::time::Instant;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+// Longest client-supplied request id we propagate instead of replacing
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// The `[logging]` table in Rocket.toml (or `APP_LOGGING__*`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// An `EnvFilter` directive such as `info` or `todo_backend=debug,info`;
+    /// `RUST_LOG` takes precedence when set.
+    pub filter: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::Text,
+            filter: "info".to_string(),
+        }
+    }
+}
+
+/// Installs the global `tracing` subscriber. Rocket's own `log` records are
+/// forwarded to it, so this must run before the Rocket instance is built.
+pub fn init(config: &LoggingConfig) -> Result<(), String> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&config.filter)
+            .map_err(|e| format!("invalid logging.filter `{}`: {}", config.filter, e))?,
+    };
+
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    let installed = match config.format {
+        LogFormat::Json => subscriber.json().with_current_span(true).with_span_list(true).try_init(),
+        LogFormat::Text => subscriber.try_init(),
+    };
+    installed.map_err(|e| e.to_string())
+}
+
+/// Identifies a request in logs and in error responses: the client's
+/// `X-Request-Id` when it looks sane, a fresh UUID otherwise.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r str {
+        &request.local_cache(|| RequestId(Uuid::new_v4().to_string())).0
+    }
+
+    fn accept(value: &str) -> bool {
+        !value.is_empty()
+            && value.len() <= MAX_REQUEST_ID_LEN
+            && value.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(RequestId(RequestId::of(request).to_string()))
+    }
+}
+
+/// The route template a request matched, e.g. `/api/todos/<item_id_str>`.
+pub fn route_of(request: &Request<'_>) -> String {
+    request
+        .route()
+        .map(|route| route.uri.origin.path().to_string())
+        .unwrap_or_else(|| "unmatched".to_string())
+}
+
+// When the current request started, for the access log
+struct Started(Option<Instant>);
+
+/// Assigns every request its [`RequestId`], echoes it in the `X-Request-Id`
+/// response header, and writes one access log line per request.
+pub struct RequestLogger;
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request IDs and Access Log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let incoming = request
+            .headers()
+            .get_one(REQUEST_ID_HEADER)
+            .filter(|value| RequestId::accept(value))
+            .map(str::to_string);
+        request.local_cache(|| RequestId(incoming.unwrap_or_else(|| Uuid::new_v4().to_string())));
+        request.local_cache(|| Started(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let request_id = RequestId::of(request);
+        response.set_header(Header::new(REQUEST_ID_HEADER, request_id.to_string()));
+
+        let latency_ms = request
+            .local_cache(|| Started(None))
+            .0
+            .map(|started| started.elapsed().as_secs_f64() * 1000.0);
+        tracing::info!(
+            request_id,
+            method = %request.method(),
+            route = %route_of(request),
+            status = response.status().code,
+            latency_ms,
+            "request completed"
+        );
+    }
+}
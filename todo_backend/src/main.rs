@@ -1,11 +1,70 @@
 // todo_backend/src/main.rs
+use todo_backend::config::{self, AppConfig};
+use todo_backend::db;
+use todo_backend::logging::{self, LoggingConfig};
 use todo_backend::rocket_instance; // Use the lib's rocket_instance
 
+const MIGRATE_USAGE: &str = "usage: todo_backend_server migrate [up|down|status]";
+
+// `todo_backend_server migrate ...` manages the schema without starting the server
+fn migrate(subcommand: Option<&str>) -> Result<(), db::MigrationError> {
+    let config: AppConfig = config::figment().extract()?;
+    let mut conn = db::establish_connection(config.database_url()?)?;
+    match subcommand.unwrap_or("up") {
+        "up" => {
+            let applied = db::run_pending_migrations(&mut conn)?;
+            if applied.is_empty() {
+                println!("No pending migrations");
+            }
+            for version in applied {
+                println!("Applied {}", version);
+            }
+        }
+        "down" => {
+            let reverted = db::revert_last_migration(&mut conn)?;
+            println!("Reverted {}", reverted);
+        }
+        "status" => {
+            for (name, applied) in db::migration_status(&mut conn)? {
+                println!("[{}] {}", if applied { "X" } else { " " }, name);
+            }
+        }
+        other => return Err(format!("unknown migrate subcommand `{}`\n{}", other, MIGRATE_USAGE).into()),
+    }
+    Ok(())
+}
+
 #[rocket::main]
+#[allow(clippy::result_large_err)] // rocket::Error is large, but main only returns it once
 async fn main() -> Result<(), rocket::Error> {
     // Load .env file for database URL and other configurations
     dotenvy::dotenv().ok(); // Use dotenvy
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("migrate") => {
+            if let Err(e) = migrate(args.get(1).map(String::as_str)) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(other) => {
+            eprintln!("Error: unknown command `{}`\n{}", other, MIGRATE_USAGE);
+            std::process::exit(2);
+        }
+        None => {}
+    }
+
+    // Before building Rocket, so that its log output goes through `tracing` too.
+    // An invalid `logging` table is reported by the config check at ignition.
+    let logging_config = config::figment()
+        .extract_inner::<LoggingConfig>("logging")
+        .unwrap_or_default();
+    if let Err(e) = logging::init(&logging_config) {
+        eprintln!("Error: failed to initialize logging: {}", e);
+    }
+
     let _rocket = rocket_instance()
         .launch()
         .await?;
@@ -0,0 +1,198 @@
+// todo_backend/src/metrics.rs
+use crate::config::AppConfig;
+use crate::db::{PgPool, PoolMetrics};
+use crate::logging::route_of;
+use crate::services::history::ItemAction;
+use crate::{ApiError, ErrorDetail};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
+use rocket::{Data, Response};
+use sha2::{Digest, Sha256};
+use std::time::Instant;
+
+/// The `[metrics]` table in Rocket.toml (or `APP_METRICS__*`).
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// When set, `/metrics` requires `Authorization: Bearer <token>`.
+    pub token: Option<String>,
+}
+
+/// Prometheus collectors for one Rocket instance, rendered at `/metrics`.
+/// Clones share the collectors.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    pool_connections: IntGauge,
+    pool_idle_connections: IntGauge,
+    pool_max_size: IntGauge,
+    todos_created: IntCounter,
+    todos_completed: IntCounter,
+    logins: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new();
+        let metrics = Metrics {
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests by route and status class"),
+                &["method", "route", "status"],
+            )?,
+            request_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "HTTP request latency by route"),
+                &["method", "route"],
+            )?,
+            pool_connections: IntGauge::new("db_pool_connections", "Open database connections")?,
+            pool_idle_connections: IntGauge::new("db_pool_idle_connections", "Idle database connections")?,
+            pool_max_size: IntGauge::new("db_pool_max_size", "Maximum database connections")?,
+            todos_created: IntCounter::new("todos_created_total", "Todo items created")?,
+            todos_completed: IntCounter::new("todos_completed_total", "Todo items marked completed")?,
+            logins: IntCounterVec::new(Opts::new("logins_total", "Login attempts by outcome"), &["outcome"])?,
+            registry,
+        };
+
+        metrics.registry.register(Box::new(metrics.requests.clone()))?;
+        metrics.registry.register(Box::new(metrics.request_duration.clone()))?;
+        metrics.registry.register(Box::new(metrics.pool_connections.clone()))?;
+        metrics.registry.register(Box::new(metrics.pool_idle_connections.clone()))?;
+        metrics.registry.register(Box::new(metrics.pool_max_size.clone()))?;
+        metrics.registry.register(Box::new(metrics.todos_created.clone()))?;
+        metrics.registry.register(Box::new(metrics.todos_completed.clone()))?;
+        metrics.registry.register(Box::new(metrics.logins.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Counts a committed change to an item, whichever way it was made.
+    pub fn record_change(&self, action: ItemAction) {
+        match action {
+            ItemAction::Created => self.todos_created.inc(),
+            ItemAction::Completed => self.todos_completed.inc(),
+            _ => {}
+        }
+    }
+
+    /// Counts a login that succeeded, or failed on its credentials.
+    pub fn record_login(&self, succeeded: bool) {
+        let outcome = if succeeded { "succeeded" } else { "failed" };
+        self.logins.with_label_values(&[outcome]).inc();
+    }
+
+    /// Samples the pool and encodes everything in the Prometheus text format.
+    pub fn render(&self, pool: &PgPool) -> prometheus::Result<String> {
+        let state = pool.state();
+        self.pool_connections.set(state.connections.into());
+        self.pool_idle_connections.set(state.idle_connections.into());
+        self.pool_max_size.set(pool.max_size().into());
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
+
+// When the current request started, kept in the request-local cache
+struct RequestStart(Option<Instant>);
+
+/// Records the count and latency of every request, labelled by route template
+/// (e.g. `/api/todos/<item_id_str>`) so that ids don't explode cardinality.
+pub struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(metrics) = request.rocket().state::<Metrics>() else {
+            return;
+        };
+        let route = route_of(request);
+        let method = request.method().as_str();
+        let status = format!("{}xx", response.status().code / 100);
+
+        metrics.requests.with_label_values(&[method, &route, &status]).inc();
+        if let Some(started) = request.local_cache(|| RequestStart(None)).0 {
+            metrics
+                .request_duration
+                .with_label_values(&[method, &route])
+                .observe(started.elapsed().as_secs_f64());
+        }
+    }
+}
+
+/// Request guard for `/metrics`: passes when no `metrics.token` is configured,
+/// otherwise requires it as a bearer token.
+pub struct MetricsAccess;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for MetricsAccess {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let expected = request
+            .rocket()
+            .state::<AppConfig>()
+            .and_then(|config| config.metrics.token.as_deref());
+        let Some(expected) = expected else {
+            return request::Outcome::Success(MetricsAccess);
+        };
+
+        let provided = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if provided.is_some_and(|provided| token_matches(provided, expected)) {
+            request::Outcome::Success(MetricsAccess)
+        } else {
+            ApiError::Unauthorized(Json(ErrorDetail::new(
+                "invalid_metrics_token",
+                "A valid metrics token is required",
+            )))
+            .fail_guard(request)
+        }
+    }
+}
+
+// Compares digests of the tokens so the time taken does not tell how much of
+// the token was right
+fn token_matches(provided: &str, expected: &str) -> bool {
+    let (provided, expected) = (Sha256::digest(provided), Sha256::digest(expected));
+    provided.iter().zip(expected.iter()).fold(0, |differences, (a, b)| differences | (a ^ b)) == 0
+}
+
+// Fairing that sets up the collectors and starts recording requests
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Prometheus Metrics", |rocket| async {
+        let metrics = match Metrics::new() {
+            Ok(metrics) => metrics,
+            Err(e) => {
+                tracing::error!(error = %e, "failed to set up metrics");
+                return Err(rocket);
+            }
+        };
+        if let Some(pool_metrics) = rocket.state::<PoolMetrics>() {
+            if let Err(e) = pool_metrics.register(&metrics.registry) {
+                tracing::error!(error = %e, "failed to register pool metrics");
+                return Err(rocket);
+            }
+        }
+        Ok(rocket.manage(metrics).attach(RequestMetrics))
+    })
+}
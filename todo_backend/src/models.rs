@@ -1,9 +1,10 @@
 // todo_backend/src/models.rs
-use crate::schema::{users, todo_items};
+use crate::schema::{users, todo_items, todo_comments, todo_attachments, todo_item_events, undo_tokens, idempotency_keys};
 use diesel::prelude::*;
 use rocket::serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::NaiveDateTime;
+use utoipa::{IntoParams, ToSchema};
 
 #[derive(Queryable, Identifiable, Selectable, Serialize, Debug, PartialEq, Clone)]
 #[diesel(table_name = users)]
@@ -25,7 +26,7 @@ pub struct NewUser<'a> {
 }
 
 // For returning user info without password hash
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct UserInfo {
     pub id: Uuid,
@@ -46,7 +47,7 @@ impl From<User> for UserInfo {
 }
 
 
-#[derive(Queryable, Identifiable, Selectable, Associations, Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[derive(Queryable, Identifiable, Selectable, Associations, Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
 #[diesel(belongs_to(User))]
 #[diesel(table_name = todo_items)]
 #[serde(crate = "rocket::serde")]
@@ -57,6 +58,13 @@ pub struct TodoItem {
     pub completed: bool,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub deleted_at: Option<NaiveDateTime>, // Set while the item is in the trash
+    /// Position of the item's latest write in the sync change order.
+    #[serde(default)] // Absent from snapshots taken before it was added
+    pub change_seq: i64,
+    /// Incremented by every write; the item's `ETag`.
+    #[serde(default)]
+    pub version: i32,
 }
 
 #[derive(Insertable, Deserialize)]
@@ -68,15 +76,284 @@ pub struct NewTodoItem {
 }
 
 // Used for creating a todo item from a request (user_id will be from auth)
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct CreateTodoRequest {
     pub description: String,
 }
 
-#[derive(Deserialize, Debug, rocket::form::FromForm)]
+// Partial update of a todo item; omitted fields are left unchanged
+#[derive(Deserialize, Debug, Default, ToSchema)]
 #[serde(crate = "rocket::serde")]
+pub struct UpdateTodoRequest {
+    pub description: Option<String>,
+    pub completed: Option<bool>,
+}
+
+// A todo item as returned by list endpoints, with per-item aggregates
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct TodoListItem {
+    #[serde(flatten)]
+    pub item: TodoItem,
+    pub comment_count: i64,
+}
+
+#[derive(Deserialize, Debug, rocket::form::FromForm, IntoParams)]
+#[serde(crate = "rocket::serde")]
+#[into_params(parameter_in = Query)]
 pub struct TodoSearchQuery {
+    /// Only items whose description contains this text (case-insensitive).
     pub description: Option<String>,
-    pub completed: Option<bool>, // Add this for filtering by completion status
+    /// Only completed (`true`) or open (`false`) items.
+    pub completed: Option<bool>,
+}
+
+#[derive(Queryable, Identifiable, Selectable, Associations, Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+#[diesel(belongs_to(TodoItem))]
+#[diesel(belongs_to(User, foreign_key = author_id))]
+#[diesel(table_name = todo_comments)]
+#[serde(crate = "rocket::serde")]
+pub struct TodoComment {
+    pub id: Uuid,
+    pub todo_item_id: Uuid,
+    pub author_id: Uuid,
+    pub body: String,
+    pub edited: bool, // Set once the body has been changed after posting
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    #[serde(skip_serializing)] // Soft-deleted comments are never returned
+    pub deleted_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = todo_comments)]
+pub struct NewTodoComment {
+    pub todo_item_id: Uuid,
+    pub author_id: Uuid,
+    pub body: String,
+}
+
+// Used for creating or editing a comment (author and item come from the route/auth)
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct CommentRequest {
+    pub body: String,
+}
+
+#[derive(Queryable, Identifiable, Selectable, Associations, Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+#[diesel(belongs_to(TodoItem))]
+#[diesel(belongs_to(User, foreign_key = uploader_id))]
+#[diesel(table_name = todo_attachments)]
+#[serde(crate = "rocket::serde")]
+pub struct TodoAttachment {
+    pub id: Uuid,
+    pub todo_item_id: Uuid,
+    pub uploader_id: Uuid,
+    pub file_name: String,
+    pub content_type: String,
+    pub size_bytes: i64,
+    pub sha256: String, // Hex digest of the stored contents
+    #[serde(skip)] // Backend-specific location, not part of the API
+    pub storage_key: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = todo_attachments)]
+pub struct NewTodoAttachment {
+    pub id: Uuid,
+    pub todo_item_id: Uuid,
+    pub uploader_id: Uuid,
+    pub file_name: String,
+    pub content_type: String,
+    pub size_bytes: i64,
+    pub sha256: String,
+    pub storage_key: String,
+}
+
+// Multipart form for `POST /api/todos/<id>/attachments`
+#[derive(rocket::form::FromForm)]
+pub struct AttachmentUpload<'r> {
+    pub file: rocket::data::Capped<rocket::fs::TempFile<'r>>,
+}
+
+// One entry in an item's audit history. `before`/`after` hold only the fields
+// that changed (or the whole item for creations and deletions).
+#[derive(Queryable, Identifiable, Selectable, Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+#[diesel(table_name = todo_item_events)]
+#[serde(crate = "rocket::serde")]
+pub struct TodoItemEvent {
+    pub id: Uuid,
+    pub todo_item_id: Uuid,
+    pub owner_id: Uuid,
+    pub actor_id: Uuid,
+    pub action: String,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = todo_item_events)]
+pub struct NewTodoItemEvent {
+    pub todo_item_id: Uuid,
+    pub owner_id: Uuid,
+    pub actor_id: Uuid,
+    pub action: String,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+#[derive(Queryable, Identifiable, Selectable, Debug, Clone)]
+#[diesel(table_name = undo_tokens)]
+pub struct UndoToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub todo_item_id: Uuid,
+    pub action: String,
+    pub previous: serde_json::Value, // Snapshot of the item before the operation
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = undo_tokens)]
+pub struct NewUndoToken {
+    pub user_id: Uuid,
+    pub todo_item_id: Uuid,
+    pub action: String,
+    pub previous: serde_json::Value,
+    pub expires_at: NaiveDateTime,
+}
+
+// A write sent with an `Idempotency-Key`; `status` is unset until it has been answered
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = idempotency_keys)]
+pub struct IdempotencyRecord {
+    pub user_id: Uuid,
+    pub idempotency_key: String,
+    pub request_hash: String,
+    pub status: Option<i16>,
+    pub headers: serde_json::Value, // `[name, value]` pairs
+    pub body: Vec<u8>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = idempotency_keys)]
+pub struct NewIdempotencyRecord<'a> {
+    pub user_id: Uuid,
+    pub idempotency_key: &'a str,
+    pub request_hash: &'a str,
+}
+
+// Response of an operation that can be reverted with `POST /api/undo/<undo_token>`
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Undoable<T> {
+    #[serde(flatten)]
+    pub item: T,
+    pub undo_token: Uuid,
+    pub undo_expires_at: NaiveDateTime,
+}
+
+// Query parameters of `GET /api/v1/sync`
+#[derive(Debug, rocket::form::FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SyncQuery {
+    /// The `token` returned by the previous sync; omit it for a full sync.
+    pub since: Option<i64>,
+    /// Maximum number of changes to return (default 500, at most 1000).
+    pub limit: Option<i64>,
+}
+
+/// An item that left the list (trashed or purged) since the last sync.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Tombstone {
+    pub id: Uuid,
+    pub change_seq: i64,
+}
+
+// Response of `GET /api/v1/sync`
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct SyncChanges {
+    /// Items created or changed since `since`, in change order.
+    pub items: Vec<TodoItem>,
+    /// Items deleted since `since`, in change order.
+    pub deleted: Vec<Tombstone>,
+    /// The `since` of the next sync.
+    pub token: i64,
+    /// Set when `limit` cut the changes short; sync again with `token` for the rest.
+    pub has_more: bool,
+}
+
+/// A change made on the client. `base_version` is the item's `version` as the
+/// client last saw it, the same token `If-Match` carries; the change conflicts
+/// if the item moved on since.
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(crate = "rocket::serde", tag = "op", rename_all = "snake_case")]
+pub enum SyncMutation {
+    Create {
+        id: Uuid,
+        description: String,
+        #[serde(default)]
+        completed: bool,
+    },
+    Update {
+        id: Uuid,
+        base_version: i32,
+        description: Option<String>,
+        completed: Option<bool>,
+    },
+    Delete {
+        id: Uuid,
+        base_version: i32,
+    },
+}
+
+impl SyncMutation {
+    pub fn id(&self) -> Uuid {
+        match self {
+            SyncMutation::Create { id, .. } | SyncMutation::Update { id, .. } | SyncMutation::Delete { id, .. } => *id,
+        }
+    }
+}
+
+// Body of `POST /api/v1/sync`; mutations are applied in order
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct SyncRequest {
+    pub mutations: Vec<SyncMutation>,
+}
+
+/// The outcome of one mutation. On a conflict, `item` is the item as the
+/// server has it, or absent if it was deleted.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+#[serde(crate = "rocket::serde", tag = "status", rename_all = "snake_case")]
+pub enum SyncResult {
+    Applied {
+        id: Uuid,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        item: Option<TodoItem>,
+    },
+    Conflict {
+        id: Uuid,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        item: Option<TodoItem>,
+    },
+    Rejected {
+        id: Uuid,
+        error: crate::ErrorDetail,
+    },
+}
+
+// Response of `POST /api/v1/sync`, one result per mutation
+#[derive(Serialize, Debug, Clone, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct SyncResponse {
+    pub results: Vec<SyncResult>,
 }
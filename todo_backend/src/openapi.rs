@@ -0,0 +1,166 @@
+// todo_backend/src/openapi.rs
+use utoipa::openapi::path::{Operation, ParameterBuilder, ParameterIn};
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::header::Header;
+use utoipa::openapi::schema::{Object, Type};
+use utoipa::openapi::{ContentBuilder, Ref, ResponseBuilder};
+use utoipa::{Modify, OpenApi, PartialSchema, ToSchema};
+use utoipa_swagger_ui::SwaggerUi;
+
+/// Where the generated document is served.
+pub const SPEC_PATH: &str = "/api/openapi.json";
+
+/// Where the Swagger UI is served.
+pub const DOCS_PATH: &str = "/api/docs";
+
+/// The OpenAPI 3 description of the `/api/v1` routes, generated from the
+/// `#[utoipa::path]` attributes on the handlers in `lib.rs`. Paths are relative
+/// to the `/api/v1` server.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "webtodo API",
+        version = "1",
+        description = "Errors are RFC 7807 problem details with a stable `error` code. \
+            The unversioned `/api/*` and `/auth/*` paths are deprecated aliases of these routes."
+    ),
+    servers((url = "/api/v1")),
+    paths(
+        crate::register_user,
+        crate::login_user,
+        crate::logout_user,
+        crate::add_todo_item,
+        crate::list_or_search_todos,
+        crate::search_todos,
+        crate::get_todos_count,
+        crate::get_sync_changes,
+        crate::apply_sync_mutations,
+        crate::todo_events,
+        crate::todo_socket,
+        crate::get_todo_item,
+        crate::update_todo_item,
+        crate::complete_todo_item,
+        crate::delete_todo_item,
+        crate::get_item_history,
+        crate::undo,
+        crate::list_trash,
+        crate::restore_todo_item,
+        crate::purge_todo_item,
+        crate::empty_trash,
+        crate::add_comment,
+        crate::list_comments,
+        crate::update_comment,
+        crate::delete_comment,
+        crate::add_attachment,
+        crate::list_attachments,
+        crate::download_attachment,
+        crate::delete_attachment,
+    ),
+    components(schemas(crate::ErrorDetail)),
+    modifiers(&SessionAuth, &IdempotencyKeys, &RateLimits),
+    tags(
+        (name = "auth", description = "Accounts and sessions"),
+        (name = "todos", description = "Todo items"),
+        (name = "trash", description = "Deleted items, kept for `trash.retention_days`"),
+        (name = "comments", description = "Comments on todo items"),
+        (name = "attachments", description = "Files attached to todo items"),
+        (name = "sync", description = "Offline sync with change tokens"),
+        (name = "events", description = "Real-time change notifications"),
+    )
+)]
+pub struct ApiDoc;
+
+// Sessions from `/auth/login` are accepted as a bearer token or as the cookie
+struct SessionAuth;
+
+impl Modify for SessionAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+        );
+        components.add_security_scheme(
+            "session_cookie",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("session_token"))),
+        );
+    }
+}
+
+// An error response documented on many operations at once
+fn problem(description: &str) -> ResponseBuilder {
+    ResponseBuilder::new()
+        .description(description)
+        .content("application/problem+json", ContentBuilder::new().schema(Some(Ref::from_schema_name("Problem"))).build())
+}
+
+// Every signed-in write takes an `Idempotency-Key` (see `crate::idempotency`)
+struct IdempotencyKeys;
+
+impl IdempotencyKeys {
+    fn document(operation: &mut Operation) {
+        if operation.security.is_none() {
+            return;
+        }
+        operation.parameters.get_or_insert_with(Vec::new).push(
+            ParameterBuilder::new()
+                .name(crate::idempotency::KEY_HEADER)
+                .parameter_in(ParameterIn::Header)
+                .description(Some("Unique key for this write; retries with it get the first response back"))
+                .schema(Some(String::schema()))
+                .build(),
+        );
+        let responses = &mut operation.responses.responses;
+        responses
+            .entry("409".to_string())
+            .or_insert_with(|| problem("A request with the `Idempotency-Key` is still in progress").build().into());
+        responses
+            .entry("422".to_string())
+            .or_insert_with(|| problem("The `Idempotency-Key` was used for a different request").build().into());
+    }
+}
+
+impl Modify for IdempotencyKeys {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        for item in openapi.paths.paths.values_mut() {
+            for operation in [&mut item.post, &mut item.put, &mut item.patch, &mut item.delete].into_iter().flatten() {
+                IdempotencyKeys::document(operation);
+            }
+        }
+    }
+}
+
+// Every operation is rate limited (see `crate::ratelimit`)
+struct RateLimits;
+
+impl Modify for RateLimits {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let integer = || Header::new(Object::with_type(Type::Integer));
+        for item in openapi.paths.paths.values_mut() {
+            let operations = [&mut item.get, &mut item.post, &mut item.put, &mut item.patch, &mut item.delete];
+            for operation in operations.into_iter().flatten() {
+                let limited = problem("Too many requests; retry after `Retry-After` seconds")
+                    .header("Retry-After", integer())
+                    .header("X-RateLimit-Limit", integer())
+                    .header("X-RateLimit-Remaining", integer())
+                    .header("X-RateLimit-Reset", integer())
+                    .build();
+                operation.responses.responses.insert("429".to_string(), limited.into());
+            }
+        }
+    }
+}
+
+/// The multipart body of an attachment upload (the handler reads it as a form).
+#[derive(ToSchema)]
+pub struct AttachmentUploadForm {
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+}
+
+/// Routes serving the document at [`SPEC_PATH`] and the Swagger UI at [`DOCS_PATH`].
+pub fn routes() -> Vec<rocket::Route> {
+    SwaggerUi::new(format!("{}/<_..>", DOCS_PATH))
+        .url(SPEC_PATH, ApiDoc::openapi())
+        .into()
+}
@@ -0,0 +1,311 @@
+// todo_backend/src/ratelimit.rs
+// Token-bucket rate limiting for the API: per user for requests with a
+// session, per client IP for the rest (logging in, registering)
+use crate::config::AppConfig;
+use crate::openapi::{DOCS_PATH, SPEC_PATH};
+use crate::{ApiError, AuthenticatedUser, ErrorDetail};
+use dashmap::DashMap;
+use rocket::data::Data;
+use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::request::Outcome;
+use rocket::route::{self, Handler, Route};
+use rocket::serde::json::Json;
+use rocket::serde::Deserialize;
+use rocket::{Request, Response};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// The first path segments of the routes that are limited; the web UI,
+// health checks and metrics are not
+const LIMITED_PREFIXES: [&str; 2] = ["api", "auth"];
+
+// Under `/api`, but static pages rather than the API
+const UNLIMITED_PATHS: [&str; 2] = [DOCS_PATH, SPEC_PATH];
+
+// Ahead of every other route (including idempotent replays), so a limited
+// request is answered before anything else looks at it
+const LIMITED_RANK: isize = -101;
+
+// How often buckets that have filled up again are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The `[rate_limit]` table in Rocket.toml (or `APP_RATE_LIMIT__*`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Throttle requests to `/api` and `/auth`.
+    pub enabled: bool,
+    /// The bucket of each signed-in user.
+    pub user: BucketConfig,
+    /// The bucket of each client IP, for requests without a session.
+    pub anonymous: BucketConfig,
+    /// Addresses of the proxies in front of the app. Only requests from these
+    /// are counted against the client IP they name in Rocket's `ip_header`;
+    /// for others the header is ignored, as clients could set it themselves.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            user: BucketConfig { burst: 60, per_minute: 300 },
+            anonymous: BucketConfig { burst: 10, per_minute: 30 },
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+/// The size of a token bucket and how fast it refills.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(crate = "rocket::serde")]
+pub struct BucketConfig {
+    /// Requests that can be made in a burst after a quiet period.
+    pub burst: u32,
+    /// Requests allowed per minute once the burst is used up.
+    pub per_minute: u32,
+}
+
+impl BucketConfig {
+    fn per_second(&self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Client {
+    User(Uuid),
+    Ip(IpAddr),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+// What a request's bucket looked like after it was counted, sent back as
+// `X-RateLimit-*` headers
+#[derive(Debug, Clone, Copy)]
+struct Quota {
+    limit: u32,
+    remaining: u32,
+    // Seconds until the bucket is full again
+    reset: u64,
+    // Seconds until the next request is allowed, when this one was not
+    retry_after: Option<u64>,
+}
+
+/// The buckets of every client seen recently. Each instance keeps its own, so
+/// behind a load balancer a client gets the configured rate from each.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: DashMap<Client, Bucket>,
+    // The user behind each session token seen recently, by digest, so that
+    // requests with a known token are counted without looking it up
+    sessions: DashMap<[u8; 32], Uuid>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            buckets: DashMap::new(),
+            sessions: DashMap::new(),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn bucket_config(&self, client: Client) -> BucketConfig {
+        match client {
+            Client::User(_) => self.config.user,
+            Client::Ip(_) => self.config.anonymous,
+        }
+    }
+
+    // Takes a token from the client's bucket, if it has one left
+    fn take(&self, client: Client) -> Quota {
+        let config = self.bucket_config(client);
+        let (capacity, rate) = (f64::from(config.burst), config.per_second());
+        let now = Instant::now();
+
+        let mut bucket = self.buckets.entry(client).or_insert(Bucket { tokens: capacity, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(capacity);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+
+        Quota {
+            limit: config.burst,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((capacity - bucket.tokens) / rate).ceil() as u64,
+            retry_after: (!allowed).then(|| ((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64),
+        }
+    }
+
+    /// Counts something a signed-in user does outside of an HTTP request (a
+    /// WebSocket command) against their bucket, failing with `429
+    /// rate_limited` once it is empty.
+    pub fn charge(&self, user_id: Uuid) -> Result<(), ApiError> {
+        match self.take(Client::User(user_id)).retry_after {
+            None => Ok(()),
+            Some(retry_after) => Err(ApiError::TooManyRequests(Json(ErrorDetail::new(
+                "rate_limited",
+                format!("Too many requests; retry after {} seconds", retry_after),
+            )))),
+        }
+    }
+
+    // The client's address: the peer's, unless the peer is a trusted proxy
+    // that names the client in the `ip_header`
+    fn client_ip(&self, request: &Request<'_>) -> Option<IpAddr> {
+        let peer = request.remote()?.ip();
+        if self.config.trusted_proxies.contains(&peer) {
+            return Some(request.real_ip().unwrap_or(peer));
+        }
+        Some(peer)
+    }
+
+    // Forgets clients whose buckets have filled up again, as a fresh bucket
+    // is the same as theirs
+    fn sweep(&self) {
+        {
+            let mut last_sweep = self.last_sweep.lock().unwrap();
+            if last_sweep.elapsed() < SWEEP_INTERVAL {
+                return;
+            }
+            *last_sweep = Instant::now();
+        }
+        self.buckets.retain(|client, bucket| {
+            let config = self.bucket_config(*client);
+            let refilled = bucket.tokens + bucket.updated.elapsed().as_secs_f64() * config.per_second();
+            refilled < f64::from(config.burst)
+        });
+        self.sessions.retain(|_, user_id| self.buckets.contains_key(&Client::User(*user_id)));
+    }
+}
+
+fn is_limited_path(request: &Request<'_>) -> bool {
+    let path = request.uri().path();
+    path.segments().next().is_some_and(|segment| LIMITED_PREFIXES.contains(&segment))
+        && !UNLIMITED_PATHS.iter().any(|unlimited| path.as_str().starts_with(unlimited))
+}
+
+// Counts a request with a session token not seen yet. Its client IP pays for
+// it first, so made-up tokens cannot run up database lookups; once the token
+// turns out to be a session, the request also counts against its user.
+async fn count_new_session(limiter: &RateLimiter, request: &Request<'_>, digest: [u8; 32]) -> Option<Quota> {
+    let ip_quota = limiter.client_ip(request).map(|ip| limiter.take(Client::Ip(ip)));
+    if ip_quota.is_some_and(|quota| quota.retry_after.is_some()) {
+        return ip_quota;
+    }
+    match request.guard::<AuthenticatedUser>().await {
+        Outcome::Success(user) => {
+            limiter.sessions.insert(digest, user.user_id);
+            Some(limiter.take(Client::User(user.user_id)))
+        }
+        _ => ip_quota,
+    }
+}
+
+/// Counts every request to `/api` and `/auth` against its user or client IP.
+/// Requests whose client cannot be told (no session and no address) pass.
+pub struct RateLimit;
+
+#[rocket::async_trait]
+impl Fairing for RateLimit {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate Limiting",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some(limiter) = request.rocket().state::<RateLimiter>() else {
+            return;
+        };
+        // CORS preflights never carry a session, so they would all count
+        // against the IP of signed-in users of a separately hosted frontend
+        if !is_limited_path(request) || request.method() == Method::Options {
+            return;
+        }
+        limiter.sweep();
+        let quota = match crate::session_token(request) {
+            Some(token) => {
+                let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+                let known_user = limiter.sessions.get(&digest).map(|user_id| *user_id);
+                match known_user {
+                    Some(user_id) => Some(limiter.take(Client::User(user_id))),
+                    None => count_new_session(limiter, request, digest).await,
+                }
+            }
+            None => limiter.client_ip(request).map(|ip| limiter.take(Client::Ip(ip))),
+        };
+        if let Some(quota) = quota {
+            request.local_cache(|| Some(quota));
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(quota) = request.local_cache(|| None::<Quota>) else {
+            return;
+        };
+        response.set_header(Header::new("X-RateLimit-Limit", quota.limit.to_string()));
+        response.set_header(Header::new("X-RateLimit-Remaining", quota.remaining.to_string()));
+        response.set_header(Header::new("X-RateLimit-Reset", quota.reset.to_string()));
+        if let Some(retry_after) = quota.retry_after {
+            response.set_header(Header::new("Retry-After", retry_after.to_string()));
+        }
+    }
+}
+
+/// True if the request was over its limit and is answered with `429`.
+pub fn is_limited(request: &Request<'_>) -> bool {
+    request.local_cache(|| None::<Quota>).is_some_and(|quota| quota.retry_after.is_some())
+}
+
+// Answers requests that are over their limit, and forwards the rest
+#[derive(Clone)]
+struct LimitedHandler;
+
+#[rocket::async_trait]
+impl Handler for LimitedHandler {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        if !is_limited(request) {
+            return route::Outcome::forward(data, Status::NotFound);
+        }
+        let error = ApiError::TooManyRequests(Json(ErrorDetail::new(
+            "rate_limited",
+            "Too many requests; retry after the number of seconds in Retry-After",
+        )));
+        route::Outcome::from(request, error)
+    }
+}
+
+fn routes() -> Vec<Route> {
+    [Method::Get, Method::Put, Method::Post, Method::Delete, Method::Head, Method::Patch]
+        .into_iter()
+        .map(|method| Route::ranked(LIMITED_RANK, method, "/<_..>", LimitedHandler))
+        .collect()
+}
+
+// Fairing that sets up the limiter for [`RateLimit`], when enabled
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Rate Limiting", |rocket| async {
+        let Some(config) = rocket.state::<AppConfig>().map(|config| config.rate_limit.clone()) else {
+            return rocket;
+        };
+        if !config.enabled {
+            return rocket;
+        }
+        rocket.manage(RateLimiter::new(config)).mount("/", routes())
+    })
+}
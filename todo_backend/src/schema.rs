@@ -1,5 +1,63 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    idempotency_keys (user_id, idempotency_key) {
+        user_id -> Uuid,
+        #[max_length = 255]
+        idempotency_key -> Varchar,
+        #[max_length = 64]
+        request_hash -> Bpchar,
+        status -> Nullable<Int2>,
+        headers -> Jsonb,
+        body -> Bytea,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    todo_attachments (id) {
+        id -> Uuid,
+        todo_item_id -> Uuid,
+        uploader_id -> Uuid,
+        #[max_length = 255]
+        file_name -> Varchar,
+        #[max_length = 255]
+        content_type -> Varchar,
+        size_bytes -> Int8,
+        #[max_length = 64]
+        sha256 -> Bpchar,
+        storage_key -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    todo_comments (id) {
+        id -> Uuid,
+        todo_item_id -> Uuid,
+        author_id -> Uuid,
+        body -> Text,
+        edited -> Bool,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        deleted_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    todo_item_events (id) {
+        id -> Uuid,
+        todo_item_id -> Uuid,
+        owner_id -> Uuid,
+        actor_id -> Uuid,
+        #[max_length = 32]
+        action -> Varchar,
+        before -> Nullable<Jsonb>,
+        after -> Nullable<Jsonb>,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     todo_items (id) {
         id -> Uuid,
@@ -8,6 +66,32 @@ diesel::table! {
         completed -> Bool,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        deleted_at -> Nullable<Timestamp>,
+        change_seq -> Int8,
+        version -> Int4,
+    }
+}
+
+diesel::table! {
+    todo_item_tombstones (todo_item_id) {
+        todo_item_id -> Uuid,
+        user_id -> Uuid,
+        change_seq -> Int8,
+        deleted_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    undo_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        todo_item_id -> Uuid,
+        #[max_length = 32]
+        action -> Varchar,
+        previous -> Jsonb,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
     }
 }
 
@@ -32,11 +116,24 @@ diesel::table! {
     }
 }
 
+diesel::joinable!(todo_attachments -> todo_items (todo_item_id));
+diesel::joinable!(todo_attachments -> users (uploader_id));
+diesel::joinable!(todo_comments -> todo_items (todo_item_id));
+diesel::joinable!(todo_comments -> users (author_id));
+diesel::joinable!(todo_item_tombstones -> users (user_id));
 diesel::joinable!(todo_items -> users (user_id));
+diesel::joinable!(undo_tokens -> users (user_id));
+diesel::joinable!(idempotency_keys -> users (user_id));
 diesel::joinable!(sessions -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    idempotency_keys,
     sessions,
+    todo_attachments,
+    todo_comments,
+    todo_item_events,
+    todo_item_tombstones,
     todo_items,
+    undo_tokens,
     users,
 );
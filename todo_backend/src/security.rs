@@ -0,0 +1,107 @@
+// todo_backend/src/security.rs
+// Browser security headers on every response: Rocket's Shield, configured
+// from `[security_headers]`, plus a Content-Security-Policy
+use crate::config::AppConfig;
+use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::serde::Deserialize;
+use rocket::shield::{Frame, Hsts, NoSniff, Referrer, Shield};
+use rocket::{Request, Response};
+
+/// The `[security_headers]` table in Rocket.toml (or `APP_SECURITY_HEADERS__*`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct SecurityHeadersConfig {
+    /// Sent as `Content-Security-Policy`; empty to omit it. Its
+    /// `frame-ancestors` is what keeps other sites from framing the pages.
+    pub content_security_policy: String,
+    /// Sent as `Referrer-Policy`, e.g. `no-referrer`.
+    pub referrer_policy: String,
+    /// `max-age` of `Strict-Transport-Security`; 0 omits the header. Only
+    /// enable it once the site is served over HTTPS for good.
+    pub hsts_max_age_secs: u32,
+    /// Extend HSTS to every subdomain.
+    pub hsts_include_subdomains: bool,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        SecurityHeadersConfig {
+            content_security_policy: "default-src 'self'; img-src 'self' data:; object-src 'none'; \
+                base-uri 'self'; form-action 'self'; frame-ancestors 'none'"
+                .to_string(),
+            referrer_policy: "strict-origin-when-cross-origin".to_string(),
+            hsts_max_age_secs: 0,
+            hsts_include_subdomains: false,
+        }
+    }
+}
+
+impl SecurityHeadersConfig {
+    /// The `Referrer-Policy` named by `referrer_policy`, if it is one.
+    pub fn referrer(&self) -> Option<Referrer> {
+        Some(match self.referrer_policy.as_str() {
+            "no-referrer" => Referrer::NoReferrer,
+            "no-referrer-when-downgrade" => Referrer::NoReferrerWhenDowngrade,
+            "origin" => Referrer::Origin,
+            "origin-when-cross-origin" => Referrer::OriginWhenCrossOrigin,
+            "same-origin" => Referrer::SameOrigin,
+            "strict-origin" => Referrer::StrictOrigin,
+            "strict-origin-when-cross-origin" => Referrer::StrictOriginWhenCrossOrigin,
+            "unsafe-url" => Referrer::UnsafeUrl,
+            _ => return None,
+        })
+    }
+
+    // Rocket's defaults (which include nosniff), with framing denied outright
+    fn shield(&self) -> Shield {
+        let mut shield = Shield::default().enable(NoSniff::Enable).enable(Frame::Deny);
+        if let Some(referrer) = self.referrer() {
+            shield = shield.enable(referrer);
+        }
+        if self.hsts_max_age_secs > 0 {
+            let max_age = rocket::time::Duration::seconds(self.hsts_max_age_secs.into());
+            shield = shield.enable(if self.hsts_include_subdomains {
+                Hsts::IncludeSubDomains(max_age)
+            } else {
+                Hsts::Enable(max_age)
+            });
+        }
+        shield
+    }
+}
+
+/// Adds the configured `Content-Security-Policy` to responses that do not
+/// set their own.
+pub struct ContentSecurityPolicy(String);
+
+#[rocket::async_trait]
+impl Fairing for ContentSecurityPolicy {
+    fn info(&self) -> Info {
+        Info {
+            name: "Content Security Policy",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, _: &'r Request<'_>, response: &mut Response<'r>) {
+        if !response.headers().contains("Content-Security-Policy") {
+            response.set_header(Header::new("Content-Security-Policy", self.0.clone()));
+        }
+    }
+}
+
+// Fairing that replaces Rocket's default Shield with the configured one
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Security Headers", |rocket| async {
+        let Some(config) = rocket.state::<AppConfig>().map(|config| config.security_headers.clone()) else {
+            return rocket;
+        };
+        let rocket = rocket.attach(config.shield());
+        if config.content_security_policy.is_empty() {
+            return rocket;
+        }
+        rocket.attach(ContentSecurityPolicy(config.content_security_policy))
+    })
+}
@@ -0,0 +1,259 @@
+use crate::db::DbConn;
+use crate::models::{AttachmentUpload, NewTodoAttachment, TodoAttachment};
+use crate::storage::AttachmentStore;
+use diesel::prelude::*;
+use rocket::form::Form;
+use rocket::http::{ContentType, Header};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket::tokio::io::{AsyncBufReadExt, AsyncRead};
+use rocket::{Request, State};
+use sha2::{Digest, Sha256};
+use std::pin::Pin;
+use uuid::Uuid;
+use crate::AuthenticatedUser;
+use super::error::ServiceError;
+use super::todos::{find_owned_item, parse_item_id};
+use tracing::instrument;
+
+/// Streams a stored attachment back with its recorded content type.
+pub struct AttachmentDownload {
+    pub content_type: ContentType,
+    pub file_name: String,
+    pub reader: Pin<Box<dyn AsyncRead + Send>>,
+}
+
+impl<'r> Responder<'r, 'static> for AttachmentDownload {
+    fn respond_to(self, _req: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .header(self.content_type)
+            .header(Header::new("Content-Disposition", content_disposition(&self.file_name)))
+            .header(Header::new("X-Content-Type-Options", "nosniff"))
+            .streamed_body(self.reader)
+            .ok()
+    }
+}
+
+// RFC 6266: an ASCII-only `filename` for old clients, and the exact name as
+// percent-encoded UTF-8 in `filename*` (RFC 8187) for everyone else
+fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| if c == ' ' || (c.is_ascii_graphic() && !matches!(c, '"' | '\\')) { c } else { '_' })
+        .collect();
+    let mut encoded = String::with_capacity(file_name.len());
+    for byte in file_name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+fn parse_attachment_id(attachment_id_str: &str) -> Result<Uuid, ServiceError> {
+    Uuid::parse_str(attachment_id_str)
+        .map_err(|_| ServiceError::InvalidInput("invalid_uuid", "Invalid UUID format".to_string()))
+}
+
+// Keep only the final path segment of a client-supplied name and drop anything
+// that would need escaping inside a Content-Disposition header.
+fn sanitize_file_name(raw: &str) -> String {
+    let base = raw.rsplit(['/', '\\']).next().unwrap_or("");
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"' && *c != ';')
+        .take(255)
+        .collect();
+    let cleaned = cleaned.trim().trim_start_matches('.').to_string();
+    if cleaned.is_empty() {
+        "attachment".to_string()
+    } else {
+        cleaned
+    }
+}
+
+fn find_owned_attachment(
+    conn: &mut PgConnection,
+    owner_id: Uuid,
+    item_id_str: &str,
+    attachment_id_str: &str,
+) -> Result<TodoAttachment, ServiceError> {
+    use crate::schema::todo_attachments::dsl::*;
+    let item_uuid = parse_item_id(item_id_str)?;
+    let attachment_uuid = parse_attachment_id(attachment_id_str)?;
+
+    let item = find_owned_item(conn, owner_id, item_uuid)?;
+
+    todo_attachments
+        .filter(id.eq(attachment_uuid).and(todo_item_id.eq(item.id)))
+        .select(TodoAttachment::as_select())
+        .first::<TodoAttachment>(conn)
+        .optional()?
+        .ok_or_else(|| ServiceError::NotFound("attachment_not_found", "Attachment not found".to_string()))
+}
+
+#[instrument(parent = conn.span(), skip_all, fields(user_id = %auth_user.user_id))]
+pub async fn add_attachment(
+    conn: &mut DbConn,
+    store: &State<AttachmentStore>,
+    auth_user: AuthenticatedUser,
+    item_id_str: String,
+    mut upload: Form<AttachmentUpload<'_>>,
+) -> Result<Json<TodoAttachment>, ServiceError> {
+    let item_uuid = parse_item_id(&item_id_str)?;
+    let owner_id = auth_user.user_id;
+    let item = conn.run(move |conn| find_owned_item(conn, owner_id, item_uuid)).await?;
+
+    let file = &mut upload.file;
+    if !file.is_complete() || file.len() > store.max_size.as_u64() {
+        return Err(ServiceError::PayloadTooLarge("attachment_too_large", format!(
+            "Attachments are limited to {}",
+            store.max_size
+        )));
+    }
+    if file.is_empty() {
+        return Err(ServiceError::invalid_field("file", "required", "Attachment is empty"));
+    }
+
+    // Hash the upload before it is handed to the storage backend
+    let mut hasher = Sha256::new();
+    {
+        let mut reader = file.open().await?;
+        loop {
+            let chunk = reader.fill_buf().await?;
+            if chunk.is_empty() {
+                break;
+            }
+            hasher.update(chunk);
+            let consumed = chunk.len();
+            reader.consume(consumed);
+        }
+    }
+    let digest = hex::encode(hasher.finalize());
+
+    let raw_name = file
+        .raw_name()
+        .map(|name| name.dangerous_unsafe_unsanitized_raw().as_str().to_string());
+    let stored_name = sanitize_file_name(raw_name.as_deref().unwrap_or(""));
+    let mime = file
+        .content_type()
+        .filter(|ct| !ct.is_form_data())
+        .cloned()
+        .or_else(|| {
+            stored_name
+                .rsplit_once('.')
+                .and_then(|(_, ext)| ContentType::from_extension(ext))
+        })
+        .unwrap_or(ContentType::Binary);
+
+    let attachment_id = Uuid::new_v4();
+    let key = format!("{}/{}", item.id, attachment_id);
+    store.backend.store(&key, file).await?;
+
+    let new_attachment = NewTodoAttachment {
+        id: attachment_id,
+        todo_item_id: item.id,
+        uploader_id: auth_user.user_id,
+        file_name: stored_name,
+        content_type: mime.to_string(),
+        size_bytes: file.len() as i64,
+        sha256: digest,
+        storage_key: key.clone(),
+    };
+
+    let inserted = conn
+        .run(move |conn| {
+            use crate::schema::todo_attachments::dsl::*;
+            diesel::insert_into(todo_attachments)
+                .values(&new_attachment)
+                .get_result::<TodoAttachment>(conn)
+        })
+        .await;
+
+    match inserted {
+        Ok(attachment) => Ok(Json(attachment)),
+        Err(e) => {
+            // Don't leave orphaned contents behind when the metadata insert fails
+            if let Err(io_err) = store.backend.delete(&key).await {
+                tracing::warn!(storage_key = %key, error = %io_err, "failed to remove orphaned attachment");
+            }
+            Err(e.into())
+        }
+    }
+}
+
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn list_attachments(
+    conn: &mut PgConnection,
+    auth_user: AuthenticatedUser,
+    item_id_str: String,
+) -> Result<Json<Vec<TodoAttachment>>, ServiceError> {
+    use crate::schema::todo_attachments::dsl::*;
+    let item_uuid = parse_item_id(&item_id_str)?;
+
+    let item = find_owned_item(conn, auth_user.user_id, item_uuid)?;
+
+    let attachments = todo_attachments
+        .filter(todo_item_id.eq(item.id))
+        .order(created_at.asc())
+        .select(TodoAttachment::as_select())
+        .load::<TodoAttachment>(conn)?;
+    Ok(Json(attachments))
+}
+
+#[instrument(parent = conn.span(), skip_all, fields(user_id = %auth_user.user_id))]
+pub async fn download_attachment(
+    conn: &mut DbConn,
+    store: &State<AttachmentStore>,
+    auth_user: AuthenticatedUser,
+    item_id_str: String,
+    attachment_id_str: String,
+) -> Result<AttachmentDownload, ServiceError> {
+    let attachment = conn
+        .run(move |conn| find_owned_attachment(conn, auth_user.user_id, &item_id_str, &attachment_id_str))
+        .await?;
+
+    let reader = store.backend.open(&attachment.storage_key).await?;
+    let content_type = ContentType::parse_flexible(&attachment.content_type)
+        .unwrap_or(ContentType::Binary);
+
+    Ok(AttachmentDownload {
+        content_type,
+        file_name: attachment.file_name,
+        reader,
+    })
+}
+
+#[instrument(parent = conn.span(), skip_all, fields(user_id = %auth_user.user_id))]
+pub async fn delete_attachment(
+    conn: &mut DbConn,
+    store: &State<AttachmentStore>,
+    auth_user: AuthenticatedUser,
+    item_id_str: String,
+    attachment_id_str: String,
+) -> Result<(), ServiceError> {
+    let attachment = conn
+        .run(move |conn| {
+            use crate::schema::todo_attachments::dsl::*;
+            let attachment = find_owned_attachment(conn, auth_user.user_id, &item_id_str, &attachment_id_str)?;
+            diesel::delete(todo_attachments.filter(id.eq(attachment.id))).execute(conn)?;
+            Ok::<_, ServiceError>(attachment)
+        })
+        .await?;
+
+    store.backend.delete(&attachment.storage_key).await?;
+    Ok(())
+}
+
+/// Removes stored contents for attachments whose rows are already gone (e.g. via
+/// the `ON DELETE CASCADE` from `todo_items`). Failures are logged, not returned,
+/// since the owning item has already been deleted.
+pub async fn remove_stored_contents(store: &AttachmentStore, storage_keys: Vec<String>) {
+    for key in storage_keys {
+        if let Err(e) = store.backend.delete(&key).await {
+            tracing::warn!(storage_key = %key, error = %e, "failed to remove attachment contents");
+        }
+    }
+}
@@ -1,35 +1,62 @@
-use crate::db::PgPool;
 use crate::models::{NewUser, User, UserInfo};
 use crate::schema::sessions;
 use diesel::prelude::*;
-use rocket::State;
 use rocket::serde::json::Json;
 use uuid::Uuid;
-use bcrypt::{hash, verify, DEFAULT_COST};
-use crate::{AuthRequest, LoginResponse};
+use bcrypt::{hash, verify};
+use crate::config::SessionConfig;
+use crate::metrics::Metrics;
+use crate::{AuthRequest, FieldError, LoginResponse};
 use chrono::{Utc, Duration};
 use super::error::ServiceError;
+use tracing::instrument;
+
+// The users.username column is a VARCHAR(255)
+const MAX_USERNAME_LEN: usize = 255;
+
+fn validate_registration(auth_req: &AuthRequest) -> Result<(), ServiceError> {
+    let mut errors = Vec::new();
+    if auth_req.username.trim().is_empty() {
+        errors.push(FieldError::new("username", "required", "Username must not be empty"));
+    } else if auth_req.username.chars().count() > MAX_USERNAME_LEN {
+        errors.push(FieldError::new(
+            "username",
+            "too_long",
+            format!("Username must be at most {} characters", MAX_USERNAME_LEN),
+        ));
+    }
+    if auth_req.password.is_empty() {
+        errors.push(FieldError::new("password", "required", "Password must not be empty"));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ServiceError::Validation(errors))
+    }
+}
 
+#[instrument(skip_all, fields(username = %auth_req.username))]
 pub fn register_user(
-    pool: &State<PgPool>,
+    conn: &mut PgConnection,
+    bcrypt_cost: u32,
     auth_req: Json<AuthRequest>,
 ) -> Result<Json<UserInfo>, ServiceError> {
     use crate::schema::users::dsl::*;
-
-    let mut conn = pool.get().map_err(|_| ServiceError::InternalError("Failed to get DB connection".to_string()))?;
+    validate_registration(&auth_req)?;
 
     // Check if user already exists
     let existing_user = users
         .filter(username.eq(&auth_req.username))
         .select(User::as_select())
-        .first::<User>(&mut conn)
+        .first::<User>(conn)
         .optional()?;
 
     if existing_user.is_some() {
-        return Err(ServiceError::Conflict("Username already exists".to_string()));
+        return Err(ServiceError::Conflict("username_taken", "Username already exists".to_string()));
     }
 
-    let hashed_password = hash(&auth_req.password, DEFAULT_COST)?;
+    let hashed_password = hash(&auth_req.password, bcrypt_cost)?;
 
     let new_user = NewUser {
         username: &auth_req.username,
@@ -38,23 +65,40 @@ pub fn register_user(
 
     let user = diesel::insert_into(users)
         .values(&new_user)
-        .get_result::<User>(&mut conn)?;
+        .get_result::<User>(conn)?;
 
     Ok(Json(user.into()))
 }
 
+/// Checks the credentials and opens a session; the caller sets the cookie.
+#[instrument(skip_all, fields(username = %auth_req.username))]
 pub fn login_user(
-    pool: &State<PgPool>,
-    cookies: &rocket::http::CookieJar<'_>,
+    conn: &mut PgConnection,
+    session_config: &SessionConfig,
+    metrics: &Metrics,
+    auth_req: Json<AuthRequest>,
+) -> Result<Json<LoginResponse>, ServiceError> {
+    let result = open_session(conn, session_config, auth_req);
+    // Faults say nothing about the credentials, so they are not counted as failed logins
+    match result {
+        Ok(_) => metrics.record_login(true),
+        Err(ServiceError::NotFound(..) | ServiceError::Unauthorized(..)) => metrics.record_login(false),
+        Err(_) => {}
+    }
+    result
+}
+
+fn open_session(
+    conn: &mut PgConnection,
+    session_config: &SessionConfig,
     auth_req: Json<AuthRequest>,
 ) -> Result<Json<LoginResponse>, ServiceError> {
     use crate::schema::users::dsl::*;
-    let mut conn = pool.get().map_err(|_| ServiceError::InternalError("Failed to get DB connection".to_string()))?;
 
     let found_user = users
         .filter(username.eq(&auth_req.username))
         .select(User::as_select())
-        .first::<User>(&mut conn)
+        .first::<User>(conn)
         .optional()?;
 
     match found_user {
@@ -63,49 +107,48 @@ pub fn login_user(
             {
                 let new_session = NewSession {
                     user_id: user.id,
-                    expires_at: Utc::now().naive_utc() + Duration::days(1),
+                    expires_at: Utc::now().naive_utc() + Duration::seconds(session_config.ttl_secs as i64),
                 };
 
                 let session = diesel::insert_into(sessions::table)
                     .values(&new_session)
-                    .get_result::<Session>(&mut conn)?;
-
-                cookies.add(rocket::http::Cookie::new("session_token", session.id.to_string()));
+                    .get_result::<Session>(conn)?;
 
                 Ok(Json(LoginResponse {
                     session_token: session.id.to_string(),
                     username: user.username,
                 }))
             } else {
-                Err(ServiceError::Unauthorized("Invalid credentials".to_string()))
+                Err(ServiceError::Unauthorized("invalid_credentials", "Invalid credentials".to_string()))
             }
         }
-        None => Err(ServiceError::NotFound("User not found".to_string())),
+        None => Err(ServiceError::NotFound("user_not_found", "User not found".to_string())),
     }
 }
 
+#[instrument(skip_all)]
 pub fn logout_user(
-    pool: &State<PgPool>,
-    cookies: &rocket::http::CookieJar<'_>,
+    conn: &mut PgConnection,
+    session_token: String,
 ) -> Result<(), ServiceError> {
-    let session_token = match cookies.get("session_token") {
-        Some(cookie) => cookie.value().to_string(),
-        None => return Ok(()),
-    };
-
     let session_uuid = Uuid::parse_str(&session_token)
-        .map_err(|_| ServiceError::InvalidInput("Invalid session token".to_string()))?;
-
-    let mut conn = pool.get().map_err(|_| ServiceError::InternalError("Failed to get DB connection".to_string()))?;
+        .map_err(|_| ServiceError::InvalidInput("invalid_token", "Invalid session token".to_string()))?;
 
     diesel::delete(sessions::table.filter(sessions::id.eq(session_uuid)))
-        .execute(&mut conn)?;
-
-    cookies.remove(rocket::http::Cookie::from("session_token"));
+        .execute(conn)?;
 
     Ok(())
 }
 
+/// The session with this id, unless it has expired or was logged out.
+pub fn find_active_session(conn: &mut PgConnection, session_id: Uuid) -> Result<Option<Session>, ServiceError> {
+    Ok(sessions::table
+        .filter(sessions::id.eq(session_id))
+        .filter(sessions::expires_at.gt(Utc::now().naive_utc()))
+        .first::<Session>(conn)
+        .optional()?)
+}
+
 #[derive(Queryable, Insertable, Debug)]
 #[diesel(table_name = sessions)]
 pub struct Session {
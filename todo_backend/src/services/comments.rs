@@ -0,0 +1,131 @@
+use crate::models::{CommentRequest, NewTodoComment, TodoComment};
+use diesel::prelude::*;
+use rocket::serde::json::Json;
+use uuid::Uuid;
+use chrono::Utc;
+use crate::AuthenticatedUser;
+use super::error::ServiceError;
+use super::todos::{find_owned_item, parse_item_id};
+use tracing::instrument;
+
+fn parse_comment_id(comment_id_str: &str) -> Result<Uuid, ServiceError> {
+    Uuid::parse_str(comment_id_str)
+        .map_err(|_| ServiceError::InvalidInput("invalid_uuid", "Invalid UUID format".to_string()))
+}
+
+fn validate_body(body: &str) -> Result<String, ServiceError> {
+    let trimmed = body.trim();
+    if trimmed.is_empty() {
+        return Err(ServiceError::invalid_field("body", "required", "Comment body must not be empty"));
+    }
+    Ok(trimmed.to_string())
+}
+
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn add_comment(
+    conn: &mut PgConnection,
+    auth_user: AuthenticatedUser,
+    item_id_str: String,
+    comment_req: Json<CommentRequest>,
+) -> Result<Json<TodoComment>, ServiceError> {
+    use crate::schema::todo_comments::dsl::*;
+    let item_uuid = parse_item_id(&item_id_str)?;
+    let comment_body = validate_body(&comment_req.body)?;
+
+    let item = find_owned_item(conn, auth_user.user_id, item_uuid)?;
+
+    let new_comment = NewTodoComment {
+        todo_item_id: item.id,
+        author_id: auth_user.user_id,
+        body: comment_body,
+    };
+
+    let comment = diesel::insert_into(todo_comments)
+        .values(&new_comment)
+        .get_result::<TodoComment>(conn)?;
+    Ok(Json(comment))
+}
+
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn list_comments(
+    conn: &mut PgConnection,
+    auth_user: AuthenticatedUser,
+    item_id_str: String,
+) -> Result<Json<Vec<TodoComment>>, ServiceError> {
+    use crate::schema::todo_comments::dsl::*;
+    let item_uuid = parse_item_id(&item_id_str)?;
+
+    let item = find_owned_item(conn, auth_user.user_id, item_uuid)?;
+
+    let comments = todo_comments
+        .filter(todo_item_id.eq(item.id))
+        .filter(deleted_at.is_null())
+        .order(created_at.asc())
+        .select(TodoComment::as_select())
+        .load::<TodoComment>(conn)?;
+    Ok(Json(comments))
+}
+
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn update_comment(
+    conn: &mut PgConnection,
+    auth_user: AuthenticatedUser,
+    item_id_str: String,
+    comment_id_str: String,
+    comment_req: Json<CommentRequest>,
+) -> Result<Json<TodoComment>, ServiceError> {
+    use crate::schema::todo_comments::dsl::*;
+    let item_uuid = parse_item_id(&item_id_str)?;
+    let comment_uuid = parse_comment_id(&comment_id_str)?;
+    let comment_body = validate_body(&comment_req.body)?;
+
+    let item = find_owned_item(conn, auth_user.user_id, item_uuid)?;
+
+    // Only the author may edit, and deleted comments stay deleted
+    let target = todo_comments
+        .filter(id.eq(comment_uuid))
+        .filter(todo_item_id.eq(item.id))
+        .filter(author_id.eq(auth_user.user_id))
+        .filter(deleted_at.is_null());
+
+    let updated_comment = diesel::update(target)
+        .set((body.eq(comment_body), edited.eq(true)))
+        .get_result::<TodoComment>(conn)
+        .optional()?;
+
+    match updated_comment {
+        Some(comment) => Ok(Json(comment)),
+        None => Err(ServiceError::NotFound("comment_not_found", "Comment not found".to_string())),
+    }
+}
+
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn delete_comment(
+    conn: &mut PgConnection,
+    auth_user: AuthenticatedUser,
+    item_id_str: String,
+    comment_id_str: String,
+) -> Result<(), ServiceError> {
+    use crate::schema::todo_comments::dsl::*;
+    let item_uuid = parse_item_id(&item_id_str)?;
+    let comment_uuid = parse_comment_id(&comment_id_str)?;
+
+    let item = find_owned_item(conn, auth_user.user_id, item_uuid)?;
+
+    // Soft delete: the row is kept so the thread history stays intact
+    let target = todo_comments
+        .filter(id.eq(comment_uuid))
+        .filter(todo_item_id.eq(item.id))
+        .filter(author_id.eq(auth_user.user_id))
+        .filter(deleted_at.is_null());
+
+    let num_deleted = diesel::update(target)
+        .set(deleted_at.eq(Some(Utc::now().naive_utc())))
+        .execute(conn)?;
+
+    if num_deleted > 0 {
+        Ok(())
+    } else {
+        Err(ServiceError::NotFound("comment_not_found", "Comment not found".to_string()))
+    }
+}
@@ -1,8 +1,11 @@
 use thiserror::Error;
 use rocket::serde::json::Json;
 use crate::ApiError;
-use crate::ErrorDetail;
+use crate::{ErrorDetail, FieldError, InternalErrorDetail};
 
+// Client-facing variants carry a stable error code (e.g. `todo_not_found`)
+// followed by a message for people. The rest are server faults: their details
+// are logged, never sent to the client.
 #[derive(Debug, Error)]
 pub enum ServiceError {
     #[error("Database error: {0}")]
@@ -11,33 +14,78 @@ pub enum ServiceError {
     #[error("Password hashing error: {0}")]
     HashingError(#[from] bcrypt::BcryptError),
 
-    #[error("Invalid input: {0}")]
-    InvalidInput(String),
+    #[error("Invalid input: {1}")]
+    InvalidInput(&'static str, String),
 
-    #[error("Item not found: {0}")]
-    NotFound(String),
+    #[error("Validation failed: {} invalid field(s)", .0.len())]
+    Validation(Vec<FieldError>),
 
-    #[error("Unauthorized access: {0}")]
-    Unauthorized(String),
+    #[error("Item not found: {1}")]
+    NotFound(&'static str, String),
 
-    #[error("Conflict: {0}")]
-    Conflict(String),
+    #[error("Unauthorized access: {1}")]
+    Unauthorized(&'static str, String),
+
+    #[error("Payload too large: {1}")]
+    PayloadTooLarge(&'static str, String),
+
+    #[error("Storage error: {0}")]
+    StorageError(#[from] std::io::Error),
+
+    #[error("Service unavailable: {0}")]
+    Unavailable(#[from] diesel::r2d2::PoolError),
+
+    #[error("Conflict: {1}")]
+    Conflict(&'static str, String),
+
+    #[error("Unprocessable request: {1}")]
+    Unprocessable(&'static str, String),
+
+    #[error("Precondition failed: {1}")]
+    PreconditionFailed(&'static str, String),
 
     #[error("Internal server error: {0}")]
     InternalError(String),
 }
 
+impl ServiceError {
+    /// A validation failure for a single field.
+    pub fn invalid_field(field: &str, code: &str, message: &str) -> Self {
+        ServiceError::Validation(vec![FieldError::new(field, code, message)])
+    }
+}
+
 impl From<ServiceError> for ApiError {
     fn from(err: ServiceError) -> Self {
-        let detail = err.to_string();
         match err {
-            ServiceError::NotFound(_) => ApiError::NotFound(Json(ErrorDetail { error: detail })),
-            ServiceError::Unauthorized(_) => ApiError::Unauthorized(Json(ErrorDetail { error: detail })),
-            ServiceError::Conflict(_) => ApiError::Conflict(Json(ErrorDetail { error: detail })),
-            ServiceError::InvalidInput(_) => ApiError::BadRequest(Json(ErrorDetail { error: detail })),
-            ServiceError::DatabaseError(_) | ServiceError::HashingError(_) | ServiceError::InternalError(_) => {
-                ApiError::InternalError(Json(ErrorDetail { error: detail }))
+            ServiceError::NotFound(code, message) => ApiError::NotFound(Json(ErrorDetail::new(code, message))),
+            ServiceError::Unauthorized(code, message) => ApiError::Unauthorized(Json(ErrorDetail::new(code, message))),
+            ServiceError::Conflict(code, message) => ApiError::Conflict(Json(ErrorDetail::new(code, message))),
+            ServiceError::Unprocessable(code, message) => {
+                ApiError::UnprocessableEntity(Json(ErrorDetail::new(code, message)))
+            }
+            ServiceError::PreconditionFailed(code, message) => {
+                ApiError::PreconditionFailed(Json(ErrorDetail::new(code, message)))
+            }
+            ServiceError::InvalidInput(code, message) => ApiError::BadRequest(Json(ErrorDetail::new(code, message))),
+            ServiceError::Validation(fields) => ApiError::BadRequest(Json(ErrorDetail {
+                fields,
+                ..ErrorDetail::new("validation_failed", "Some fields are invalid")
+            })),
+            ServiceError::PayloadTooLarge(code, message) => {
+                ApiError::PayloadTooLarge(Json(ErrorDetail::new(code, message)))
+            }
+            ServiceError::Unavailable(e) => {
+                tracing::warn!(error = %e, "no database connection available");
+                ApiError::ServiceUnavailable(Json(ErrorDetail::new(
+                    "database_unavailable",
+                    "The database is temporarily unavailable",
+                )))
             }
+            ServiceError::DatabaseError(_)
+            | ServiceError::HashingError(_)
+            | ServiceError::StorageError(_)
+            | ServiceError::InternalError(_) => ApiError::InternalError(InternalErrorDetail(err.to_string())),
         }
     }
 }
@@ -0,0 +1,149 @@
+use crate::models::{NewTodoItemEvent, TodoItem, TodoItemEvent};
+use diesel::prelude::*;
+use rocket::serde::json::Json;
+use serde_json::{Map, Value};
+use uuid::Uuid;
+use crate::AuthenticatedUser;
+use super::error::ServiceError;
+use super::todos::{find_owned_item, parse_item_id};
+use tracing::instrument;
+
+/// The kinds of change recorded in `todo_item_events.action`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemAction {
+    Created,
+    Updated,
+    Completed,
+    /// A completion taken back with its undo token.
+    Uncompleted,
+    Deleted,
+    Restored,
+    Purged,
+}
+
+impl ItemAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ItemAction::Created => "created",
+            ItemAction::Updated => "updated",
+            ItemAction::Completed => "completed",
+            ItemAction::Uncompleted => "uncompleted",
+            ItemAction::Deleted => "deleted",
+            ItemAction::Restored => "restored",
+            ItemAction::Purged => "purged",
+        }
+    }
+
+    /// How a change from `before` to `after` is announced to streams: completing
+    /// an item is `completed`, as the change notification trigger has it.
+    pub fn of_update(before: &TodoItem, after: &TodoItem) -> Self {
+        if !before.completed && after.completed {
+            ItemAction::Completed
+        } else {
+            ItemAction::Updated
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            ItemAction::Created,
+            ItemAction::Updated,
+            ItemAction::Completed,
+            ItemAction::Uncompleted,
+            ItemAction::Deleted,
+            ItemAction::Restored,
+            ItemAction::Purged,
+        ]
+        .into_iter()
+        .find(|action| action.as_str() == name)
+    }
+}
+
+// Bookkeeping columns that change on every write and would only add noise
+const IGNORED_FIELDS: &[&str] = &["updated_at", "change_seq", "version"];
+
+fn snapshot(item: &TodoItem) -> Result<Map<String, Value>, ServiceError> {
+    match serde_json::to_value(item) {
+        Ok(Value::Object(map)) => Ok(map),
+        _ => Err(ServiceError::InternalError("Failed to serialize todo item".to_string())),
+    }
+}
+
+// Reduces two snapshots to the fields that differ between them
+fn diff(
+    before: Map<String, Value>,
+    mut after: Map<String, Value>,
+) -> (Map<String, Value>, Map<String, Value>) {
+    let mut changed_before = Map::new();
+    let mut changed_after = Map::new();
+    for (key, old_value) in before {
+        if IGNORED_FIELDS.contains(&key.as_str()) {
+            continue;
+        }
+        let new_value = after.remove(&key).unwrap_or(Value::Null);
+        if new_value != old_value {
+            changed_before.insert(key.clone(), old_value);
+            changed_after.insert(key, new_value);
+        }
+    }
+    (changed_before, changed_after)
+}
+
+/// Records a change to `item_id`. Call this on the same connection, inside the
+/// same transaction, as the write it describes.
+pub(crate) fn record_event(
+    conn: &mut PgConnection,
+    item_id: Uuid,
+    owner_id: Uuid,
+    actor_id: Uuid,
+    action: ItemAction,
+    before: Option<&TodoItem>,
+    after: Option<&TodoItem>,
+) -> Result<TodoItemEvent, ServiceError> {
+    use crate::schema::todo_item_events;
+
+    let (before_json, after_json) = match (before.map(snapshot).transpose()?, after.map(snapshot).transpose()?) {
+        (Some(old), Some(new)) => {
+            let (old, new) = diff(old, new);
+            (Some(Value::Object(old)), Some(Value::Object(new)))
+        }
+        (old, new) => (old.map(Value::Object), new.map(Value::Object)),
+    };
+
+    let event = NewTodoItemEvent {
+        todo_item_id: item_id,
+        owner_id,
+        actor_id,
+        action: action.as_str().to_string(),
+        before: before_json,
+        after: after_json,
+    };
+
+    Ok(diesel::insert_into(todo_item_events::table)
+        .values(&event)
+        .get_result::<TodoItemEvent>(conn)?)
+}
+
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn get_item_history(
+    conn: &mut PgConnection,
+    auth_user: AuthenticatedUser,
+    item_id_str: String,
+) -> Result<Json<Vec<TodoItemEvent>>, ServiceError> {
+    use crate::schema::todo_item_events::dsl::*;
+    let item_uuid = parse_item_id(&item_id_str)?;
+
+    // Ownership is checked against the events themselves so that the history
+    // of a deleted item stays available to its owner.
+    let events = todo_item_events
+        .filter(todo_item_id.eq(item_uuid).and(owner_id.eq(auth_user.user_id)))
+        .order(created_at.asc())
+        .select(TodoItemEvent::as_select())
+        .load::<TodoItemEvent>(conn)?;
+
+    if events.is_empty() {
+        // Items that predate history tracking simply have an empty history
+        find_owned_item(conn, auth_user.user_id, item_uuid)?;
+    }
+    Ok(Json(events))
+}
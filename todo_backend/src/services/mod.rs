@@ -1,3 +1,9 @@
 pub mod auth;
 pub mod todos;
+pub mod comments;
+pub mod attachments;
+pub mod history;
+pub mod trash;
+pub mod undo;
+pub mod sync;
 pub mod error;
@@ -0,0 +1,243 @@
+use crate::models::{SyncChanges, SyncMutation, SyncQuery, SyncRequest, SyncResponse, SyncResult, Tombstone, TodoItem};
+use crate::schema::{todo_item_tombstones, todo_items};
+use chrono::Utc;
+use diesel::prelude::*;
+use rocket::serde::json::Json;
+use uuid::Uuid;
+use crate::{ApiError, AuthenticatedUser};
+use crate::etag::IfMatch;
+use crate::events::EventBus;
+use crate::metrics::Metrics;
+use super::error::ServiceError;
+use super::history::{record_event, ItemAction};
+use tracing::instrument;
+
+const DEFAULT_PAGE_SIZE: i64 = 500;
+const MAX_PAGE_SIZE: i64 = 1000;
+
+// Upper bound on the mutations accepted in one `POST /api/v1/sync`
+const MAX_BATCH_SIZE: usize = 500;
+
+// Items trashed since the last sync are reported as deleted, like purged ones
+enum Change {
+    Item(TodoItem),
+    Deleted(Tombstone),
+}
+
+impl Change {
+    fn change_seq(&self) -> i64 {
+        match self {
+            Change::Item(item) => item.change_seq,
+            Change::Deleted(tombstone) => tombstone.change_seq,
+        }
+    }
+}
+
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn get_changes(
+    conn: &mut PgConnection,
+    auth_user: AuthenticatedUser,
+    query: SyncQuery,
+) -> Result<Json<SyncChanges>, ServiceError> {
+    let since = query.since.unwrap_or(0);
+    if since < 0 {
+        return Err(ServiceError::invalid_field("since", "out_of_range", "since must not be negative"));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(ServiceError::invalid_field("limit", "out_of_range", "limit must be between 1 and 1000"));
+    }
+
+    // One more than a page from each source tells whether there is more
+    let items = todo_items::table
+        .filter(todo_items::user_id.eq(auth_user.user_id))
+        .filter(todo_items::change_seq.gt(since))
+        .order(todo_items::change_seq.asc())
+        .limit(limit + 1)
+        .select(TodoItem::as_select())
+        .load::<TodoItem>(conn)?;
+    let purged = todo_item_tombstones::table
+        .filter(todo_item_tombstones::user_id.eq(auth_user.user_id))
+        .filter(todo_item_tombstones::change_seq.gt(since))
+        .order(todo_item_tombstones::change_seq.asc())
+        .limit(limit + 1)
+        .select((todo_item_tombstones::todo_item_id, todo_item_tombstones::change_seq))
+        .load::<(Uuid, i64)>(conn)?;
+
+    let mut changes: Vec<Change> = items
+        .into_iter()
+        .map(|item| match item.deleted_at {
+            Some(_) => Change::Deleted(Tombstone { id: item.id, change_seq: item.change_seq }),
+            None => Change::Item(item),
+        })
+        .chain(purged.into_iter().map(|(id, change_seq)| Change::Deleted(Tombstone { id, change_seq })))
+        .collect();
+    changes.sort_by_key(Change::change_seq);
+    let has_more = changes.len() as i64 > limit;
+    changes.truncate(limit as usize);
+
+    let token = changes.last().map_or(since, Change::change_seq);
+    let mut sync = SyncChanges { items: Vec::new(), deleted: Vec::new(), token, has_more };
+    for change in changes {
+        match change {
+            Change::Item(item) => sync.items.push(item),
+            Change::Deleted(tombstone) => sync.deleted.push(tombstone),
+        }
+    }
+    Ok(Json(sync))
+}
+
+// Applies each mutation in its own transaction, so one that conflicts or is
+// rejected does not hold back the rest. A server fault stops the batch: the
+// mutation it hit is rejected and the results so far are returned, as the
+// ones before it are already committed.
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn apply_mutations(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    metrics: &Metrics,
+    auth_user: AuthenticatedUser,
+    sync_req: Json<SyncRequest>,
+) -> Result<Json<SyncResponse>, ServiceError> {
+    if sync_req.mutations.len() > MAX_BATCH_SIZE {
+        return Err(ServiceError::invalid_field("mutations", "too_many", "At most 500 mutations can be sent at once"));
+    }
+
+    let mut results = Vec::with_capacity(sync_req.mutations.len());
+    for mutation in sync_req.into_inner().mutations {
+        let id = mutation.id();
+        match conn.transaction(|conn| apply_mutation(conn, &auth_user, mutation)) {
+            Ok((result, change)) => {
+                if let Some((action, item)) = change {
+                    events.publish(action, &item);
+                    metrics.record_change(action);
+                }
+                results.push(result);
+            }
+            Err(error) => {
+                let error = ApiError::from(error);
+                let fault = error.status().class().is_server_error();
+                results.push(SyncResult::Rejected { id, error: error.into_reported_detail() });
+                if fault {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(Json(SyncResponse { results }))
+}
+
+fn validate_description(description: &str) -> Result<(), ServiceError> {
+    if description.trim().is_empty() {
+        return Err(ServiceError::invalid_field("description", "required", "Description must not be empty"));
+    }
+    Ok(())
+}
+
+// The item with this id whatever its state, locked until the transaction ends
+fn lock_item(conn: &mut PgConnection, item_uuid: Uuid) -> Result<Option<TodoItem>, ServiceError> {
+    Ok(todo_items::table
+        .filter(todo_items::id.eq(item_uuid))
+        .select(TodoItem::as_select())
+        .for_update()
+        .first::<TodoItem>(conn)
+        .optional()?)
+}
+
+fn was_purged(conn: &mut PgConnection, owner_id: Uuid, item_uuid: Uuid) -> Result<bool, ServiceError> {
+    let tombstones = todo_item_tombstones::table
+        .filter(todo_item_tombstones::todo_item_id.eq(item_uuid))
+        .filter(todo_item_tombstones::user_id.eq(owner_id))
+        .count()
+        .get_result::<i64>(conn)?;
+    Ok(tombstones > 0)
+}
+
+// The result of one mutation, and the change to announce once it commits
+fn apply_mutation(
+    conn: &mut PgConnection,
+    auth_user: &AuthenticatedUser,
+    mutation: SyncMutation,
+) -> Result<(SyncResult, Option<(ItemAction, TodoItem)>), ServiceError> {
+    let item_uuid = mutation.id();
+    let existing = lock_item(conn, item_uuid)?;
+    let not_found = || ServiceError::NotFound("todo_not_found", "Todo item not found".to_string());
+
+    // Other users' items are treated as if they did not exist
+    let existing = match existing {
+        Some(item) if item.user_id != auth_user.user_id => {
+            return match mutation {
+                SyncMutation::Create { .. } => {
+                    Err(ServiceError::Conflict("id_in_use", "The id is already in use".to_string()))
+                }
+                _ => Err(not_found()),
+            };
+        }
+        existing => existing,
+    };
+    let conflict = |item: Option<TodoItem>| Ok((SyncResult::Conflict { id: item_uuid, item }, None));
+
+    match mutation {
+        SyncMutation::Create { id, description, completed } => {
+            validate_description(&description)?;
+            match existing {
+                // Already created, likely by an earlier attempt at this batch
+                Some(item) if item.deleted_at.is_none() => return conflict(Some(item)),
+                Some(_) => return conflict(None),
+                None if was_purged(conn, auth_user.user_id, id)? => return conflict(None),
+                None => {}
+            }
+            let item = diesel::insert_into(todo_items::table)
+                .values((
+                    todo_items::id.eq(id),
+                    todo_items::user_id.eq(auth_user.user_id),
+                    todo_items::description.eq(description),
+                    todo_items::completed.eq(completed),
+                ))
+                .get_result::<TodoItem>(conn)?;
+            record_event(conn, item.id, item.user_id, auth_user.user_id, ItemAction::Created, None, Some(&item))?;
+            Ok((SyncResult::Applied { id, item: Some(item.clone()) }, Some((ItemAction::Created, item))))
+        }
+        SyncMutation::Update { id, base_version, description, completed } => {
+            if let Some(ref new_description) = description {
+                validate_description(new_description)?;
+            }
+            let existing = match existing {
+                Some(item) if item.deleted_at.is_none() => item,
+                Some(_) => return conflict(None),
+                None if was_purged(conn, auth_user.user_id, id)? => return conflict(None),
+                None => return Err(not_found()),
+            };
+            if IfMatch::version(base_version).check(existing.version).is_err() {
+                return conflict(Some(existing));
+            }
+            let updated = diesel::update(todo_items::table.filter(todo_items::id.eq(id)))
+                .set((
+                    todo_items::description.eq(description.unwrap_or_else(|| existing.description.clone())),
+                    todo_items::completed.eq(completed.unwrap_or(existing.completed)),
+                ))
+                .get_result::<TodoItem>(conn)?;
+            record_event(conn, id, updated.user_id, auth_user.user_id, ItemAction::Updated, Some(&existing), Some(&updated))?;
+            let change = ItemAction::of_update(&existing, &updated);
+            Ok((SyncResult::Applied { id, item: Some(updated.clone()) }, Some((change, updated))))
+        }
+        SyncMutation::Delete { id, base_version } => {
+            let existing = match existing {
+                Some(item) if item.deleted_at.is_none() => item,
+                // Deleting what is already gone changes nothing
+                Some(_) => return Ok((SyncResult::Applied { id, item: None }, None)),
+                None if was_purged(conn, auth_user.user_id, id)? => return Ok((SyncResult::Applied { id, item: None }, None)),
+                None => return Err(not_found()),
+            };
+            if IfMatch::version(base_version).check(existing.version).is_err() {
+                return conflict(Some(existing));
+            }
+            // Moved to the trash, as `DELETE /api/todos/<id>` does
+            let trashed = diesel::update(todo_items::table.filter(todo_items::id.eq(id)))
+                .set(todo_items::deleted_at.eq(Some(Utc::now().naive_utc())))
+                .get_result::<TodoItem>(conn)?;
+            record_event(conn, id, trashed.user_id, auth_user.user_id, ItemAction::Deleted, Some(&existing), Some(&trashed))?;
+            Ok((SyncResult::Applied { id, item: None }, Some((ItemAction::Deleted, trashed))))
+        }
+    }
+}
@@ -1,84 +1,187 @@
-use crate::db::PgPool;
-use crate::models::{NewTodoItem, TodoItem, CreateTodoRequest, TodoSearchQuery};
-use crate::schema::todo_items::{self};
+use crate::models::{NewTodoItem, TodoItem, TodoListItem, CreateTodoRequest, UpdateTodoRequest, TodoSearchQuery, Undoable};
+use crate::schema::{todo_comments, todo_items::{self}};
 use diesel::prelude::*;
-use rocket::State;
 use rocket::serde::json::Json;
 use uuid::Uuid;
+use chrono::Utc;
 use crate::AuthenticatedUser;
 use super::error::ServiceError;
+use super::history::{record_event, ItemAction};
+use super::undo::{issue_token, UndoConfig};
+use crate::etag::IfMatch;
+use crate::events::EventBus;
+use crate::metrics::Metrics;
+use std::collections::HashMap;
+use tracing::instrument;
 
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
 pub fn add_todo_item(
-    pool: &State<PgPool>,
+    conn: &mut PgConnection,
+    events: &EventBus,
+    metrics: &Metrics,
     auth_user: AuthenticatedUser,
     create_req: Json<CreateTodoRequest>,
 ) -> Result<Json<TodoItem>, ServiceError> {
     use crate::schema::todo_items::dsl::*;
-    let mut conn = pool.get().map_err(|_| ServiceError::InternalError("Failed to get DB connection".to_string()))?;
+    if create_req.description.trim().is_empty() {
+        return Err(ServiceError::invalid_field("description", "required", "Description must not be empty"));
+    }
 
     let new_item = NewTodoItem {
         user_id: auth_user.user_id,
         description: create_req.description.clone(),
     };
 
-    let item = diesel::insert_into(todo_items)
-        .values(&new_item)
-        .get_result::<TodoItem>(&mut conn)?;
+    let item = conn.transaction::<_, ServiceError, _>(|conn| {
+        let item = diesel::insert_into(todo_items)
+            .values(&new_item)
+            .get_result::<TodoItem>(conn)?;
+        record_event(conn, item.id, item.user_id, auth_user.user_id, ItemAction::Created, None, Some(&item))?;
+        Ok(item)
+    })?;
+    events.publish(ItemAction::Created, &item);
+    metrics.record_change(ItemAction::Created);
     Ok(Json(item))
 }
 
-pub fn get_todo_item(
-    pool: &State<PgPool>,
-    auth_user: AuthenticatedUser,
-    item_id_str: String,
-) -> Result<Json<TodoItem>, ServiceError> {
+pub(crate) fn parse_item_id(item_id_str: &str) -> Result<Uuid, ServiceError> {
+    Uuid::parse_str(item_id_str)
+        .map_err(|_| ServiceError::InvalidInput("invalid_uuid", "Invalid UUID format".to_string()))
+}
+
+// Loads an item only if it belongs to the given user and is not in the trash. Other services that hang
+// data off a todo item (comments, attachments, history) go through this for their access checks.
+pub(crate) fn find_owned_item(
+    conn: &mut PgConnection,
+    owner_id: Uuid,
+    item_uuid: Uuid,
+) -> Result<TodoItem, ServiceError> {
     use crate::schema::todo_items::dsl::*;
-    let mut conn = pool.get().map_err(|_| ServiceError::InternalError("Failed to get DB connection".to_string()))?;
-    let item_uuid = Uuid::parse_str(&item_id_str)
-        .map_err(|_| ServiceError::InvalidInput("Invalid UUID format".to_string()))?;
+    let item = todo_items
+        .filter(id.eq(item_uuid).and(user_id.eq(owner_id)))
+        .filter(deleted_at.is_null())
+        .select(TodoItem::as_select())
+        .first::<TodoItem>(conn)
+        .optional()?;
 
+    item.ok_or_else(|| ServiceError::NotFound("todo_not_found", "Todo item not found".to_string()))
+}
+
+// Like `find_owned_item`, but locks the row until the transaction ends, so the
+// item cannot change between checking `If-Match` against it and writing it
+fn lock_owned_item(
+    conn: &mut PgConnection,
+    owner_id: Uuid,
+    item_uuid: Uuid,
+) -> Result<TodoItem, ServiceError> {
+    use crate::schema::todo_items::dsl::*;
     let item = todo_items
-        .filter(id.eq(item_uuid).and(user_id.eq(auth_user.user_id)))
+        .filter(id.eq(item_uuid).and(user_id.eq(owner_id)))
+        .filter(deleted_at.is_null())
         .select(TodoItem::as_select())
-        .first::<TodoItem>(&mut conn)
+        .for_update()
+        .first::<TodoItem>(conn)
         .optional()?;
 
-    match item {
-        Some(it) => Ok(Json(it)),
-        None => Err(ServiceError::NotFound("Todo item not found".to_string())),
-    }
+    item.ok_or_else(|| ServiceError::NotFound("todo_not_found", "Todo item not found".to_string()))
 }
 
-pub fn complete_todo_item(
-    pool: &State<PgPool>,
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn get_todo_item(
+    conn: &mut PgConnection,
     auth_user: AuthenticatedUser,
     item_id_str: String,
 ) -> Result<Json<TodoItem>, ServiceError> {
+    let item_uuid = parse_item_id(&item_id_str)?;
+
+    let item = find_owned_item(conn, auth_user.user_id, item_uuid)?;
+    Ok(Json(item))
+}
+
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn complete_todo_item(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    metrics: &Metrics,
+    undo_config: &UndoConfig,
+    auth_user: AuthenticatedUser,
+    item_id_str: String,
+    if_match: &IfMatch,
+) -> Result<Json<Undoable<TodoItem>>, ServiceError> {
     use crate::schema::todo_items::dsl::*;
-    let mut conn = pool.get().map_err(|_| ServiceError::InternalError("Failed to get DB connection".to_string()))?;
-    let item_uuid = Uuid::parse_str(&item_id_str)
-        .map_err(|_| ServiceError::InvalidInput("Invalid UUID format".to_string()))?;
+    let item_uuid = parse_item_id(&item_id_str)?;
 
-    let updated_item = diesel::update(todo_items.filter(id.eq(item_uuid).and(user_id.eq(auth_user.user_id))))
-        .set(completed.eq(true))
-        .get_result::<TodoItem>(&mut conn)
-        .optional()?;
+    let updated_item = conn.transaction::<_, ServiceError, _>(|conn| {
+        let existing = lock_owned_item(conn, auth_user.user_id, item_uuid)?;
+        if_match.check(existing.version)?;
+
+        let updated = diesel::update(todo_items.filter(id.eq(existing.id)))
+            .set(completed.eq(true))
+            .get_result::<TodoItem>(conn)?;
+        record_event(conn, updated.id, updated.user_id, auth_user.user_id, ItemAction::Completed, Some(&existing), Some(&updated))?;
+        issue_token(conn, undo_config, ItemAction::Completed, &existing, updated)
+    })?;
+
+    events.publish(ItemAction::Completed, &updated_item.item);
+    metrics.record_change(ItemAction::Completed);
+    Ok(Json(updated_item))
+}
+
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn update_todo_item(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    metrics: &Metrics,
+    auth_user: AuthenticatedUser,
+    item_id_str: String,
+    update_req: Json<UpdateTodoRequest>,
+    if_match: &IfMatch,
+) -> Result<Json<TodoItem>, ServiceError> {
+    use crate::schema::todo_items::dsl::*;
+    let item_uuid = parse_item_id(&item_id_str)?;
 
-    match updated_item {
-        Some(it) => Ok(Json(it)),
-        None => Err(ServiceError::NotFound("Todo item not found or not owned by user".to_string())),
+    if let Some(ref new_description) = update_req.description {
+        if new_description.trim().is_empty() {
+            return Err(ServiceError::invalid_field("description", "required", "Description must not be empty"));
+        }
     }
+
+    let (change, updated_item) = conn.transaction::<_, ServiceError, _>(|conn| {
+        let existing = lock_owned_item(conn, auth_user.user_id, item_uuid)?;
+        if_match.check(existing.version)?;
+
+        let updated = diesel::update(todo_items.filter(id.eq(existing.id)))
+            .set((
+                description.eq(update_req.description.clone().unwrap_or_else(|| existing.description.clone())),
+                completed.eq(update_req.completed.unwrap_or(existing.completed)),
+            ))
+            .get_result::<TodoItem>(conn)?;
+        record_event(conn, updated.id, updated.user_id, auth_user.user_id, ItemAction::Updated, Some(&existing), Some(&updated))?;
+        Ok((ItemAction::of_update(&existing, &updated), updated))
+    })?;
+
+    events.publish(change, &updated_item);
+    metrics.record_change(change);
+    Ok(Json(updated_item))
 }
 
-fn _build_todo_query<'a>(
+// Trashed items are only ever listed when `trashed` is set, and then exclusively
+pub(crate) fn _build_todo_query<'a>(
     auth_user: &AuthenticatedUser,
     search_query: &TodoSearchQuery,
+    trashed: bool,
 ) -> todo_items::BoxedQuery<'a, diesel::pg::Pg> {
     use crate::schema::todo_items::dsl::*;
     let mut query = todo_items
         .filter(user_id.eq(auth_user.user_id))
         .into_boxed();
 
+    if trashed {
+        query = query.filter(deleted_at.is_not_null());
+    } else {
+        query = query.filter(deleted_at.is_null());
+    }
+
     if let Some(ref desc_filter) = search_query.description {
         query = query.filter(description.ilike(format!("%{}%", desc_filter)));
     }
@@ -88,57 +191,82 @@ fn _build_todo_query<'a>(
     query
 }
 
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
 pub fn list_or_search_todos(
-    pool: &State<PgPool>,
+    conn: &mut PgConnection,
     auth_user: AuthenticatedUser,
     search_query: TodoSearchQuery,
-) -> Result<Json<Vec<TodoItem>>, ServiceError> {
-    let mut conn = pool.get().map_err(|_| ServiceError::InternalError("Failed to get DB connection".to_string()))?;
+) -> Result<Json<Vec<TodoListItem>>, ServiceError> {
 
-    let query = _build_todo_query(&auth_user, &search_query);
+    let query = _build_todo_query(&auth_user, &search_query, false);
 
     let items = query
         .order(todo_items::created_at.desc())
         .select(TodoItem::as_select())
-        .load::<TodoItem>(&mut conn)?;
+        .load::<TodoItem>(conn)?;
+
+    // One grouped query for the comment counts of the whole page
+    let item_ids: Vec<Uuid> = items.iter().map(|item| item.id).collect();
+    let comment_counts: HashMap<Uuid, i64> = todo_comments::table
+        .filter(todo_comments::todo_item_id.eq_any(&item_ids))
+        .filter(todo_comments::deleted_at.is_null())
+        .group_by(todo_comments::todo_item_id)
+        .select((todo_comments::todo_item_id, diesel::dsl::count_star()))
+        .load::<(Uuid, i64)>(conn)?
+        .into_iter()
+        .collect();
+
+    let listed = items
+        .into_iter()
+        .map(|item| {
+            let comment_count = comment_counts.get(&item.id).copied().unwrap_or(0);
+            TodoListItem { item, comment_count }
+        })
+        .collect();
 
-    Ok(Json(items))
+    Ok(Json(listed))
 }
 
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
 pub fn get_todos_count(
-    pool: &State<PgPool>,
+    conn: &mut PgConnection,
     auth_user: AuthenticatedUser,
     search_query: TodoSearchQuery,
 ) -> Result<Json<i64>, ServiceError> {
-    let mut conn = pool.get().map_err(|_| ServiceError::InternalError("Failed to get DB connection".to_string()))?;
 
-    let query = _build_todo_query(&auth_user, &search_query);
+    let query = _build_todo_query(&auth_user, &search_query, false);
 
     let count_val = query
         .count()
-        .get_result(&mut conn)?;
+        .get_result(conn)?;
 
     Ok(Json(count_val))
 }
 
+// Moves an item to the trash; see `services::trash` for restoring and purging
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
 pub fn delete_todo_item(
-    pool: &State<PgPool>,
+    conn: &mut PgConnection,
+    events: &EventBus,
+    undo_config: &UndoConfig,
     auth_user: AuthenticatedUser,
     item_id_str: String,
-) -> Result<(), ServiceError> {
+    if_match: &IfMatch,
+) -> Result<Json<Undoable<TodoItem>>, ServiceError> {
     use crate::schema::todo_items::dsl::*;
-    let mut conn = pool.get().map_err(|_| ServiceError::InternalError("Failed to get DB connection".to_string()))?;
-    let item_uuid = Uuid::parse_str(&item_id_str)
-        .map_err(|_| ServiceError::InvalidInput("Invalid UUID format".to_string()))?;
+    let item_uuid = parse_item_id(&item_id_str)?;
 
-    let target = todo_items.filter(id.eq(item_uuid).and(user_id.eq(auth_user.user_id)));
+    let trashed_item = conn.transaction::<_, ServiceError, _>(|conn| {
+        let existing = lock_owned_item(conn, auth_user.user_id, item_uuid)?;
+        if_match.check(existing.version)?;
 
-    let num_deleted = diesel::delete(target)
-        .execute(&mut conn)?;
+        let trashed = diesel::update(todo_items.filter(id.eq(existing.id)))
+            .set(deleted_at.eq(Some(Utc::now().naive_utc())))
+            .get_result::<TodoItem>(conn)?;
+        record_event(conn, trashed.id, trashed.user_id, auth_user.user_id, ItemAction::Deleted, Some(&existing), Some(&trashed))?;
+        issue_token(conn, undo_config, ItemAction::Deleted, &existing, trashed)
+    })?;
 
-    if num_deleted > 0 {
-        Ok(())
-    } else {
-        Err(ServiceError::NotFound("Todo item not found or not owned by user".to_string()))
-    }
+    events.publish(ItemAction::Deleted, &trashed_item.item);
+    Ok(Json(trashed_item))
 }
@@ -0,0 +1,245 @@
+use crate::config::AppConfig;
+use crate::db::{DbConn, PgPool};
+use crate::models::{TodoItem, TodoSearchQuery};
+use crate::schema::{todo_attachments, todo_items};
+use crate::storage::AttachmentStore;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use rocket::fairing::AdHoc;
+use rocket::serde::Deserialize;
+use rocket::serde::json::Json;
+use rocket::State;
+use uuid::Uuid;
+use crate::AuthenticatedUser;
+use crate::events::EventBus;
+use super::attachments::remove_stored_contents;
+use super::error::ServiceError;
+use super::history::{record_event, ItemAction};
+use super::todos::{_build_todo_query, parse_item_id};
+use tracing::instrument;
+
+// Upper bound on items purged per transaction by the background job
+const PURGE_BATCH_SIZE: i64 = 500;
+
+/// The `[trash]` table in Rocket.toml (or `APP_TRASH__*`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct TrashConfig {
+    /// Days an item stays in the trash before it is purged; 0 keeps it forever.
+    pub retention_days: u32,
+    /// Seconds between runs of the purge job.
+    pub purge_interval_secs: u64,
+}
+
+impl Default for TrashConfig {
+    fn default() -> Self {
+        TrashConfig {
+            retention_days: 30,
+            purge_interval_secs: 3600,
+        }
+    }
+}
+
+pub(crate) fn find_trashed_item(
+    conn: &mut PgConnection,
+    owner_id: Uuid,
+    item_uuid: Uuid,
+) -> Result<TodoItem, ServiceError> {
+    use crate::schema::todo_items::dsl::*;
+    let item = todo_items
+        .filter(id.eq(item_uuid).and(user_id.eq(owner_id)))
+        .filter(deleted_at.is_not_null())
+        .select(TodoItem::as_select())
+        .first::<TodoItem>(conn)
+        .optional()?;
+
+    item.ok_or_else(|| ServiceError::NotFound("todo_not_found", "Todo item not found in trash".to_string()))
+}
+
+// Permanently deletes the given items and records a `purged` event for each.
+// Returns the storage keys of their attachments, whose rows cascade away with
+// the items; the caller removes the contents once the transaction committed.
+fn purge_items(
+    conn: &mut PgConnection,
+    items: &[TodoItem],
+    actor: Option<Uuid>,
+) -> Result<Vec<String>, ServiceError> {
+    let item_ids: Vec<Uuid> = items.iter().map(|item| item.id).collect();
+
+    let storage_keys = todo_attachments::table
+        .filter(todo_attachments::todo_item_id.eq_any(&item_ids))
+        .select(todo_attachments::storage_key)
+        .load::<String>(conn)?;
+
+    diesel::delete(todo_items::table.filter(todo_items::id.eq_any(&item_ids)))
+        .execute(conn)?;
+
+    for item in items {
+        // Automatic purges have no acting user; attribute them to the owner
+        let actor_id = actor.unwrap_or(item.user_id);
+        record_event(conn, item.id, item.user_id, actor_id, ItemAction::Purged, Some(item), None)?;
+    }
+    Ok(storage_keys)
+}
+
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn list_trash(
+    conn: &mut PgConnection,
+    auth_user: AuthenticatedUser,
+    search_query: TodoSearchQuery,
+) -> Result<Json<Vec<TodoItem>>, ServiceError> {
+
+    let items = _build_todo_query(&auth_user, &search_query, true)
+        .order(todo_items::deleted_at.desc())
+        .select(TodoItem::as_select())
+        .load::<TodoItem>(conn)?;
+
+    Ok(Json(items))
+}
+
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn restore_todo_item(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    auth_user: AuthenticatedUser,
+    item_id_str: String,
+) -> Result<Json<TodoItem>, ServiceError> {
+    use crate::schema::todo_items::dsl::*;
+    let item_uuid = parse_item_id(&item_id_str)?;
+
+    let restored = conn.transaction::<_, ServiceError, _>(|conn| {
+        let existing = find_trashed_item(conn, auth_user.user_id, item_uuid)?;
+
+        let restored = diesel::update(todo_items.filter(id.eq(existing.id)))
+            .set(deleted_at.eq(None::<NaiveDateTime>))
+            .get_result::<TodoItem>(conn)?;
+        record_event(conn, restored.id, restored.user_id, auth_user.user_id, ItemAction::Restored, Some(&existing), Some(&restored))?;
+        Ok(restored)
+    })?;
+
+    events.publish(ItemAction::Restored, &restored);
+    Ok(Json(restored))
+}
+
+#[instrument(parent = conn.span(), skip_all, fields(user_id = %auth_user.user_id))]
+pub async fn purge_todo_item(
+    conn: &mut DbConn,
+    store: &State<AttachmentStore>,
+    auth_user: AuthenticatedUser,
+    item_id_str: String,
+) -> Result<(), ServiceError> {
+    let item_uuid = parse_item_id(&item_id_str)?;
+
+    let storage_keys = conn
+        .run(move |conn| {
+            conn.transaction::<_, ServiceError, _>(|conn| {
+                let item = find_trashed_item(conn, auth_user.user_id, item_uuid)?;
+                purge_items(conn, &[item], Some(auth_user.user_id))
+            })
+        })
+        .await?;
+
+    remove_stored_contents(store, storage_keys).await;
+    Ok(())
+}
+
+#[instrument(parent = conn.span(), skip_all, fields(user_id = %auth_user.user_id))]
+pub async fn empty_trash(
+    conn: &mut DbConn,
+    store: &State<AttachmentStore>,
+    auth_user: AuthenticatedUser,
+) -> Result<(), ServiceError> {
+    let storage_keys = conn
+        .run(move |conn| {
+            use crate::schema::todo_items::dsl::*;
+            conn.transaction::<_, ServiceError, _>(|conn| {
+                let items = todo_items
+                    .filter(user_id.eq(auth_user.user_id))
+                    .filter(deleted_at.is_not_null())
+                    .select(TodoItem::as_select())
+                    .for_update()
+                    .load::<TodoItem>(conn)?;
+                purge_items(conn, &items, Some(auth_user.user_id))
+            })
+        })
+        .await?;
+
+    remove_stored_contents(store, storage_keys).await;
+    Ok(())
+}
+
+/// Purges one batch of items that have been in the trash since before `cutoff`.
+/// Returns the number of items purged and the attachment contents to remove.
+#[instrument(skip_all, fields(%cutoff))]
+pub fn purge_expired(
+    conn: &mut PgConnection,
+    cutoff: NaiveDateTime,
+) -> Result<(usize, Vec<String>), ServiceError> {
+    use crate::schema::todo_items::dsl::*;
+    conn.transaction::<_, ServiceError, _>(|conn| {
+        let items = todo_items
+            .filter(deleted_at.lt(cutoff))
+            .order(deleted_at.asc())
+            .limit(PURGE_BATCH_SIZE)
+            .select(TodoItem::as_select())
+            .for_update()
+            .skip_locked()
+            .load::<TodoItem>(conn)?;
+        let storage_keys = purge_items(conn, &items, None)?;
+        Ok((items.len(), storage_keys))
+    })
+}
+
+async fn run_purge(pool: PgPool, store: AttachmentStore, retention: Duration) -> Result<usize, ServiceError> {
+    let mut total = 0;
+    loop {
+        let cutoff = Utc::now().naive_utc() - retention;
+        let batch_pool = pool.clone();
+        let (purged, storage_keys) = rocket::tokio::task::spawn_blocking(move || {
+            let mut conn = batch_pool.get()?;
+            purge_expired(&mut conn, cutoff)
+        })
+        .await
+        .map_err(|e| ServiceError::InternalError(format!("Purge task failed: {}", e)))??;
+
+        remove_stored_contents(&store, storage_keys).await;
+        total += purged;
+        if purged < PURGE_BATCH_SIZE as usize {
+            return Ok(total);
+        }
+    }
+}
+
+// Fairing that periodically purges items older than the retention period
+pub fn stage() -> AdHoc {
+    AdHoc::on_liftoff("Trash Purge", |rocket| Box::pin(async move {
+        let Some(config) = rocket.state::<AppConfig>().map(|config| config.trash.clone()) else {
+            return;
+        };
+        if config.retention_days == 0 {
+            return;
+        }
+        let (Some(pool), Some(store)) = (rocket.state::<PgPool>().cloned(), rocket.state::<AttachmentStore>().cloned()) else {
+            return;
+        };
+
+        let retention = Duration::days(config.retention_days.into());
+        let interval = std::time::Duration::from_secs(config.purge_interval_secs.max(1));
+        let mut shutdown = rocket.shutdown();
+        rocket::tokio::spawn(async move {
+            let mut ticker = rocket::tokio::time::interval(interval);
+            loop {
+                rocket::tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = &mut shutdown => return,
+                }
+                match run_purge(pool.clone(), store.clone(), retention).await {
+                    Ok(0) => {}
+                    Ok(purged) => tracing::info!(purged, "purged expired items from the trash"),
+                    Err(e) => tracing::error!(error = %e, "trash purge failed"),
+                }
+            }
+        });
+    }))
+}
@@ -0,0 +1,136 @@
+use crate::models::{NewUndoToken, TodoItem, UndoToken, Undoable};
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+use rocket::serde::Deserialize;
+use rocket::serde::json::Json;
+use uuid::Uuid;
+use crate::AuthenticatedUser;
+use crate::events::EventBus;
+use super::error::ServiceError;
+use super::history::{record_event, ItemAction};
+use super::todos::find_owned_item;
+use super::trash::find_trashed_item;
+use tracing::instrument;
+
+/// The `[undo]` table in Rocket.toml (or `APP_UNDO__*`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct UndoConfig {
+    /// Seconds during which an undo token can be redeemed.
+    pub window_secs: u32,
+}
+
+impl Default for UndoConfig {
+    fn default() -> Self {
+        UndoConfig { window_secs: 30 }
+    }
+}
+
+/// Issues a token that reverts `item_action` on `after`, given the item's state
+/// `before` it. Call this inside the transaction that performed the action so
+/// a token never exists for a change that was rolled back.
+pub(crate) fn issue_token(
+    conn: &mut PgConnection,
+    config: &UndoConfig,
+    item_action: ItemAction,
+    before: &TodoItem,
+    after: TodoItem,
+) -> Result<Undoable<TodoItem>, ServiceError> {
+    use crate::schema::undo_tokens::dsl::*;
+    let now = Utc::now().naive_utc();
+
+    // Expired tokens are useless; drop this user's while we are here
+    diesel::delete(undo_tokens.filter(user_id.eq(after.user_id)).filter(expires_at.lt(now)))
+        .execute(conn)?;
+
+    let snapshot = serde_json::to_value(before)
+        .map_err(|_| ServiceError::InternalError("Failed to serialize todo item".to_string()))?;
+    let new_token = NewUndoToken {
+        user_id: after.user_id,
+        todo_item_id: after.id,
+        action: item_action.as_str().to_string(),
+        previous: snapshot,
+        expires_at: now + Duration::seconds(config.window_secs.into()),
+    };
+
+    let token = diesel::insert_into(undo_tokens)
+        .values(&new_token)
+        .get_result::<UndoToken>(conn)?;
+
+    Ok(Undoable {
+        item: after,
+        undo_token: token.id,
+        undo_expires_at: token.expires_at,
+    })
+}
+
+// The item an undo token refers to having moved on is a conflict; failing to look it up is not
+fn gone(e: ServiceError, message: &str) -> ServiceError {
+    match e {
+        ServiceError::NotFound(..) => ServiceError::Conflict("undo_conflict", message.to_string()),
+        e => e,
+    }
+}
+
+#[instrument(skip_all, fields(user_id = %auth_user.user_id))]
+pub fn undo(
+    conn: &mut PgConnection,
+    events: &EventBus,
+    auth_user: AuthenticatedUser,
+    token_str: String,
+) -> Result<Json<TodoItem>, ServiceError> {
+    use crate::schema::todo_items;
+    use crate::schema::undo_tokens::dsl::*;
+    let token_uuid = Uuid::parse_str(&token_str)
+        .map_err(|_| ServiceError::InvalidInput("invalid_undo_token", "Invalid undo token format".to_string()))?;
+
+    let (change, reverted) = conn.transaction::<_, ServiceError, _>(|conn| {
+        let now = Utc::now().naive_utc();
+        // Lock the token so concurrent requests cannot redeem it twice
+        let token = undo_tokens
+            .filter(id.eq(token_uuid).and(user_id.eq(auth_user.user_id)))
+            .filter(used_at.is_null())
+            .filter(expires_at.gt(now))
+            .select(UndoToken::as_select())
+            .for_update()
+            .first::<UndoToken>(conn)
+            .optional()?
+            .ok_or_else(|| ServiceError::NotFound("undo_token_not_found", "Undo token not found or expired".to_string()))?;
+
+        let previous_item: TodoItem = serde_json::from_value(token.previous.clone())
+            .map_err(|_| ServiceError::InternalError("Corrupt undo token".to_string()))?;
+
+        let (change, reverted) = if token.action == ItemAction::Completed.as_str() {
+            let current = find_owned_item(conn, auth_user.user_id, token.todo_item_id)
+                .map_err(|e| gone(e, "Todo item is no longer available"))?;
+            if !current.completed {
+                return Err(ServiceError::Conflict("undo_conflict", "Todo item was changed since it was completed".to_string()));
+            }
+            let reverted = diesel::update(todo_items::table.filter(todo_items::id.eq(current.id)))
+                .set(todo_items::completed.eq(previous_item.completed))
+                .get_result::<TodoItem>(conn)?;
+            record_event(conn, reverted.id, reverted.user_id, auth_user.user_id, ItemAction::Uncompleted, Some(&current), Some(&reverted))?;
+            // Streams announce it like any other un-completion (see the change notification trigger)
+            (ItemAction::Updated, reverted)
+        } else if token.action == ItemAction::Deleted.as_str() {
+            let current = find_trashed_item(conn, auth_user.user_id, token.todo_item_id)
+                .map_err(|e| gone(e, "Todo item is no longer in the trash"))?;
+            let reverted = diesel::update(todo_items::table.filter(todo_items::id.eq(current.id)))
+                .set(todo_items::deleted_at.eq(previous_item.deleted_at))
+                .get_result::<TodoItem>(conn)?;
+            record_event(conn, reverted.id, reverted.user_id, auth_user.user_id, ItemAction::Restored, Some(&current), Some(&reverted))?;
+            (ItemAction::Restored, reverted)
+        } else {
+            return Err(ServiceError::InternalError(format!("Unsupported undo action: {}", token.action)));
+        };
+
+        diesel::update(undo_tokens.filter(id.eq(token.id)))
+            .set(used_at.eq(Some(now)))
+            .execute(conn)?;
+        Ok((change, reverted))
+    })?;
+
+    events.publish(change, &reverted);
+    Ok(Json(reverted))
+}
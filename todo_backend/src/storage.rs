@@ -0,0 +1,108 @@
+// todo_backend/src/storage.rs
+use crate::config::AppConfig;
+use rocket::data::ByteUnit;
+use rocket::fairing::AdHoc;
+use rocket::fs::TempFile;
+use rocket::serde::Deserialize;
+use rocket::tokio::fs::{self, File};
+use rocket::tokio::io::AsyncRead;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// Where attachment contents live. Metadata is kept in `todo_attachments`;
+/// implementations only deal with opaque keys and bytes.
+#[rocket::async_trait]
+pub trait AttachmentStorage: Send + Sync {
+    /// Stores the uploaded file under `key`, replacing anything already there.
+    async fn store(&self, key: &str, file: &mut TempFile<'_>) -> io::Result<()>;
+
+    /// Opens the contents stored under `key` for reading.
+    async fn open(&self, key: &str) -> io::Result<Pin<Box<dyn AsyncRead + Send>>>;
+
+    /// Removes the contents stored under `key`. Missing keys are not an error.
+    async fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+/// Stores attachments as plain files below a root directory.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalStorage { root: root.into() }
+    }
+
+    // Keys are generated by us, but never let one escape the root directory
+    fn path_for(&self, key: &str) -> io::Result<PathBuf> {
+        let relative = Path::new(key);
+        if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid storage key"));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+#[rocket::async_trait]
+impl AttachmentStorage for LocalStorage {
+    async fn store(&self, key: &str, file: &mut TempFile<'_>) -> io::Result<()> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // The temp dir may be on another filesystem, so copy rather than rename
+        file.move_copy_to(&path).await
+    }
+
+    async fn open(&self, key: &str) -> io::Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let file = File::open(self.path_for(key)?).await?;
+        Ok(Box::pin(file))
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path_for(key)?).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The `[attachments]` table in Rocket.toml (or `APP_ATTACHMENTS__*`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct AttachmentsConfig {
+    pub dir: PathBuf,
+    pub max_size: ByteUnit,
+}
+
+impl Default for AttachmentsConfig {
+    fn default() -> Self {
+        AttachmentsConfig {
+            dir: PathBuf::from("attachments"),
+            max_size: ByteUnit::Mebibyte(10),
+        }
+    }
+}
+
+/// Managed state handed to the attachment routes.
+#[derive(Clone)]
+pub struct AttachmentStore {
+    pub backend: Arc<dyn AttachmentStorage>,
+    pub max_size: ByteUnit,
+}
+
+// Fairing for attaching the configured storage backend to Rocket's managed state
+pub fn stage() -> AdHoc {
+    AdHoc::try_on_ignite("Attachment Storage", |rocket| async {
+        let Some(config) = rocket.state::<AppConfig>().map(|config| config.attachments.clone()) else {
+            return Err(rocket);
+        };
+        Ok(rocket.manage(AttachmentStore {
+            backend: Arc::new(LocalStorage::new(config.dir)),
+            max_size: config.max_size,
+        }))
+    })
+}
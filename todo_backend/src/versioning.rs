@@ -0,0 +1,93 @@
+// todo_backend/src/versioning.rs
+use chrono::NaiveDate;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::serde::Deserialize;
+use rocket::{Request, Response};
+
+/// Where the current version of the API is mounted.
+pub const API_V1: &str = "/api/v1";
+
+/// Where the current version of the auth routes is mounted.
+pub const API_V1_AUTH: &str = "/api/v1/auth";
+
+/// The unversioned mount points that predate `/api/v1`.
+pub const LEGACY_API: &str = "/api";
+pub const LEGACY_AUTH: &str = "/auth";
+
+/// The `[legacy_api]` table in Rocket.toml (or `APP_LEGACY_API__*`).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+#[serde(default)]
+pub struct LegacyApiConfig {
+    /// Keep serving the routes at their unversioned `/api` and `/auth` paths.
+    pub enabled: bool,
+    /// When the unversioned paths were deprecated, sent as `Deprecation`.
+    pub deprecated_on: NaiveDate,
+    /// When they will be removed, sent as `Sunset`; unset to omit the header.
+    pub sunset_on: Option<NaiveDate>,
+}
+
+impl Default for LegacyApiConfig {
+    fn default() -> Self {
+        LegacyApiConfig {
+            enabled: true,
+            deprecated_on: NaiveDate::from_ymd_opt(2026, 10, 18).unwrap(),
+            sunset_on: NaiveDate::from_ymd_opt(2027, 4, 30),
+        }
+    }
+}
+
+/// The `/api/v1` equivalent of a path under one of the legacy mounts.
+pub fn successor_path(path: &str) -> String {
+    match path.strip_prefix(LEGACY_AUTH) {
+        Some(rest) => format!("{}{}", API_V1_AUTH, rest),
+        None => format!("{}{}", API_V1, path.strip_prefix(LEGACY_API).unwrap_or(path)),
+    }
+}
+
+/// Marks responses from routes at the legacy mounts as deprecated
+/// (RFC 9745 `Deprecation`, RFC 8594 `Sunset`) and links to their successor.
+pub struct LegacyApiHeaders {
+    deprecation: String,
+    sunset: Option<String>,
+}
+
+impl LegacyApiHeaders {
+    pub fn new(config: &LegacyApiConfig) -> Self {
+        let midnight = |date: NaiveDate| date.and_hms_opt(0, 0, 0).unwrap().and_utc();
+        LegacyApiHeaders {
+            deprecation: format!("@{}", midnight(config.deprecated_on).timestamp()),
+            sunset: config
+                .sunset_on
+                .map(|date| midnight(date).format("%a, %d %b %Y %H:%M:%S GMT").to_string()),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for LegacyApiHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Legacy API Deprecation Headers",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(route) = request.route() else {
+            return;
+        };
+        let base = route.uri.base();
+        if base != LEGACY_API && base != LEGACY_AUTH {
+            return;
+        }
+
+        response.set_header(Header::new("Deprecation", self.deprecation.clone()));
+        if let Some(sunset) = &self.sunset {
+            response.set_header(Header::new("Sunset", sunset.clone()));
+        }
+        let successor = successor_path(request.uri().path().as_str());
+        response.set_header(Header::new("Link", format!("<{}>; rel=\"successor-version\"", successor)));
+    }
+}
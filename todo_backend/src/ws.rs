@@ -0,0 +1,357 @@
+// todo_backend/src/ws.rs
+use crate::config::AppConfig;
+use crate::db::PgPool;
+use crate::etag::IfMatch;
+use crate::events::{EventBus, Subscription, Update};
+use crate::logging::RequestId;
+use crate::metrics::Metrics;
+use crate::models::{CreateTodoRequest, TodoItem, TodoSearchQuery};
+use crate::ratelimit::RateLimiter;
+use crate::services::{self, error::ServiceError};
+use crate::{ApiError, AuthenticatedUser, ErrorDetail, InternalErrorDetail};
+use diesel::PgConnection;
+use rocket::data::{IoHandler, IoStream};
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::{Json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::select;
+use rocket::tokio::time;
+use rocket::Shutdown;
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+use tracing::Instrument;
+use uuid::Uuid;
+
+// How often the session behind a socket is re-checked when heartbeats
+// (`events.heartbeat_secs`) are disabled
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A WebSocket handshake (RFC 6455) from a page on this site, or from a
+/// client that is not a browser.
+pub struct WebSocketUpgrade {
+    accept_key: String,
+}
+
+impl WebSocketUpgrade {
+    /// Completes the handshake; `handler` then takes over the connection.
+    pub fn accept<H>(self, handler: H) -> WebSocketResponse<H> {
+        WebSocketResponse { accept_key: self.accept_key, handler }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for WebSocketUpgrade {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let upgrade = headers.get_one("Upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+        let (true, Some(key)) = (upgrade, headers.get_one("Sec-WebSocket-Key")) else {
+            return ApiError::BadRequest(Json(ErrorDetail::new(
+                "websocket_upgrade_required",
+                "Connect with a WebSocket client",
+            )))
+            .fail_guard(request);
+        };
+        if headers.get_one("Sec-WebSocket-Version") != Some("13") {
+            return ApiError::BadRequest(Json(ErrorDetail::new(
+                "unsupported_websocket_version",
+                "Only WebSocket version 13 is supported",
+            )))
+            .fail_guard(request);
+        }
+
+        // Browsers send the session cookie along with WebSocket requests from
+        // any site, so those from pages elsewhere are refused unless they are
+        // named in `cors.allowed_origins`
+        if let Some(origin) = headers.get_one("Origin") {
+            let origin_host = origin.split_once("://").map(|(_, host)| host);
+            let listed = request.rocket().state::<AppConfig>().is_some_and(|config| config.cors.lists_origin(origin));
+            if !listed && (origin_host.is_none() || origin_host != headers.get_one("Host")) {
+                return ApiError::Forbidden(Json(ErrorDetail::new(
+                    "cross_origin_websocket",
+                    "WebSockets can only be opened from pages on this site",
+                )))
+                .fail_guard(request);
+            }
+        }
+
+        request::Outcome::Success(WebSocketUpgrade { accept_key: derive_accept_key(key.as_bytes()) })
+    }
+}
+
+/// Switches the connection to the WebSocket protocol and hands it over.
+pub struct WebSocketResponse<H> {
+    accept_key: String,
+    handler: H,
+}
+
+impl<'r, 'o: 'r, H: IoHandler + 'o> Responder<'r, 'o> for WebSocketResponse<H> {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'o> {
+        Response::build()
+            .status(Status::SwitchingProtocols)
+            .raw_header("Sec-WebSocket-Accept", self.accept_key)
+            .upgrade("websocket", self.handler)
+            .ok()
+    }
+}
+
+// A client message; `id`, when given, is echoed in the reply. `version`
+// works like an `If-Match` header on the REST API.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+enum Command {
+    Subscribe,
+    Unsubscribe,
+    Create { description: String },
+    Complete { item_id: String, version: Option<i32> },
+    Delete { item_id: String, version: Option<i32> },
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde", tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Result { id: Option<Value>, result: Value },
+    Error { id: Option<Value>, error: ErrorDetail },
+    Event { event: &'static str, event_id: u64, item: TodoItem },
+    Resync,
+}
+
+impl ServerMessage {
+    fn into_frame(self) -> Message {
+        Message::Text(serde_json::to_string(&self).expect("server messages serialize"))
+    }
+}
+
+impl From<Update> for ServerMessage {
+    fn from(update: Update) -> Self {
+        match update {
+            Update::Change(event) => ServerMessage::Event {
+                event: event.action.as_str(),
+                event_id: event.id,
+                item: event.item,
+            },
+            Update::Resync => ServerMessage::Resync,
+        }
+    }
+}
+
+fn to_value<T: Serialize>(value: Json<T>) -> Result<Value, ApiError> {
+    serde_json::to_value(value.into_inner())
+        .map_err(|e| ApiError::InternalError(InternalErrorDetail(format!("Failed to serialize result: {}", e))))
+}
+
+async fn next_update(subscription: &mut Option<Subscription>) -> Option<Update> {
+    match subscription {
+        Some(subscription) => subscription.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The managed state a socket works with.
+pub struct SocketState<'r> {
+    pub config: &'r AppConfig,
+    pub pool: &'r PgPool,
+    pub bus: &'r EventBus,
+    pub metrics: &'r Metrics,
+    /// Present while rate limiting is enabled.
+    pub limiter: Option<&'r RateLimiter>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SocketState<'r> {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let rocket = request.rocket();
+        match (rocket.state(), rocket.state(), rocket.state(), rocket.state()) {
+            (Some(config), Some(pool), Some(bus), Some(metrics)) => {
+                request::Outcome::Success(SocketState { config, pool, bus, metrics, limiter: rocket.state() })
+            }
+            _ => ApiError::InternalError(InternalErrorDetail("WebSocket state is not managed".to_string()))
+                .fail_guard(request),
+        }
+    }
+}
+
+/// A connected `/api/v1/ws` client. It is authorized as the session it
+/// connected with for as long as that session stays valid.
+pub struct TodoSocket<'r> {
+    pub state: SocketState<'r>,
+    pub user: AuthenticatedUser,
+    pub request_id: RequestId,
+    pub shutdown: Shutdown,
+}
+
+#[rocket::async_trait]
+impl IoHandler for TodoSocket<'_> {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let span = tracing::info_span!("websocket", request_id = %self.request_id.0, user_id = %self.user.user_id);
+        let socket = WebSocketStream::from_raw_socket(io, Role::Server, None).await;
+        match Pin::into_inner(self).serve(socket).instrument(span).await {
+            Ok(()) | Err(WsError::ConnectionClosed | WsError::AlreadyClosed) => Ok(()),
+            Err(WsError::Io(e)) => Err(e),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+impl TodoSocket<'_> {
+    async fn serve(mut self, mut socket: WebSocketStream<IoStream>) -> Result<(), WsError> {
+        let heartbeat = self.state.config.events.heartbeat();
+        let mut ticks = time::interval(heartbeat.unwrap_or(SESSION_CHECK_INTERVAL));
+        ticks.tick().await;
+        let mut subscription = None;
+
+        let close = loop {
+            select! {
+                incoming = socket.next() => match incoming {
+                    Some(Ok(Message::Text(text))) => {
+                        let (reply, session_ended) = self.respond(&text, &mut subscription).await;
+                        socket.send(reply.into_frame()).await?;
+                        if session_ended {
+                            break Some(CloseFrame { code: CloseCode::Policy, reason: "session ended".into() });
+                        }
+                    }
+                    Some(Ok(Message::Binary(_))) => {
+                        let error = ErrorDetail::new("invalid_message", "Messages must be JSON text");
+                        socket.send(ServerMessage::Error { id: None, error }.into_frame()).await?;
+                    }
+                    // Pings are answered by the protocol implementation
+                    Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                    Some(Ok(Message::Close(_))) | None => break None,
+                    Some(Err(e)) => return Err(e),
+                },
+                update = next_update(&mut subscription) => match update {
+                    Some(update) => socket.send(ServerMessage::from(update).into_frame()).await?,
+                    None => subscription = None,
+                },
+                _ = ticks.tick() => {
+                    if !self.session_active().await {
+                        break Some(CloseFrame { code: CloseCode::Policy, reason: "session ended".into() });
+                    }
+                    if heartbeat.is_some() {
+                        socket.send(Message::Ping(Vec::new())).await?;
+                    }
+                },
+                _ = &mut self.shutdown => {
+                    break Some(CloseFrame { code: CloseCode::Away, reason: "server shutting down".into() });
+                }
+            }
+        };
+        socket.close(close).await
+    }
+
+    // Runs one client message. Returns the reply, and whether the socket has
+    // to be closed because its session ended.
+    async fn respond(&self, text: &str, subscription: &mut Option<Subscription>) -> (ServerMessage, bool) {
+        let message: Value = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(e) => {
+                let error = ErrorDetail::new("invalid_message", format!("Messages must be JSON objects: {}", e));
+                return (ServerMessage::Error { id: None, error }, false);
+            }
+        };
+        let id = message.get("id").cloned();
+        // Every command counts against the user's rate limit, like a request
+        if let Some(Err(error)) = self.state.limiter.map(|limiter| limiter.charge(self.user.user_id)) {
+            return (ServerMessage::Error { id, error: error.into_reported_detail() }, false);
+        }
+        let command = match serde_json::from_value::<Command>(message) {
+            Ok(command) => command,
+            Err(e) => {
+                let error = ErrorDetail::new("invalid_message", e.to_string());
+                return (ServerMessage::Error { id, error }, false);
+            }
+        };
+
+        match self.execute(command, subscription).await {
+            Ok(result) => (ServerMessage::Result { id, result }, false),
+            Err(error) => {
+                let session_ended = matches!(error, ApiError::Unauthorized(_));
+                (ServerMessage::Error { id, error: error.into_reported_detail() }, session_ended)
+            }
+        }
+    }
+
+    async fn execute(&self, command: Command, subscription: &mut Option<Subscription>) -> Result<Value, ApiError> {
+        if !self.session_active().await {
+            return Err(ApiError::Unauthorized(Json(ErrorDetail::new(
+                "invalid_token",
+                "The session token is invalid or has expired",
+            ))));
+        }
+
+        let user = AuthenticatedUser { user_id: self.user.user_id, session_id: self.user.session_id };
+        let (events, metrics) = (self.state.bus.clone(), self.state.metrics.clone());
+        match command {
+            Command::Subscribe => {
+                // Subscribed before loading, so nothing changed meanwhile is missed
+                *subscription = Some(self.state.bus.subscribe(user.user_id, None));
+                let everything = TodoSearchQuery { description: None, completed: None };
+                to_value(self.run(move |conn| services::todos::list_or_search_todos(conn, user, everything)).await?)
+            }
+            Command::Unsubscribe => {
+                *subscription = None;
+                Ok(Value::Null)
+            }
+            Command::Create { description } => {
+                let create_req = Json(CreateTodoRequest { description });
+                to_value(self.run(move |conn| services::todos::add_todo_item(conn, &events, &metrics, user, create_req)).await?)
+            }
+            Command::Complete { item_id, version } => {
+                let undo_config = self.state.config.undo.clone();
+                let if_match = version.map(IfMatch::version).unwrap_or_default();
+                let completed = self
+                    .run(move |conn| {
+                        services::todos::complete_todo_item(conn, &events, &metrics, &undo_config, user, item_id, &if_match)
+                    })
+                    .await?;
+                to_value(completed)
+            }
+            Command::Delete { item_id, version } => {
+                let undo_config = self.state.config.undo.clone();
+                let if_match = version.map(IfMatch::version).unwrap_or_default();
+                let deleted = self
+                    .run(move |conn| services::todos::delete_todo_item(conn, &events, &undo_config, user, item_id, &if_match))
+                    .await?;
+                to_value(deleted)
+            }
+        }
+    }
+
+    // Whether the session the socket was opened with is still valid. When
+    // that cannot be checked, it is given the benefit of the doubt.
+    async fn session_active(&self) -> bool {
+        let session_id: Uuid = self.user.session_id;
+        let session = self.run(move |conn| services::auth::find_active_session(conn, session_id)).await;
+        !matches!(session, Ok(None))
+    }
+
+    // Like `DbConn::run`, with a connection checked out for just this call,
+    // as the socket may stay open far longer than a request
+    async fn run<F, T>(&self, f: F) -> Result<T, ServiceError>
+    where
+        F: FnOnce(&mut PgConnection) -> Result<T, ServiceError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.state.pool.clone();
+        let span = tracing::Span::current();
+        rocket::tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            let mut conn = pool.get()?;
+            f(&mut conn)
+        })
+        .await
+        .map_err(|e| ServiceError::InternalError(format!("blocking database task failed: {}", e)))?
+    }
+}
@@ -4,8 +4,9 @@ document.addEventListener('DOMContentLoaded', () => {
     const todoList = document.getElementById('todo-list');
 
     // API URLs
-    const apiUrl = '/api/todos'; // Existing
-    const authApiUrl = '/auth';
+    const apiBaseUrl = '/api/v1';
+    const apiUrl = `${apiBaseUrl}/todos`;
+    const authApiUrl = `${apiBaseUrl}/auth`;
 
     // New auth selectors
     const authSection = document.getElementById('auth-section');
@@ -21,6 +22,11 @@ document.addEventListener('DOMContentLoaded', () => {
     const loginButton = document.getElementById('login-btn');
     const loginMessage = document.getElementById('login-message');
 
+    const undoToast = document.getElementById('undo-toast');
+    const undoMessage = document.getElementById('undo-message');
+    const undoButton = document.getElementById('undo-btn');
+    let undoTimer = null;
+
     const userInfoDiv = document.getElementById('user-info');
     const loggedInUsernameSpan = document.getElementById('logged-in-username');
     const logoutButton = document.getElementById('logout-btn');
@@ -49,7 +55,7 @@ document.addEventListener('DOMContentLoaded', () => {
                 const errorText = await response.text(); // Get text first for better error diagnosis
                 try {
                     const errorData = JSON.parse(errorText);
-                    registerMessage.textContent = `Registration failed: ${response.status} ${errorData.message || errorText}`;
+                    registerMessage.textContent = `Registration failed: ${response.status} ${errorData.detail || errorText}`;
                 } catch (e) {
                      registerMessage.textContent = `Registration failed: ${response.status} ${errorText || 'Unknown error'}`;
                 }
@@ -84,11 +90,12 @@ document.addEventListener('DOMContentLoaded', () => {
                 loginPasswordInput.value = '';
                 showLoggedInState(data.username);
                 fetchTodos();
+                openEventStream();
             } else {
                 const errorText = await response.text();
                 try {
                     const errorData = JSON.parse(errorText);
-                    loginMessage.textContent = `Login failed: ${response.status} ${errorData.message || errorText}`;
+                    loginMessage.textContent = `Login failed: ${response.status} ${errorData.detail || errorText}`;
                 } catch (e) {
                      loginMessage.textContent = `Login failed: ${response.status} ${errorText || 'Unknown error'}`;
                 }
@@ -106,6 +113,8 @@ document.addEventListener('DOMContentLoaded', () => {
     }
 
     function handleLogout() {
+        hideUndoToast();
+        closeEventStream();
         localStorage.removeItem('session_token');
         localStorage.removeItem('username');
         showLoggedOutState();
@@ -176,7 +185,7 @@ document.addEventListener('DOMContentLoaded', () => {
             completeButton.classList.add('complete-btn');
             completeButton.onclick = async () => {
                 if (!todo.completed) {
-                    await completeTodoItem(todo.id);
+                    await completeTodoItem(todo.id, todo.version);
                 }
             };
             if (todo.completed) {
@@ -184,7 +193,15 @@ document.addEventListener('DOMContentLoaded', () => {
                 completeButton.textContent = 'Completed';
             }
 
+            const deleteButton = document.createElement('button');
+            deleteButton.textContent = 'Delete';
+            deleteButton.classList.add('delete-btn');
+            deleteButton.onclick = async () => {
+                await deleteTodoItem(todo.id, todo.version);
+            };
+
             listItem.appendChild(completeButton);
+            listItem.appendChild(deleteButton);
             todoList.appendChild(listItem);
         });
     }
@@ -230,7 +247,14 @@ document.addEventListener('DOMContentLoaded', () => {
         }
     }
 
-    async function completeTodoItem(id) {
+    // Writes carry the version the list showed, so a change made in another
+    // tab in the meantime is reported instead of silently overwritten
+    function handleStaleItem() {
+        alert('This todo was changed elsewhere. The list has been refreshed.');
+        fetchTodos();
+    }
+
+    async function completeTodoItem(id, version) {
         const token = localStorage.getItem('session_token');
         if (!token) {
             alert('Please login to complete todos.');
@@ -238,7 +262,7 @@ document.addEventListener('DOMContentLoaded', () => {
             return;
         }
         try {
-            const headers = {}; // PUT request, no JSON body in this case
+            const headers = { 'If-Match': `"${version}"` }; // PUT request, no JSON body in this case
             if (token) {
                 headers['Authorization'] = `Bearer ${token}`;
             }
@@ -252,8 +276,14 @@ document.addEventListener('DOMContentLoaded', () => {
                     alert("Session expired. Please login again.");
                     return;
                 }
+                if (response.status === 412) { // Precondition Failed
+                    handleStaleItem();
+                    return;
+                }
                 throw new Error(`HTTP error! status: ${response.status}`);
             }
+            const data = await response.json();
+            showUndoToast('Todo completed.', data.undo_token, data.undo_expires_at);
             fetchTodos();
         } catch (error) {
             console.error(`Failed to complete todo ${id}:`, error);
@@ -261,6 +291,112 @@ document.addEventListener('DOMContentLoaded', () => {
         }
     }
 
+    async function deleteTodoItem(id, version) {
+        const token = localStorage.getItem('session_token');
+        if (!token) {
+            alert('Please login to delete todos.');
+            showLoggedOutState();
+            return;
+        }
+        try {
+            const response = await fetch(`${apiUrl}/${id}`, {
+                method: 'DELETE',
+                headers: { 'Authorization': `Bearer ${token}`, 'If-Match': `"${version}"` }
+            });
+            if (!response.ok) {
+                if (response.status === 401) { // Unauthorized
+                    handleLogout();
+                    alert("Session expired. Please login again.");
+                    return;
+                }
+                if (response.status === 412) { // Precondition Failed
+                    handleStaleItem();
+                    return;
+                }
+                throw new Error(`HTTP error! status: ${response.status}`);
+            }
+            const data = await response.json();
+            showUndoToast('Todo moved to trash.', data.undo_token, data.undo_expires_at);
+            fetchTodos();
+        } catch (error) {
+            console.error(`Failed to delete todo ${id}:`, error);
+            alert(`Failed to delete todo ${id}.`);
+        }
+    }
+
+    // --- Live Updates ---
+    // Changes made in other tabs or by other clients arrive as server-sent
+    // events; the session cookie set at login authenticates the stream, and
+    // EventSource resumes with Last-Event-ID after a dropped connection.
+    const liveEvents = ['created', 'updated', 'completed', 'deleted', 'restored', 'resync'];
+    let eventSource = null;
+    let refreshTimer = null;
+
+    function scheduleRefresh() {
+        // Bursts of events (e.g. a replay after reconnecting) reload the list once
+        clearTimeout(refreshTimer);
+        refreshTimer = setTimeout(fetchTodos, 100);
+    }
+
+    function openEventStream() {
+        if (eventSource || !window.EventSource) {
+            return;
+        }
+        eventSource = new EventSource(`${apiBaseUrl}/events`);
+        liveEvents.forEach(name => eventSource.addEventListener(name, scheduleRefresh));
+    }
+
+    function closeEventStream() {
+        clearTimeout(refreshTimer);
+        if (eventSource) {
+            eventSource.close();
+            eventSource = null;
+        }
+    }
+
+    // --- Undo Toast ---
+    function hideUndoToast() {
+        clearTimeout(undoTimer);
+        undoTimer = null;
+        undoToast.style.display = 'none';
+        undoButton.onclick = null;
+    }
+
+    function showUndoToast(message, undoToken, expiresAt) {
+        hideUndoToast();
+        if (!undoToken) {
+            return;
+        }
+        // expires_at is a UTC timestamp without a zone suffix
+        const remainingMs = new Date(`${expiresAt}Z`).getTime() - Date.now();
+        undoMessage.textContent = message;
+        undoButton.onclick = () => undoOperation(undoToken);
+        undoToast.style.display = 'flex';
+        undoTimer = setTimeout(hideUndoToast, Number.isFinite(remainingMs) ? Math.max(remainingMs, 0) : 10000);
+    }
+
+    async function undoOperation(undoToken) {
+        hideUndoToast();
+        const token = localStorage.getItem('session_token');
+        if (!token) {
+            showLoggedOutState();
+            return;
+        }
+        try {
+            const response = await fetch(`${apiBaseUrl}/undo/${undoToken}`, {
+                method: 'POST',
+                headers: { 'Authorization': `Bearer ${token}` }
+            });
+            if (!response.ok) {
+                throw new Error(`HTTP error! status: ${response.status}`);
+            }
+            fetchTodos();
+        } catch (error) {
+            console.error('Failed to undo:', error);
+            alert('Could not undo, the operation may have expired.');
+        }
+    }
+
     // Event Listeners
     registerButton.addEventListener('click', handleRegister);
     loginButton.addEventListener('click', handleLogin);
@@ -279,6 +415,7 @@ document.addEventListener('DOMContentLoaded', () => {
     if (currentToken && currentUsername) {
         showLoggedInState(currentUsername);
         fetchTodos();
+        openEventStream();
     } else {
         showLoggedOutState();
     }
@@ -3,7 +3,9 @@ mod tests {
     use rocket::http::{ContentType, Status};
     use rocket::local::blocking::Client;
     use serde_json::json;
-    use todo_backend::models::{TodoItem, UserInfo};
+    use sha2::{Digest, Sha256};
+    use todo_backend::models::{SyncChanges, TodoAttachment, TodoComment, TodoItem, TodoItemEvent, TodoListItem, Undoable, UserInfo};
+    use todo_backend::db::PgPool;
     use todo_backend::LoginResponse;
     use uuid::Uuid; // For Uuid parsing
 
@@ -13,6 +15,30 @@ mod tests {
         Client::tracked(rocket_instance).expect("valid rocket instance")
     }
 
+    // Helper function to register and log in a fresh user, returning (user_id, session_token)
+    fn register_and_login(client: &Client, prefix: &str) -> (Uuid, String) {
+        let username = format!("{}_{}", prefix, Uuid::new_v4());
+        let password = "password123";
+        let reg_response = client.post("/auth/register")
+            .header(ContentType::JSON)
+            .body(json!({ "username": username, "password": password }).to_string())
+            .dispatch();
+        assert_eq!(reg_response.status(), Status::Ok, "Registration failed");
+        let user_info = reg_response.into_json::<UserInfo>().unwrap();
+
+        let login_response = client.post("/auth/login")
+            .header(ContentType::JSON)
+            .body(json!({ "username": username, "password": password }).to_string())
+            .dispatch();
+        assert_eq!(login_response.status(), Status::Ok, "Login failed");
+        let login_info = login_response.into_json::<LoginResponse>().unwrap();
+        (user_info.id, login_info.session_token)
+    }
+
+    fn bearer(token: &str) -> rocket::http::Header<'static> {
+        rocket::http::Header::new("Authorization", format!("Bearer {}", token))
+    }
+
     // --- New Authentication Tests ---
     #[test]
     fn test_register_user_success() {
@@ -45,6 +71,7 @@ mod tests {
             .body(json!({ "username": username, "password": password }).to_string())
             .dispatch();
         assert_eq!(response.status(), Status::Conflict);
+        assert_eq!(response.into_json::<serde_json::Value>().unwrap()["error"], "username_taken");
     }
 
     #[test]
@@ -500,4 +527,1924 @@ mod tests {
         let body = subsequent_access_response.into_string().unwrap();
         assert!(body.contains("invalid_token"), "Error message for invalid token not found after logout. Body: {}", body);
     }
+
+    // --- Comment Tests ---
+
+    #[test]
+    fn test_todo_comments() {
+        let client = test_client();
+        let (user_id, token) = register_and_login(&client, "testuser_comments");
+
+        let add_response = client.post("/api/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "Discuss this item" }).to_string())
+            .dispatch();
+        assert_eq!(add_response.status(), Status::Ok);
+        let item_id = add_response.into_json::<TodoItem>().unwrap().id;
+
+        // Post two comments
+        let c1 = client.post(format!("/api/todos/{}/comments", item_id))
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "body": "First thought" }).to_string())
+            .dispatch();
+        assert_eq!(c1.status(), Status::Ok);
+        let c1 = c1.into_json::<TodoComment>().unwrap();
+        assert_eq!(c1.author_id, user_id);
+        assert!(!c1.edited);
+        let c2 = client.post(format!("/api/todos/{}/comments", item_id))
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "body": "Second thought" }).to_string())
+            .dispatch()
+            .into_json::<TodoComment>()
+            .unwrap();
+
+        // Empty bodies are rejected
+        let empty = client.post(format!("/api/todos/{}/comments", item_id))
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "body": "   " }).to_string())
+            .dispatch();
+        assert_eq!(empty.status(), Status::BadRequest);
+
+        // Edit marks the comment as edited
+        let edit = client.put(format!("/api/todos/{}/comments/{}", item_id, c1.id))
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "body": "First thought, revised" }).to_string())
+            .dispatch();
+        assert_eq!(edit.status(), Status::Ok);
+        let edited = edit.into_json::<TodoComment>().unwrap();
+        assert!(edited.edited);
+        assert_eq!(edited.body, "First thought, revised");
+
+        // Soft delete hides the comment from the thread
+        let delete = client.delete(format!("/api/todos/{}/comments/{}", item_id, c2.id))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(delete.status(), Status::NoContent);
+        let delete_again = client.delete(format!("/api/todos/{}/comments/{}", item_id, c2.id))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(delete_again.status(), Status::NotFound);
+
+        let thread = client.get(format!("/api/todos/{}/comments", item_id))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(thread.status(), Status::Ok);
+        let thread = thread.into_json::<Vec<TodoComment>>().unwrap();
+        assert_eq!(thread.len(), 1);
+        assert_eq!(thread[0].id, c1.id);
+
+        // Listings carry the live comment count
+        let listing = client.get("/api/todos")
+            .header(bearer(&token))
+            .dispatch()
+            .into_json::<Vec<TodoListItem>>()
+            .unwrap();
+        assert_eq!(listing.len(), 1);
+        assert_eq!(listing[0].item.id, item_id);
+        assert_eq!(listing[0].comment_count, 1);
+
+        // Another user cannot see or add to the thread
+        let (_, other_token) = register_and_login(&client, "testuser_comments_other");
+        let foreign_get = client.get(format!("/api/todos/{}/comments", item_id))
+            .header(bearer(&other_token))
+            .dispatch();
+        assert_eq!(foreign_get.status(), Status::NotFound);
+        let foreign_post = client.post(format!("/api/todos/{}/comments", item_id))
+            .header(ContentType::JSON)
+            .header(bearer(&other_token))
+            .body(json!({ "body": "Intruding" }).to_string())
+            .dispatch();
+        assert_eq!(foreign_post.status(), Status::NotFound);
+    }
+
+    // --- Attachment Tests ---
+
+    fn multipart_body(boundary: &str, file_name: &str, content_type: &str, contents: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+            boundary, file_name, content_type
+        ).as_bytes());
+        body.extend_from_slice(contents);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        body
+    }
+
+    #[test]
+    fn test_todo_attachments() {
+        let client = test_client();
+        let (user_id, token) = register_and_login(&client, "testuser_attachments");
+
+        let item_id = client.post("/api/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "Item with a screenshot" }).to_string())
+            .dispatch()
+            .into_json::<TodoItem>()
+            .unwrap()
+            .id;
+
+        // Upload a small text file
+        let contents = b"steps to reproduce";
+        let boundary = "X-TODO-BOUNDARY";
+        let upload = client.post(format!("/api/todos/{}/attachments", item_id))
+            .header(ContentType::new("multipart", "form-data").with_params(("boundary", boundary)))
+            .header(bearer(&token))
+            .body(multipart_body(boundary, "../notes.txt", "text/plain", contents))
+            .dispatch();
+        assert_eq!(upload.status(), Status::Ok);
+        let attachment = upload.into_json::<TodoAttachment>().unwrap();
+        assert_eq!(attachment.uploader_id, user_id);
+        assert_eq!(attachment.file_name, "notes.txt");
+        assert_eq!(attachment.content_type, "text/plain");
+        assert_eq!(attachment.size_bytes, contents.len() as i64);
+        assert_eq!(attachment.sha256, hex::encode(Sha256::digest(contents)));
+
+        let stored_path = std::path::Path::new("attachments")
+            .join(item_id.to_string())
+            .join(attachment.id.to_string());
+        assert!(stored_path.exists(), "Attachment contents were not stored");
+
+        // Listed on the item
+        let listed = client.get(format!("/api/todos/{}/attachments", item_id))
+            .header(bearer(&token))
+            .dispatch()
+            .into_json::<Vec<TodoAttachment>>()
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, attachment.id);
+
+        // Downloaded with the recorded content type
+        let download = client.get(format!("/api/todos/{}/attachments/{}", item_id, attachment.id))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(download.status(), Status::Ok);
+        assert_eq!(download.content_type(), Some(ContentType::Plain));
+        assert_eq!(download.into_bytes().unwrap(), contents.to_vec());
+
+        // Names outside ASCII are sent percent-encoded, with an ASCII fallback
+        let accented = client.post(format!("/api/todos/{}/attachments", item_id))
+            .header(ContentType::new("multipart", "form-data").with_params(("boundary", boundary)))
+            .header(bearer(&token))
+            .body(multipart_body(boundary, "résumé v2.txt", "text/plain", contents))
+            .dispatch()
+            .into_json::<TodoAttachment>()
+            .unwrap();
+        let download = client.get(format!("/api/todos/{}/attachments/{}", item_id, accented.id))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(
+            download.headers().get_one("Content-Disposition"),
+            Some("attachment; filename=\"r_sum_ v2.txt\"; filename*=UTF-8''r%C3%A9sum%C3%A9%20v2.txt")
+        );
+
+        // Other users cannot download it
+        let (_, other_token) = register_and_login(&client, "testuser_attachments_other");
+        let foreign = client.get(format!("/api/todos/{}/attachments/{}", item_id, attachment.id))
+            .header(bearer(&other_token))
+            .dispatch();
+        assert_eq!(foreign.status(), Status::NotFound);
+
+        // Trashing the item keeps the contents; purging it removes them
+        let delete = client.delete(format!("/api/todos/{}", item_id))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(delete.status(), Status::Ok);
+        assert!(stored_path.exists(), "Attachment contents removed while item is only trashed");
+        let purge = client.delete(format!("/api/trash/{}", item_id))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(purge.status(), Status::NoContent);
+        assert!(!stored_path.exists(), "Attachment contents survived item purge");
+    }
+
+    #[test]
+    fn test_attachment_size_limit() {
+        let rocket = todo_backend::rocket_instance();
+        let figment = todo_backend::config::figment().merge(("attachments.max_size", "1KiB"));
+        let client = Client::tracked(rocket.configure(figment)).expect("valid rocket instance");
+        let (_, token) = register_and_login(&client, "testuser_attachment_limit");
+
+        let item_id = client.post("/api/v1/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "Item with a large file" }).to_string())
+            .dispatch()
+            .into_json::<TodoItem>()
+            .unwrap()
+            .id;
+
+        let boundary = "X-TODO-BOUNDARY";
+        let upload = |contents: &[u8]| {
+            client.post(format!("/api/v1/todos/{}/attachments", item_id))
+                .header(ContentType::new("multipart", "form-data").with_params(("boundary", boundary)))
+                .header(bearer(&token))
+                .body(multipart_body(boundary, "large.bin", "application/octet-stream", contents))
+                .dispatch()
+        };
+
+        let too_large = upload(&[0u8; 1025]);
+        assert_eq!(too_large.status(), Status::PayloadTooLarge);
+        assert_eq!(too_large.into_json::<serde_json::Value>().unwrap()["error"], "attachment_too_large");
+
+        assert_eq!(upload(&[0u8; 1024]).status(), Status::Ok);
+        let listed = client.get(format!("/api/v1/todos/{}/attachments", item_id))
+            .header(bearer(&token))
+            .dispatch()
+            .into_json::<Vec<TodoAttachment>>()
+            .unwrap();
+        assert_eq!(listed.len(), 1, "Oversized upload was stored");
+    }
+
+    // --- History Tests ---
+
+    #[test]
+    fn test_item_history() {
+        let client = test_client();
+        let (user_id, token) = register_and_login(&client, "testuser_history");
+
+        let item_id = client.post("/api/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "Original description" }).to_string())
+            .dispatch()
+            .into_json::<TodoItem>()
+            .unwrap()
+            .id;
+
+        let update = client.put(format!("/api/todos/{}", item_id))
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "Revised description" }).to_string())
+            .dispatch();
+        assert_eq!(update.status(), Status::Ok);
+        assert_eq!(update.into_json::<TodoItem>().unwrap().description, "Revised description");
+
+        client.put(format!("/api/todos/{}/complete", item_id))
+            .header(bearer(&token))
+            .dispatch();
+        let delete = client.delete(format!("/api/todos/{}", item_id))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(delete.status(), Status::Ok);
+
+        // History outlives the item
+        let history = client.get(format!("/api/todos/{}/history", item_id))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(history.status(), Status::Ok);
+        let events = history.into_json::<Vec<TodoItemEvent>>().unwrap();
+        let actions: Vec<&str> = events.iter().map(|e| e.action.as_str()).collect();
+        assert_eq!(actions, vec!["created", "updated", "completed", "deleted"]);
+        assert!(events.iter().all(|e| e.actor_id == user_id && e.owner_id == user_id));
+
+        // Creations and deletions carry full snapshots, changes only the diff
+        assert!(events[0].before.is_none());
+        assert_eq!(events[0].after.as_ref().unwrap()["description"], "Original description");
+        assert_eq!(events[1].before, Some(json!({ "description": "Original description" })));
+        assert_eq!(events[1].after, Some(json!({ "description": "Revised description" })));
+        assert_eq!(events[2].before, Some(json!({ "completed": false })));
+        assert_eq!(events[2].after, Some(json!({ "completed": true })));
+        assert_eq!(events[3].before.as_ref().unwrap()["deleted_at"], json!(null));
+        assert!(events[3].after.as_ref().unwrap()["deleted_at"].is_string());
+
+        // Nobody else can read it
+        let (_, other_token) = register_and_login(&client, "testuser_history_other");
+        let foreign = client.get(format!("/api/todos/{}/history", item_id))
+            .header(bearer(&other_token))
+            .dispatch();
+        assert_eq!(foreign.status(), Status::NotFound);
+    }
+
+    // --- Trash Tests ---
+
+    #[test]
+    fn test_trash_restore_and_purge() {
+        let client = test_client();
+        let (_, token) = register_and_login(&client, "testuser_trash");
+
+        let mut ids = Vec::new();
+        for description in ["Keep me", "Trash and restore me", "Trash and purge me"] {
+            let item = client.post("/api/todos")
+                .header(ContentType::JSON)
+                .header(bearer(&token))
+                .body(json!({ "description": description }).to_string())
+                .dispatch()
+                .into_json::<TodoItem>()
+                .unwrap();
+            ids.push(item.id);
+        }
+
+        for id in &ids[1..] {
+            let delete = client.delete(format!("/api/todos/{}", id))
+                .header(bearer(&token))
+                .dispatch();
+            assert_eq!(delete.status(), Status::Ok);
+        }
+
+        // Trashed items are hidden from listings, counts and direct access
+        let listing = client.get("/api/todos")
+            .header(bearer(&token))
+            .dispatch()
+            .into_json::<Vec<TodoItem>>()
+            .unwrap();
+        assert_eq!(listing.len(), 1);
+        assert_eq!(listing[0].id, ids[0]);
+        let count = client.get("/api/todos/count")
+            .header(bearer(&token))
+            .dispatch()
+            .into_json::<usize>()
+            .unwrap();
+        assert_eq!(count, 1);
+        let trashed_get = client.get(format!("/api/todos/{}", ids[1]))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(trashed_get.status(), Status::NotFound);
+
+        // ...but show up in the trash
+        let trash = client.get("/api/trash")
+            .header(bearer(&token))
+            .dispatch()
+            .into_json::<Vec<TodoItem>>()
+            .unwrap();
+        assert_eq!(trash.len(), 2);
+        assert!(trash.iter().all(|item| item.deleted_at.is_some()));
+
+        // Restore brings an item back
+        let restore = client.post(format!("/api/todos/{}/restore", ids[1]))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(restore.status(), Status::Ok);
+        assert!(restore.into_json::<TodoItem>().unwrap().deleted_at.is_none());
+        let restore_again = client.post(format!("/api/todos/{}/restore", ids[1]))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(restore_again.status(), Status::NotFound);
+
+        // Live items cannot be purged directly, trashed ones can
+        let purge_live = client.delete(format!("/api/trash/{}", ids[0]))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(purge_live.status(), Status::NotFound);
+        let purge = client.delete(format!("/api/trash/{}", ids[2]))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(purge.status(), Status::NoContent);
+        let restore_purged = client.post(format!("/api/todos/{}/restore", ids[2]))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(restore_purged.status(), Status::NotFound);
+
+        // Emptying the trash purges everything left in it
+        client.delete(format!("/api/todos/{}", ids[0])).header(bearer(&token)).dispatch();
+        let empty = client.delete("/api/trash")
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(empty.status(), Status::NoContent);
+        let trash = client.get("/api/trash")
+            .header(bearer(&token))
+            .dispatch()
+            .into_json::<Vec<TodoItem>>()
+            .unwrap();
+        assert!(trash.is_empty());
+        let listing = client.get("/api/todos")
+            .header(bearer(&token))
+            .dispatch()
+            .into_json::<Vec<TodoItem>>()
+            .unwrap();
+        assert_eq!(listing.len(), 1);
+        assert_eq!(listing[0].id, ids[1]);
+    }
+
+    // --- Undo Tests ---
+
+    #[test]
+    fn test_undo_complete_and_delete() {
+        let client = test_client();
+        let (_, token) = register_and_login(&client, "testuser_undo");
+
+        let item_id = client.post("/api/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "Oops" }).to_string())
+            .dispatch()
+            .into_json::<TodoItem>()
+            .unwrap()
+            .id;
+
+        // Undo a completion
+        let complete = client.put(format!("/api/todos/{}/complete", item_id))
+            .header(bearer(&token))
+            .dispatch()
+            .into_json::<Undoable<TodoItem>>()
+            .unwrap();
+        assert!(complete.item.completed);
+        let undo = client.post(format!("/api/undo/{}", complete.undo_token))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(undo.status(), Status::Ok);
+        assert!(!undo.into_json::<TodoItem>().unwrap().completed);
+        let history = client.get(format!("/api/todos/{}/history", item_id))
+            .header(bearer(&token))
+            .dispatch()
+            .into_json::<Vec<TodoItemEvent>>()
+            .unwrap();
+        let actions: Vec<&str> = history.iter().map(|e| e.action.as_str()).collect();
+        assert_eq!(actions, vec!["created", "completed", "uncompleted"]);
+
+        // Tokens are single use
+        let undo_again = client.post(format!("/api/undo/{}", complete.undo_token))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(undo_again.status(), Status::NotFound);
+
+        // Undo a delete
+        let delete = client.delete(format!("/api/todos/{}", item_id))
+            .header(bearer(&token))
+            .dispatch()
+            .into_json::<Undoable<TodoItem>>()
+            .unwrap();
+        assert!(delete.item.deleted_at.is_some());
+
+        // Only the user who performed the operation can undo it
+        let (_, other_token) = register_and_login(&client, "testuser_undo_other");
+        let foreign = client.post(format!("/api/undo/{}", delete.undo_token))
+            .header(bearer(&other_token))
+            .dispatch();
+        assert_eq!(foreign.status(), Status::NotFound);
+
+        let undo = client.post(format!("/api/undo/{}", delete.undo_token))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(undo.status(), Status::Ok);
+        let restored = client.get(format!("/api/todos/{}", item_id))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(restored.status(), Status::Ok);
+
+        // A delete whose item was purged in the meantime can no longer be undone
+        let delete = client.delete(format!("/api/todos/{}", item_id))
+            .header(bearer(&token))
+            .dispatch()
+            .into_json::<Undoable<TodoItem>>()
+            .unwrap();
+        client.delete(format!("/api/trash/{}", item_id)).header(bearer(&token)).dispatch();
+        let undo = client.post(format!("/api/undo/{}", delete.undo_token))
+            .header(bearer(&token))
+            .dispatch();
+        assert_eq!(undo.status(), Status::Conflict);
+    }
+
+    // --- Sync Tests ---
+
+    #[test]
+    fn test_sync_changes_and_mutations() {
+        let client = test_client();
+        let (_, token) = register_and_login(&client, "testuser_sync");
+        let (_, other_token) = register_and_login(&client, "testuser_sync_other");
+        let sync = |since: i64, limit: i64| {
+            let response = client.get(format!("/api/v1/sync?since={}&limit={}", since, limit)).header(bearer(&token)).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            response.into_json::<SyncChanges>().unwrap()
+        };
+        let push = |mutations: serde_json::Value| {
+            let response = client.post("/api/v1/sync")
+                .header(ContentType::JSON)
+                .header(bearer(&token))
+                .body(json!({ "mutations": mutations }).to_string())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            response.into_json::<serde_json::Value>().unwrap()["results"].as_array().unwrap().clone()
+        };
+
+        let initial = client.get("/api/v1/sync").header(bearer(&token)).dispatch().into_json::<SyncChanges>().unwrap();
+        assert!(initial.items.is_empty() && initial.deleted.is_empty() && !initial.has_more);
+        assert_eq!(initial.token, 0);
+
+        let item = client.post("/api/v1/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "Made online" }).to_string())
+            .dispatch()
+            .into_json::<TodoItem>().unwrap();
+        let changes = sync(initial.token, 500);
+        assert_eq!(changes.items, vec![item.clone()]);
+        assert_eq!(changes.token, item.change_seq);
+
+        // Offline changes, each with its own outcome
+        let offline_id = Uuid::new_v4();
+        let results = push(json!([
+            { "op": "create", "id": offline_id, "description": "Made offline" },
+            { "op": "update", "id": item.id, "base_version": item.version, "completed": true },
+            { "op": "update", "id": item.id, "base_version": item.version, "description": "Stale edit" },
+            { "op": "delete", "id": Uuid::new_v4(), "base_version": 1 },
+            { "op": "create", "id": Uuid::new_v4(), "description": " " },
+            { "op": "create", "id": offline_id, "description": "Made offline" },
+        ]));
+        let statuses: Vec<&str> = results.iter().map(|result| result["status"].as_str().unwrap()).collect();
+        assert_eq!(statuses, ["applied", "applied", "conflict", "rejected", "rejected", "conflict"]);
+        assert_eq!(results[0]["item"]["id"], offline_id.to_string());
+        let completed = serde_json::from_value::<TodoItem>(results[1]["item"].clone()).unwrap();
+        assert!(completed.completed && completed.change_seq > item.change_seq);
+        assert_eq!(serde_json::from_value::<TodoItem>(results[2]["item"].clone()).unwrap(), completed);
+        assert_eq!(results[3]["error"]["error"], "todo_not_found");
+        assert_eq!(results[4]["error"]["error"], "validation_failed");
+        assert_eq!(results[5]["item"]["description"], "Made offline");
+
+        // Both changes show up once, in the order they were made
+        let changes = sync(changes.token, 500);
+        let ids: Vec<Uuid> = changes.items.iter().map(|item| item.id).collect();
+        assert_eq!(ids, vec![offline_id, item.id]);
+        assert!(changes.deleted.is_empty());
+        assert_eq!(sync(changes.token, 500).items.len(), 0);
+
+        // Trashed and purged items come back as tombstones
+        client.delete(format!("/api/v1/todos/{}", item.id)).header(bearer(&token)).dispatch();
+        let trashed = sync(changes.token, 500);
+        assert_eq!(trashed.deleted.iter().map(|tombstone| tombstone.id).collect::<Vec<_>>(), vec![item.id]);
+        client.delete(format!("/api/v1/trash/{}", item.id)).header(bearer(&token)).dispatch();
+        let purged = sync(trashed.token, 500);
+        assert_eq!(purged.deleted.len(), 1);
+        assert!(purged.deleted[0].change_seq > trashed.token);
+        let results = push(json!([
+            { "op": "update", "id": item.id, "base_version": completed.version, "description": "Too late" },
+            { "op": "delete", "id": item.id, "base_version": completed.version },
+        ]));
+        assert_eq!((results[0]["status"].as_str(), results[0].get("item")), (Some("conflict"), None));
+        assert_eq!(results[1]["status"], "applied");
+
+        // Large backlogs are paged
+        let first = sync(0, 1);
+        assert!(first.has_more);
+        assert_eq!(first.items.len() + first.deleted.len(), 1);
+        let rest = sync(first.token, 500);
+        assert!(!rest.has_more);
+        assert_eq!(rest.token, purged.token);
+
+        // Items are private to their owner
+        let response = client.get("/api/v1/sync").header(bearer(&other_token)).dispatch();
+        assert!(response.into_json::<SyncChanges>().unwrap().items.is_empty());
+        let response = client.post("/api/v1/sync")
+            .header(ContentType::JSON)
+            .header(bearer(&other_token))
+            .body(json!({ "mutations": [{ "op": "create", "id": offline_id, "description": "Mine now" }] }).to_string())
+            .dispatch();
+        assert_eq!(response.into_json::<serde_json::Value>().unwrap()["results"][0]["error"]["error"], "id_in_use");
+
+        // A server fault (here, text Postgres cannot store) ends the batch, but
+        // what was applied before it is still reported
+        let (applied_id, failing_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let results = push(json!([
+            { "op": "create", "id": applied_id, "description": "Before the fault" },
+            { "op": "create", "id": failing_id, "description": "Nul \u{0000} byte" },
+            { "op": "create", "id": Uuid::new_v4(), "description": "Never attempted" },
+        ]));
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0]["status"], "applied");
+        assert_eq!(results[1]["status"], "rejected");
+        assert_eq!(results[1]["id"], failing_id.to_string());
+        assert_eq!(results[1]["error"]["error"], "internal_error");
+
+        let response = client.get("/api/v1/sync?limit=0").header(bearer(&token)).dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    // --- Conditional Request Tests ---
+
+    #[test]
+    fn test_etags_and_preconditions() {
+        use rocket::http::Header;
+        let client = test_client();
+        let (_, token) = register_and_login(&client, "testuser_etag");
+        let item = client.post("/api/v1/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "Edited in two tabs" }).to_string())
+            .dispatch()
+            .into_json::<TodoItem>().unwrap();
+        assert_eq!(item.version, 1);
+        let item_url = format!("/api/v1/todos/{}", item.id);
+
+        let response = client.get(&item_url).header(bearer(&token)).dispatch();
+        assert_eq!(response.headers().get_one("ETag"), Some("\"1\""));
+        for tag in ["\"1\"", "W/\"1\"", "\"7\", \"1\"", "*"] {
+            let response = client.get(&item_url).header(bearer(&token)).header(Header::new("If-None-Match", tag)).dispatch();
+            assert_eq!(response.status(), Status::NotModified, "If-None-Match: {}", tag);
+            assert_eq!(response.headers().get_one("ETag"), Some("\"1\""));
+            assert!(response.into_string().is_none());
+        }
+        let response = client.get(&item_url).header(bearer(&token)).header(Header::new("If-None-Match", "\"7\"")).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // The first tab saves; the second, still holding version 1, is refused
+        let edit = |description: &str, tag: &str| {
+            client.put(&item_url)
+                .header(ContentType::JSON)
+                .header(bearer(&token))
+                .header(Header::new("If-Match", tag.to_string()))
+                .body(json!({ "description": description }).to_string())
+                .dispatch()
+        };
+        let response = edit("First tab", "\"1\"");
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("ETag"), Some("\"2\""));
+        let response = edit("Second tab", "\"1\"");
+        assert_eq!(response.status(), Status::PreconditionFailed);
+        assert_eq!(response.into_json::<serde_json::Value>().unwrap()["error"], "precondition_failed");
+        assert_eq!(edit("Second tab", "W/\"2\"").status(), Status::PreconditionFailed);
+        let current = client.get(&item_url).header(bearer(&token)).dispatch().into_json::<TodoItem>().unwrap();
+        assert_eq!((current.description.as_str(), current.version), ("First tab", 2));
+
+        let complete = |tag: &str| {
+            client.put(format!("{}/complete", item_url)).header(bearer(&token)).header(Header::new("If-Match", tag.to_string())).dispatch()
+        };
+        assert_eq!(complete("\"1\"").status(), Status::PreconditionFailed);
+        let response = complete("*");
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("ETag"), Some("\"3\""));
+
+        let response = client.delete(&item_url).header(bearer(&token)).header(Header::new("If-Match", "\"2\"")).dispatch();
+        assert_eq!(response.status(), Status::PreconditionFailed);
+
+        // Lists are tagged with a digest of their contents
+        let response = client.get("/api/v1/todos").header(bearer(&token)).dispatch();
+        let list_tag = response.headers().get_one("ETag").unwrap().to_string();
+        let response = client.get("/api/v1/todos").header(bearer(&token)).header(Header::new("If-None-Match", list_tag.clone())).dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+        let response = client.delete(&item_url).header(bearer(&token)).header(Header::new("If-Match", "\"3\"")).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get("/api/v1/todos").header(bearer(&token)).header(Header::new("If-None-Match", list_tag.clone())).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_ne!(response.headers().get_one("ETag"), Some(list_tag.as_str()));
+        assert!(response.into_json::<Vec<TodoListItem>>().unwrap().is_empty());
+    }
+
+    // Two writes racing with the same If-Match: the item is checked and
+    // written under a row lock, so only one of them goes through
+    #[rocket::async_test]
+    async fn test_if_match_race() {
+        use diesel::prelude::*;
+        use diesel::sql_types::{BigInt, Integer};
+        use rocket::http::Header;
+        use rocket::local::asynchronous::Client as AsyncClient;
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let client = AsyncClient::tracked(todo_backend::rocket_instance()).await.expect("valid rocket instance");
+        let username = format!("testuser_if_match_race_{}", Uuid::new_v4());
+        let credentials = json!({ "username": username, "password": "password123" }).to_string();
+        client.post("/api/v1/auth/register").header(ContentType::JSON).body(&credentials).dispatch().await;
+        let token = client.post("/api/v1/auth/login").header(ContentType::JSON).body(&credentials).dispatch().await
+            .into_json::<LoginResponse>().await.unwrap()
+            .session_token;
+        let item = client.post("/api/v1/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "Edited in two tabs at once" }).to_string())
+            .dispatch().await
+            .into_json::<TodoItem>().await.unwrap();
+
+        // Hold the row from another connection until both writes are waiting for it
+        let pool = client.rocket().state::<PgPool>().unwrap().clone();
+        let locker_pool = pool.clone();
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let locker = std::thread::spawn(move || {
+            let mut conn = locker_pool.get().unwrap();
+            conn.transaction::<_, diesel::result::Error, _>(|conn| {
+                diesel::sql_query("SELECT id FROM todo_items WHERE id = $1 FOR UPDATE")
+                    .bind::<diesel::sql_types::Uuid, _>(item.id)
+                    .execute(conn)?;
+                let pid = diesel::select(diesel::dsl::sql::<Integer>("pg_backend_pid()")).get_result::<i32>(conn)?;
+                locked_tx.send(pid).unwrap();
+                // Bounded, so a regression fails the assertions instead of hanging
+                let _ = release_rx.recv_timeout(Duration::from_secs(5));
+                Ok(())
+            }).unwrap();
+        });
+        let locker_pid = locked_rx.recv().unwrap();
+
+        let edit = |description: &'static str| {
+            client.put(format!("/api/v1/todos/{}", item.id))
+                .header(ContentType::JSON)
+                .header(bearer(&token))
+                .header(Header::new("If-Match", "\"1\""))
+                .body(json!({ "description": description }).to_string())
+                .dispatch()
+        };
+        let release = async {
+            for _ in 0..100 {
+                let pool = pool.clone();
+                let waiting = rocket::tokio::task::spawn_blocking(move || {
+                    let mut conn = pool.get().unwrap();
+                    diesel::select(
+                        diesel::dsl::sql::<BigInt>("(SELECT count(*) FROM pg_stat_activity WHERE ")
+                            .bind::<Integer, _>(locker_pid)
+                            .sql(" = ANY(pg_blocking_pids(pid)))"),
+                    )
+                    .get_result::<i64>(&mut conn)
+                    .unwrap()
+                }).await.unwrap();
+                if waiting >= 2 {
+                    break;
+                }
+                rocket::tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            let _ = release_tx.send(());
+        };
+        let ((first, second), ()) = rocket::futures::future::join(rocket::futures::future::join(edit("First tab"), edit("Second tab")), release).await;
+        locker.join().unwrap();
+
+        let mut statuses = [first.status(), second.status()];
+        statuses.sort_by_key(|status| status.code);
+        assert_eq!(statuses, [Status::Ok, Status::PreconditionFailed]);
+        let current = client.get(format!("/api/v1/todos/{}", item.id)).header(bearer(&token)).dispatch().await
+            .into_json::<TodoItem>().await.unwrap();
+        assert_eq!(current.version, 2);
+    }
+
+    // --- Idempotency Key Tests ---
+
+    #[test]
+    fn test_idempotency_keys() {
+        use diesel::prelude::*;
+        use rocket::http::Header;
+        let client = test_client();
+        let (user_id, token) = register_and_login(&client, "testuser_idem");
+        let create = |token: &str, key: &str, description: &str| {
+            client.post("/api/v1/todos")
+                .header(ContentType::JSON)
+                .header(bearer(token))
+                .header(Header::new("Idempotency-Key", key.to_string()))
+                .body(json!({ "description": description }).to_string())
+                .dispatch()
+        };
+
+        // A retry gets the first response again instead of a second item
+        let first = create(&token, "create-1", "Buy milk");
+        assert_eq!(first.status(), Status::Ok);
+        assert_eq!(first.headers().get_one("Idempotent-Replayed"), None);
+        let item = first.into_json::<TodoItem>().unwrap();
+        let retry = create(&token, "create-1", "Buy milk");
+        assert_eq!(retry.status(), Status::Ok);
+        assert_eq!(retry.headers().get_one("Idempotent-Replayed"), Some("true"));
+        assert_eq!(retry.content_type(), Some(ContentType::JSON));
+        assert_eq!(retry.into_json::<TodoItem>().unwrap().id, item.id);
+
+        let response = create(&token, "create-1", "Buy bread");
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(response.into_json::<serde_json::Value>().unwrap()["error"], "idempotency_key_reused");
+        let response = create(&token, &"k".repeat(256), "Buy bread");
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(response.into_json::<serde_json::Value>().unwrap()["error"], "invalid_idempotency_key");
+
+        // Keys belong to their user
+        let (_, other_token) = register_and_login(&client, "testuser_idem_other");
+        let other_item = create(&other_token, "create-1", "Buy milk").into_json::<TodoItem>().unwrap();
+        assert_ne!(other_item.id, item.id);
+        let items = client.get("/api/v1/todos").header(bearer(&token)).dispatch().into_json::<Vec<TodoListItem>>().unwrap();
+        assert_eq!(items.len(), 1);
+
+        // Long bodies are compared in full, not just by the start a fairing can peek at
+        let long = |ending: &str| format!("{}{}", "x".repeat(600), ending);
+        let first = create(&token, "create-long", &long("milk"));
+        assert_eq!(first.status(), Status::Ok);
+        let long_item = first.into_json::<TodoItem>().unwrap();
+        let retry = create(&token, "create-long", &long("milk"));
+        assert_eq!(retry.status(), Status::Ok);
+        assert_eq!(retry.headers().get_one("Idempotent-Replayed"), Some("true"));
+        assert_eq!(retry.content_type(), Some(ContentType::JSON));
+        assert_eq!(retry.into_json::<TodoItem>().unwrap().id, long_item.id);
+        let response = create(&token, "create-long", &long("silk"));
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(response.into_json::<serde_json::Value>().unwrap()["error"], "idempotency_key_reused");
+        let upload = |ending: u8| {
+            let mut contents = vec![b'x'; 1000];
+            contents.push(ending);
+            client.post(format!("/api/v1/todos/{}/attachments", long_item.id))
+                .header(ContentType::new("multipart", "form-data").with_params(("boundary", "X-TODO-BOUNDARY")))
+                .header(bearer(&token))
+                .header(Header::new("Idempotency-Key", "upload-1"))
+                .body(multipart_body("X-TODO-BOUNDARY", "log.txt", "text/plain", &contents))
+                .dispatch()
+        };
+        let attachment = upload(b'a').into_json::<TodoAttachment>().unwrap();
+        let retry = upload(b'a');
+        assert_eq!(retry.headers().get_one("Idempotent-Replayed"), Some("true"));
+        assert_eq!(retry.into_json::<TodoAttachment>().unwrap().id, attachment.id);
+        assert_eq!(upload(b'b').status(), Status::UnprocessableEntity);
+
+        // Deleting again with the key repeats the first answer rather than a 404
+        let delete = || {
+            client.delete(format!("/api/v1/todos/{}", item.id))
+                .header(bearer(&token))
+                .header(Header::new("Idempotency-Key", "delete-1"))
+                .dispatch()
+        };
+        let first = delete().into_string().unwrap();
+        assert_eq!(delete().into_string().unwrap(), first);
+
+        // Routes without a body claim the key even when a long one is sent
+        let padded_delete = || {
+            client.delete(format!("/api/v1/todos/{}", long_item.id))
+                .header(bearer(&token))
+                .header(Header::new("Idempotency-Key", "delete-long"))
+                .body("x".repeat(600))
+                .dispatch()
+        };
+        let first = padded_delete();
+        assert_eq!(first.status(), Status::Ok);
+        let first = first.into_string().unwrap();
+        let retry = padded_delete();
+        assert_eq!(retry.status(), Status::Ok);
+        assert_eq!(retry.headers().get_one("Idempotent-Replayed"), Some("true"));
+        assert_eq!(retry.into_string().unwrap(), first);
+
+        // A key still pending is refused, and an expired one runs again
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let mut conn = pool.get().unwrap();
+        diesel::sql_query("INSERT INTO idempotency_keys (user_id, idempotency_key, request_hash) VALUES ($1, 'pending-1', '')")
+            .bind::<diesel::sql_types::Uuid, _>(user_id)
+            .execute(&mut conn)
+            .unwrap();
+        assert_eq!(create(&token, "pending-1", "Buy eggs").status(), Status::Conflict);
+        diesel::sql_query("UPDATE idempotency_keys SET created_at = created_at - INTERVAL '2 days' WHERE user_id = $1")
+            .bind::<diesel::sql_types::Uuid, _>(user_id)
+            .execute(&mut conn)
+            .unwrap();
+        let response = create(&token, "create-1", "Buy milk");
+        assert_eq!(response.headers().get_one("Idempotent-Replayed"), None);
+        assert_ne!(response.into_json::<TodoItem>().unwrap().id, item.id);
+
+        let spec = client.get(todo_backend::openapi::SPEC_PATH).dispatch().into_json::<serde_json::Value>().unwrap();
+        let parameters = spec["paths"]["/todos"]["post"]["parameters"].as_array().unwrap();
+        assert!(parameters.iter().any(|parameter| parameter["name"] == "Idempotency-Key"));
+    }
+
+    // --- Rate Limiting Tests ---
+
+    #[test]
+    fn test_rate_limits() {
+        use rocket::http::Header;
+        let rocket = todo_backend::rocket_instance();
+        let figment = todo_backend::config::figment()
+            .merge(("rate_limit.user", json!({ "burst": 3, "per_minute": 1 })))
+            .merge(("rate_limit.anonymous", json!({ "burst": 6, "per_minute": 1 })))
+            .merge(("rate_limit.trusted_proxies", ["192.0.2.1"]));
+        // Untracked, so only the requests sent with a bearer token are signed in
+        let client = Client::untracked(rocket.configure(figment)).expect("valid rocket instance");
+
+        let (_, token) = register_and_login(&client, "testuser_ratelimit");
+        for remaining in ["2", "1", "0"] {
+            let response = client.get("/api/v1/todos").header(bearer(&token)).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.headers().get_one("X-RateLimit-Limit"), Some("3"));
+            assert_eq!(response.headers().get_one("X-RateLimit-Remaining"), Some(remaining));
+        }
+        let response = client.get("/api/v1/todos").header(bearer(&token)).dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let retry_after: u64 = response.headers().get_one("Retry-After").unwrap().parse().unwrap();
+        assert!((1..=60).contains(&retry_after));
+        assert_eq!(response.headers().get_one("X-RateLimit-Remaining"), Some("0"));
+        assert_eq!(response.into_json::<serde_json::Value>().unwrap()["error"], "rate_limited");
+
+        // Other users have their own bucket, and paths outside the API are not limited
+        let (_, other_token) = register_and_login(&client, "testuser_ratelimit_other");
+        assert_eq!(client.get("/api/v1/todos").header(bearer(&other_token)).dispatch().status(), Status::Ok);
+        let response = client.get("/healthz").header(bearer(&token)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("X-RateLimit-Limit"), None);
+
+        // Requests without a session are counted per client IP
+        let login = |ip: &str, real_ip: Option<&str>| {
+            let mut request = client.post("/api/v1/auth/login")
+                .remote(format!("{}:4000", ip).parse().unwrap())
+                .header(ContentType::JSON)
+                .body(json!({ "username": "nobody", "password": "wrong" }).to_string());
+            if let Some(real_ip) = real_ip {
+                request = request.header(Header::new("X-Real-IP", real_ip.to_string()));
+            }
+            request.dispatch().status()
+        };
+        for _ in 0..6 {
+            assert_eq!(login("203.0.113.7", None), Status::NotFound);
+        }
+        assert_eq!(login("203.0.113.7", None), Status::TooManyRequests);
+        assert_eq!(login("203.0.113.8", None), Status::NotFound);
+
+        // Only trusted proxies can name the client IP
+        assert_eq!(login("203.0.113.7", Some("198.51.100.1")), Status::TooManyRequests);
+        assert_eq!(login("192.0.2.1", Some("203.0.113.7")), Status::TooManyRequests);
+        assert_eq!(login("192.0.2.1", Some("203.0.113.9")), Status::NotFound);
+
+        // Unknown session tokens are counted against the client IP before being looked up
+        let response = client.get("/api/v1/todos")
+            .remote("203.0.113.7:4000".parse().unwrap())
+            .header(bearer("not-a-session"))
+            .dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+
+        // The API docs are not limited
+        for path in [todo_backend::openapi::DOCS_PATH, todo_backend::openapi::SPEC_PATH] {
+            let response = client.get(path).remote("203.0.113.7:4000".parse().unwrap()).dispatch();
+            assert_ne!(response.status(), Status::TooManyRequests, "{}", path);
+            assert_eq!(response.headers().get_one("X-RateLimit-Limit"), None, "{}", path);
+        }
+
+        let spec = client.get(todo_backend::openapi::SPEC_PATH).dispatch().into_json::<serde_json::Value>().unwrap();
+        assert!(spec["paths"]["/todos"]["get"]["responses"]["429"]["headers"]["Retry-After"].is_object());
+    }
+
+    // --- CORS Tests ---
+
+    #[test]
+    fn test_cors() {
+        use rocket::http::Header;
+        let origin = "https://app.example.com";
+        let rocket = todo_backend::rocket_instance();
+        let figment = todo_backend::config::figment()
+            .merge(("cors.allowed_origins", [origin]))
+            .merge(("cors.allow_credentials", true));
+        let client = Client::tracked(rocket.configure(figment)).expect("valid rocket instance");
+        let (_, token) = register_and_login(&client, "testuser_cors");
+
+        let preflight = |path: &str, origin: &str, method: &str, headers: &str| {
+            client.options(path.to_string())
+                .header(Header::new("Origin", origin.to_string()))
+                .header(Header::new("Access-Control-Request-Method", method.to_string()))
+                .header(Header::new("Access-Control-Request-Headers", headers.to_string()))
+                .dispatch()
+        };
+        let response = preflight("/api/v1/todos", origin, "POST", "content-type, idempotency-key");
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), Some(origin));
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Credentials"), Some("true"));
+        assert!(response.headers().get_one("Access-Control-Allow-Methods").unwrap().contains("POST"));
+        assert_eq!(response.headers().get_one("Access-Control-Max-Age"), Some("600"));
+        let item_path = format!("/api/v1/todos/{}/complete", Uuid::new_v4());
+        assert_eq!(preflight(&item_path, origin, "PUT", "authorization, if-match").status(), Status::NoContent);
+
+        // Other origins, methods and headers are refused
+        for (origin, method, headers) in [
+            ("https://evil.example.com", "POST", "content-type"),
+            (origin, "TRACE", ""),
+            (origin, "POST", "x-not-allowed"),
+        ] {
+            let response = preflight("/api/v1/todos", origin, method, headers);
+            assert_eq!(response.status(), Status::Forbidden, "{} {} {}", origin, method, headers);
+            assert_eq!(response.into_json::<serde_json::Value>().unwrap()["error"], "cors_not_allowed");
+        }
+        let response = preflight("/api/v1/todos", "https://evil.example.com", "GET", "");
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+
+        // Actual requests from an allowed origin can read the custom headers
+        let response = client.get("/api/v1/todos").header(bearer(&token)).header(Header::new("Origin", origin)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), Some(origin));
+        assert_eq!(response.headers().get_one("Vary"), Some("Origin"));
+        let exposed = response.headers().get_one("Access-Control-Expose-Headers").unwrap();
+        assert!(exposed.contains("ETag") && exposed.contains("X-Request-Id"));
+        let response = client.get("/api/v1/todos").header(bearer(&token)).header(Header::new("Origin", "https://evil.example.com")).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+
+        // Without allowed origins there is no CORS at all
+        let client = test_client();
+        let response = client.options("/api/v1/todos")
+            .header(Header::new("Origin", origin))
+            .header(Header::new("Access-Control-Request-Method", "POST"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+
+        let rocket = todo_backend::rocket_instance();
+        let figment = todo_backend::config::figment()
+            .merge(("cors.allowed_origins", ["*"]))
+            .merge(("cors.allow_credentials", true));
+        match Client::tracked(rocket.configure(figment)) {
+            Err(e) => assert!(matches!(e.kind(), rocket::error::ErrorKind::FailedFairings(_))),
+            Ok(_) => panic!("expected credentials with any origin to be refused"),
+        }
+    }
+
+    // --- Security Header Tests ---
+
+    #[test]
+    fn test_security_and_cache_headers() {
+        let client = test_client();
+        let response = client.get("/").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let headers = response.headers();
+        assert!(headers.get_one("Content-Security-Policy").unwrap().contains("frame-ancestors 'none'"));
+        assert_eq!(headers.get_one("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(headers.get_one("X-Frame-Options"), Some("DENY"));
+        assert_eq!(headers.get_one("Referrer-Policy"), Some("strict-origin-when-cross-origin"));
+        assert_eq!(headers.get_one("Strict-Transport-Security"), None);
+        assert_eq!(headers.get_one("Cache-Control"), Some("no-cache"));
+
+        // Assets are linked by digest and cached for good under that URL only
+        let html = response.into_string().unwrap();
+        let script = html.split("src=\"").nth(1).unwrap().split('"').next().unwrap().to_string();
+        let digest = format!("{:x}", Sha256::digest(std::fs::read("static/script.js").unwrap()));
+        assert_eq!(script, format!("/static/script.js?v={}", &digest[..16]));
+        assert!(html.contains("href=\"/static/style.css?v="));
+        let response = client.get(script).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Cache-Control"), Some("public, max-age=31536000, immutable"));
+        assert_eq!(response.headers().get_one("X-Content-Type-Options"), Some("nosniff"));
+        for path in ["/static/script.js", "/static/script.js?v=0123456789abcdef"] {
+            assert_eq!(client.get(path).dispatch().headers().get_one("Cache-Control"), Some("no-cache"), "{}", path);
+        }
+        let response = client.get("/api/v1/todos").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(response.headers().get_one("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(response.headers().get_one("Cache-Control"), None);
+
+        // HSTS and the static directory are configurable
+        let static_dir = std::env::temp_dir().join(format!("todo_static_{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&static_dir).unwrap();
+        std::fs::write(static_dir.join("index.html"), "<script src=\"/static/script.js\"></script>").unwrap();
+        std::fs::write(static_dir.join("script.js"), "// elsewhere").unwrap();
+        let rocket = todo_backend::rocket_instance();
+        let figment = todo_backend::config::figment()
+            .merge(("static_dir", static_dir.to_str().unwrap()))
+            .merge(("security_headers.hsts_max_age_secs", 63072000))
+            .merge(("security_headers.hsts_include_subdomains", true));
+        let client = Client::tracked(rocket.configure(figment)).expect("valid rocket instance");
+        let response = client.get("/").dispatch();
+        assert_eq!(response.headers().get_one("Strict-Transport-Security"), Some("max-age=63072000; includeSubDomains"));
+        let digest = format!("{:x}", Sha256::digest(b"// elsewhere"));
+        assert_eq!(response.into_string().unwrap(), format!("<script src=\"/static/script.js?v={}\"></script>", &digest[..16]));
+        assert_eq!(client.get("/static/script.js").dispatch().into_string().unwrap(), "// elsewhere");
+        std::fs::remove_dir_all(&static_dir).unwrap();
+    }
+
+    // --- Configuration Tests ---
+    #[test]
+    fn test_invalid_config_fails_ignite() {
+        let rocket = todo_backend::rocket_instance();
+        let figment = todo_backend::config::figment()
+            .merge(("bcrypt_cost", 2))
+            .merge(("static_dir", "does/not/exist"));
+        match Client::tracked(rocket.configure(figment)) {
+            Err(e) => assert!(matches!(e.kind(), rocket::error::ErrorKind::FailedFairings(_))),
+            Ok(_) => panic!("expected ignition to fail"),
+        }
+    }
+
+    #[test]
+    fn test_disabled_features_are_not_mounted() {
+        let rocket = todo_backend::rocket_instance();
+        let figment = todo_backend::config::figment()
+            .merge(("features.registration", false))
+            .merge(("features.comments", false));
+        let client = Client::tracked(rocket.configure(figment)).expect("valid rocket instance");
+
+        let response = client.post("/auth/register")
+            .header(ContentType::JSON)
+            .body(json!({ "username": "disabled_feature_user", "password": "password123" }).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client.get(format!("/api/todos/{}/comments", Uuid::new_v4())).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        // Attachments are still enabled, so the route exists and asks for credentials
+        let response = client.get(format!("/api/todos/{}/attachments", Uuid::new_v4())).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    // --- Connection Pool Tests ---
+    #[test]
+    fn test_exhausted_pool_returns_503() {
+        let rocket = todo_backend::rocket_instance();
+        let figment = todo_backend::config::figment()
+            .merge(("pool.max_size", 1))
+            .merge(("pool.connection_timeout_secs", 1));
+        let client = Client::tracked(rocket.configure(figment)).expect("valid rocket instance");
+        let (_, token) = register_and_login(&client, "testuser_pool");
+
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let held = pool.get().unwrap();
+        let response = client.get("/api/todos").header(bearer(&token)).dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert!(response.into_string().unwrap().contains("database_unavailable"));
+
+        drop(held);
+        let response = client.get("/api/todos").header(bearer(&token)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let status: serde_json::Value = client.get("/status/pool").dispatch().into_json().unwrap();
+        assert_eq!(status["max_size"], 1);
+        assert!(status["checkout_failures"].as_u64().unwrap() >= 1);
+        assert!(status["checkouts"].as_u64().unwrap() >= 1);
+    }
+
+    // --- Health Check Tests ---
+    #[test]
+    fn test_health_endpoints() {
+        let client = test_client();
+
+        // Neither endpoint requires authentication
+        let response = client.get("/healthz").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["status"], "ok");
+
+        let response = client.get("/readyz").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["status"], "ok");
+        for component in ["pool", "database", "migrations"] {
+            assert_eq!(body["checks"][component]["status"], "ok", "{} check failed", component);
+            assert!(body["checks"][component]["latency_ms"].is_number());
+        }
+
+        // Not ready when no connection can be checked out
+        let figment = todo_backend::config::figment().merge(("pool.max_size", 1));
+        let client = Client::tracked(todo_backend::rocket_instance().configure(figment)).expect("valid rocket instance");
+        let held = client.rocket().state::<PgPool>().unwrap().get().unwrap();
+        let response = client.get("/readyz").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["status"], "unavailable");
+        assert_eq!(body["checks"]["pool"]["status"], "unavailable");
+        assert_eq!(body["checks"]["database"]["status"], "unavailable");
+        assert!(body["checks"]["pool"].get("error").is_none(), "Readiness leaked the pool error");
+        drop(held);
+    }
+
+    // --- Metrics Tests ---
+    #[test]
+    fn test_metrics_endpoint() {
+        let client = test_client();
+        let (_, token) = register_and_login(&client, "testuser_metrics");
+        let item = client.post("/api/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "Counted" }).to_string())
+            .dispatch()
+            .into_json::<TodoItem>()
+            .unwrap();
+        client.put(format!("/api/todos/{}/complete", item.id)).header(bearer(&token)).dispatch();
+        client.post("/auth/login")
+            .header(ContentType::JSON)
+            .body(json!({ "username": "no_such_user_metrics", "password": "wrong" }).to_string())
+            .dispatch();
+        // Writes through sync count like the rest
+        let offline_id = Uuid::new_v4();
+        client.post("/api/v1/sync")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "mutations": [{ "op": "create", "id": offline_id, "description": "Counted offline" }] }).to_string())
+            .dispatch();
+        let offline = client.get(format!("/api/v1/todos/{}", offline_id)).header(bearer(&token)).dispatch().into_json::<TodoItem>().unwrap();
+        client.post("/api/v1/sync")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "mutations": [
+                { "op": "update", "id": offline_id, "base_version": offline.version, "completed": true },
+            ] }).to_string())
+            .dispatch();
+
+        let response = client.get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type().map(|ct| ct.sub().to_string()), Some("plain".to_string()));
+        let body = response.into_string().unwrap();
+        // Routes are labelled by template, not by the concrete path
+        assert!(body.contains(r#"http_requests_total{method="PUT",route="/api/todos/<item_id_str>/complete",status="2xx"} 1"#));
+        assert!(body.contains(r#"http_request_duration_seconds_bucket{method="POST",route="/api/todos""#));
+        assert!(body.contains("todos_created_total 2"));
+        assert!(body.contains("todos_completed_total 2"));
+        assert!(body.contains(r#"logins_total{outcome="succeeded"} 1"#));
+        assert!(body.contains(r#"logins_total{outcome="failed"} 1"#));
+        assert!(body.contains("db_pool_max_size 10"));
+        assert!(body.contains("db_pool_checkouts_total"));
+
+        // With a token configured, scrapes must present it
+        let figment = todo_backend::config::figment().merge(("metrics.token", "scrape-secret"));
+        let client = Client::tracked(todo_backend::rocket_instance().configure(figment)).expect("valid rocket instance");
+        assert_eq!(client.get("/metrics").dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.get("/metrics").header(bearer("wrong")).dispatch().status(), Status::Unauthorized);
+        assert_eq!(client.get("/metrics").header(bearer("scrape-secret")).dispatch().status(), Status::Ok);
+    }
+
+    // --- Error Response Tests ---
+    #[test]
+    fn test_error_codes_and_validation_details() {
+        let client = test_client();
+        let (_, token) = register_and_login(&client, "testuser_errors");
+
+        let response = client.get("/api/todos/not-a-uuid").header(bearer(&token)).dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["error"], "invalid_uuid");
+        assert!(body["detail"].is_string());
+        // `message` is still sent for clients written against the earlier error bodies
+        assert_eq!(body["message"], body["detail"]);
+
+        let response = client.get(format!("/api/todos/{}", Uuid::new_v4())).header(bearer(&token)).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.into_json::<serde_json::Value>().unwrap()["error"], "todo_not_found");
+
+        // Validation errors name each offending field
+        let response = client.post("/api/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "  " }).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["error"], "validation_failed");
+        assert_eq!(body["fields"][0]["field"], "description");
+        assert_eq!(body["fields"][0]["code"], "required");
+
+        let response = client.post("/auth/register")
+            .header(ContentType::JSON)
+            .body(json!({ "username": "x".repeat(300), "password": "" }).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let body: serde_json::Value = response.into_json().unwrap();
+        let fields: Vec<(&str, &str)> = body["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| (field["field"].as_str().unwrap(), field["code"].as_str().unwrap()))
+            .collect();
+        assert_eq!(fields, vec![("username", "too_long"), ("password", "required")]);
+
+        // Database failures are reported by request id only (Postgres rejects NUL in text)
+        let response = client.post("/api/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "nul \u{0} byte" }).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+        let request_id = response.headers().get_one("X-Request-Id").unwrap().to_string();
+        let body = response.into_string().unwrap();
+        assert!(!body.to_lowercase().contains("database"), "Internal details leaked: {}", body);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"], "internal_error");
+        assert_eq!(body["request_id"], request_id.as_str());
+    }
+
+    // --- Catcher Tests ---
+    #[test]
+    fn test_api_errors_are_problem_details() {
+        let client = test_client();
+
+        // Unknown API paths get JSON, not the HTML page
+        let response = client.get("/api/no-such-thing").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.content_type(), Some(ContentType::new("application", "problem+json")));
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["type"], "about:blank");
+        assert_eq!(body["title"], "Not Found");
+        assert_eq!(body["status"], 404);
+        assert_eq!(body["instance"], "/api/no-such-thing");
+        assert_eq!(body["error"], "not_found");
+
+        // Browser routes keep the HTML page
+        let response = client.get("/no-such-page").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+
+        // The guard's specific reason reaches the client
+        let response = client.get("/api/todos").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(response.into_json::<serde_json::Value>().unwrap()["error"], "missing_token");
+        let response = client.get("/api/todos").header(bearer("not-a-uuid")).dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(response.into_json::<serde_json::Value>().unwrap()["error"], "invalid_token_format");
+
+        // Syntax errors in JSON bodies are 400s, bodies of the wrong shape 422s
+        let (_, token) = register_and_login(&client, "testuser_catchers");
+        let response = client.post("/api/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body("{ \"description\": ")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert_eq!(response.into_json::<serde_json::Value>().unwrap()["error"], "bad_request");
+        let response = client.post("/api/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "title": "no description" }).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["error"], "invalid_body");
+        assert_eq!(body["status"], 422);
+
+        // Errors returned by handlers use the same format
+        let response = client.get(format!("/api/todos/{}", Uuid::new_v4())).header(bearer(&token)).dispatch();
+        assert_eq!(response.content_type(), Some(ContentType::new("application", "problem+json")));
+        let body: serde_json::Value = response.into_json().unwrap();
+        assert_eq!(body["status"], 404);
+        assert_eq!(body["error"], "todo_not_found");
+    }
+
+    // --- OpenAPI Tests ---
+    // Every route mounted under /api/v1 must be documented, and every
+    // documented operation must exist, so the spec can't drift from the code.
+    #[test]
+    fn test_openapi_spec_matches_routes() {
+        let client = test_client();
+
+        let response = client.get(todo_backend::openapi::SPEC_PATH).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let spec: serde_json::Value = response.into_json().unwrap();
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+
+        let mut documented = std::collections::BTreeSet::new();
+        for (path, operations) in spec["paths"].as_object().unwrap() {
+            for method in operations.as_object().unwrap().keys() {
+                documented.insert(format!("{} {}", method.to_uppercase(), path));
+            }
+        }
+
+        assert_eq!(spec["servers"][0]["url"], todo_backend::versioning::API_V1);
+        let mut mounted = std::collections::BTreeSet::new();
+        for route in client.rocket().routes() {
+            let path = route.uri.path().to_string();
+            if let Some(path) = path.strip_prefix(todo_backend::versioning::API_V1) {
+                // Rocket's `<param>` is OpenAPI's `{param}`
+                mounted.insert(format!("{} {}", route.method, path.replace('<', "{").replace('>', "}")));
+            }
+        }
+        assert_eq!(documented, mounted);
+
+        // The models clients need are published as schemas
+        for schema in ["TodoItem", "UserInfo", "LoginResponse", "ErrorDetail", "Problem"] {
+            assert!(spec["components"]["schemas"][schema].is_object(), "{} schema missing", schema);
+        }
+        let list_params = spec["paths"]["/todos"]["get"]["parameters"].as_array().unwrap();
+        assert!(list_params.iter().any(|param| param["name"] == "completed" && param["in"] == "query"));
+
+        // The Swagger UI is bundled, not loaded from a CDN
+        let response = client.get(format!("{}/", todo_backend::openapi::DOCS_PATH)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+    }
+
+    // --- API Versioning Tests ---
+    #[test]
+    fn test_versioned_and_legacy_paths() {
+        let client = test_client();
+        let (_, token) = register_and_login(&client, "testuser_versioning");
+
+        // The canonical paths are not deprecated
+        let response = client.post("/api/v1/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "Versioned" }).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers().get_one("Deprecation").is_none());
+        let item = response.into_json::<TodoItem>().unwrap();
+        let response = client.get(format!("/api/v1/todos/{}", item.id)).header(bearer(&token)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers().get_one("Sunset").is_none());
+
+        // The unversioned paths still work but say so, and point at their successor
+        let response = client.get(format!("/api/todos/{}", item.id)).header(bearer(&token)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers().get_one("Deprecation").unwrap().starts_with('@'));
+        assert!(response.headers().get_one("Sunset").unwrap().ends_with(" GMT"));
+        assert_eq!(
+            response.headers().get_one("Link"),
+            Some(format!("</api/v1/todos/{}>; rel=\"successor-version\"", item.id).as_str())
+        );
+
+        // The search endpoint is an alias of the filtered listing, in both versions
+        for path in ["/api/v1/todos/search?description=versioned", "/api/todos/search?description=versioned"] {
+            let response = client.get(path).header(bearer(&token)).dispatch();
+            assert_ne!(response.status(), Status::TooManyRequests, "{}", path);
+            let found = response.into_json::<Vec<TodoListItem>>().unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].item.id, item.id);
+        }
+
+        // Endpoints added after versioning have no unversioned alias
+        for path in ["/api/sync", "/api/events", "/api/ws"] {
+            assert_eq!(client.get(path).header(bearer(&token)).dispatch().status(), Status::NotFound, "{}", path);
+        }
+
+        // The aliases can be switched off
+        let figment = todo_backend::config::figment().merge(("legacy_api.enabled", false));
+        let client = Client::tracked(todo_backend::rocket_instance().configure(figment)).expect("valid rocket instance");
+        assert_eq!(client.get("/api/todos").header(bearer(&token)).dispatch().status(), Status::NotFound);
+        assert_eq!(client.get("/api/v1/todos").header(bearer(&token)).dispatch().status(), Status::Ok);
+
+        // Auth routes moved under /api/v1/auth
+        let client = test_client();
+        let response = client.post("/auth/logout").dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(response.headers().get_one("Link"), Some("</api/v1/auth/logout>; rel=\"successor-version\""));
+    }
+
+    // --- Request ID Tests ---
+    #[test]
+    fn test_request_id_header() {
+        let client = test_client();
+
+        // Generated when the client sends none
+        let response = client.get("/healthz").dispatch();
+        let generated = response.headers().get_one("X-Request-Id").expect("missing X-Request-Id");
+        assert!(Uuid::parse_str(generated).is_ok());
+
+        // A well-formed id from the client (e.g. a proxy) is propagated
+        let response = client.get("/api/todos")
+            .header(rocket::http::Header::new("X-Request-Id", "edge-4f1c.7"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(response.headers().get_one("X-Request-Id"), Some("edge-4f1c.7"));
+
+        // Anything else is replaced rather than echoed into logs and headers
+        let response = client.get("/healthz")
+            .header(rocket::http::Header::new("X-Request-Id", "bad id\"; injected"))
+            .dispatch();
+        let replaced = response.headers().get_one("X-Request-Id").unwrap();
+        assert!(Uuid::parse_str(replaced).is_ok());
+    }
+
+    // --- Event Stream Tests ---
+    // Reads server-sent events off a streaming response, line by line
+    struct SseReader<'c> {
+        response: rocket::local::asynchronous::LocalResponse<'c>,
+        buffered: String,
+        heartbeats: usize,
+    }
+
+    impl<'c> SseReader<'c> {
+        fn new(response: rocket::local::asynchronous::LocalResponse<'c>) -> Self {
+            SseReader { response, buffered: String::new(), heartbeats: 0 }
+        }
+
+        async fn next_line(&mut self) -> String {
+            use rocket::tokio::io::AsyncReadExt;
+            loop {
+                if let Some(end) = self.buffered.find('\n') {
+                    let line = self.buffered[..end].to_string();
+                    self.buffered.drain(..=end);
+                    return line;
+                }
+                let mut chunk = [0u8; 1024];
+                let read = self.response.read(&mut chunk).await.unwrap();
+                assert!(read > 0, "event stream ended");
+                self.buffered.push_str(std::str::from_utf8(&chunk[..read]).unwrap());
+            }
+        }
+
+        // The next (id, event, data) triple, counting the heartbeats before it;
+        // bounded so a missing event fails the test instead of hanging it
+        async fn next_event(&mut self) -> (Option<u64>, String, String) {
+            let read = async {
+                let (mut id, mut event, mut data) = (None, String::new(), String::new());
+                loop {
+                    let line = self.next_line().await;
+                    match line.split_once(':') {
+                        Some(("", _)) => self.heartbeats += 1,
+                        Some(("id", value)) => id = value.trim().parse().ok(),
+                        Some(("event", value)) => event = value.trim().to_string(),
+                        Some(("data", value)) => data.push_str(value),
+                        _ if line.is_empty() && !event.is_empty() => return (id, event, data),
+                        _ => {}
+                    }
+                }
+            };
+            rocket::tokio::time::timeout(std::time::Duration::from_secs(10), read)
+                .await
+                .expect("no event within 10s")
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_event_stream() {
+        use rocket::local::asynchronous::Client as AsyncClient;
+
+        // In-process delivery; test_event_stream_across_instances covers the listener
+        let figment = todo_backend::config::figment()
+            .merge(("events.heartbeat_secs", 1))
+            .merge(("events.listen", false));
+        let client = AsyncClient::tracked(todo_backend::rocket_instance().configure(figment)).await
+            .expect("valid rocket instance");
+        assert_eq!(client.get("/api/v1/events").dispatch().await.status(), Status::Unauthorized);
+
+        let mut tokens = Vec::new();
+        for prefix in ["testuser_events_a", "testuser_events_b"] {
+            let username = format!("{}_{}", prefix, Uuid::new_v4());
+            let credentials = json!({ "username": username, "password": "password123" }).to_string();
+            client.post("/api/v1/auth/register").header(ContentType::JSON).body(&credentials).dispatch().await;
+            let login = client.post("/api/v1/auth/login").header(ContentType::JSON).body(&credentials).dispatch().await;
+            tokens.push(login.into_json::<LoginResponse>().await.unwrap().session_token);
+        }
+        let (token_a, token_b) = (&tokens[0], &tokens[1]);
+
+        let response = client.get("/api/v1/events").header(bearer(token_a)).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::EventStream));
+        let mut events_a = SseReader::new(response);
+        let mut events_b = SseReader::new(client.get("/api/v1/events").header(bearer(token_b)).dispatch().await);
+
+        let item = client.post("/api/v1/todos")
+            .header(ContentType::JSON)
+            .header(bearer(token_a))
+            .body(json!({ "description": "Streamed item" }).to_string())
+            .dispatch().await
+            .into_json::<TodoItem>().await.unwrap();
+        let (created_id, event, data) = events_a.next_event().await;
+        assert_eq!(event, "created");
+        assert_eq!(serde_json::from_str::<TodoItem>(&data).unwrap(), item);
+
+        client.put(format!("/api/v1/todos/{}/complete", item.id)).header(bearer(token_a)).dispatch().await;
+        let (completed_id, event, data) = events_a.next_event().await;
+        assert_eq!(event, "completed");
+        assert!(serde_json::from_str::<TodoItem>(&data).unwrap().completed);
+        assert!(completed_id > created_id);
+
+        // Completing through sync is announced the same way
+        let synced = client.post("/api/v1/todos")
+            .header(ContentType::JSON)
+            .header(bearer(token_a))
+            .body(json!({ "description": "Completed offline" }).to_string())
+            .dispatch().await
+            .into_json::<TodoItem>().await.unwrap();
+        assert_eq!(events_a.next_event().await.1, "created");
+        client.post("/api/v1/sync")
+            .header(ContentType::JSON)
+            .header(bearer(token_a))
+            .body(json!({ "mutations": [
+                { "op": "update", "id": synced.id, "base_version": synced.version, "completed": true },
+            ] }).to_string())
+            .dispatch().await;
+        let (_, event, data) = events_a.next_event().await;
+        assert_eq!(event, "completed");
+        assert_eq!(serde_json::from_str::<TodoItem>(&data).unwrap().id, synced.id);
+
+        // Other users' streams only carry their own items
+        let other = client.post("/api/v1/todos")
+            .header(ContentType::JSON)
+            .header(bearer(token_b))
+            .body(json!({ "description": "Someone else's item" }).to_string())
+            .dispatch().await
+            .into_json::<TodoItem>().await.unwrap();
+        let (_, event, data) = events_b.next_event().await;
+        assert_eq!(event, "created");
+        assert_eq!(serde_json::from_str::<TodoItem>(&data).unwrap().id, other.id);
+
+        // Idle streams get heartbeats
+        rocket::tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        client.delete(format!("/api/v1/todos/{}", other.id)).header(bearer(token_b)).dispatch().await;
+        let (_, event, _) = events_b.next_event().await;
+        assert_eq!(event, "deleted");
+        assert!(events_b.heartbeats > 0);
+
+        // Reconnecting with Last-Event-ID replays what was missed
+        let mut resumed = SseReader::new(client.get("/api/v1/events")
+            .header(bearer(token_a))
+            .header(rocket::http::Header::new("Last-Event-ID", created_id.unwrap().to_string()))
+            .dispatch().await);
+        let (id, event, _) = resumed.next_event().await;
+        assert_eq!((id, event.as_str()), (completed_id, "completed"));
+
+        // ...unless it is not a recent event id
+        let mut stale = SseReader::new(client.get("/api/v1/events")
+            .header(bearer(token_a))
+            .header(rocket::http::Header::new("Last-Event-ID", "999999999"))
+            .dispatch().await);
+        assert_eq!(stale.next_event().await.1, "resync");
+    }
+
+    // Writes through one instance reach streams on another through Postgres
+    // NOTIFY, and a lost listener connection is re-established
+    #[rocket::async_test]
+    async fn test_event_stream_across_instances() {
+        use diesel::prelude::*;
+        use rocket::local::asynchronous::Client as AsyncClient;
+        use todo_backend::events::EventBus;
+
+        let instance = || async {
+            let figment = todo_backend::config::figment().merge(("events.reconnect_backoff_ms", 50));
+            AsyncClient::tracked(todo_backend::rocket_instance().configure(figment)).await
+                .expect("valid rocket instance")
+        };
+        let (writer, reader) = (instance().await, instance().await);
+
+        let username = format!("testuser_notify_{}", Uuid::new_v4());
+        let credentials = json!({ "username": username, "password": "password123" }).to_string();
+        writer.post("/api/v1/auth/register").header(ContentType::JSON).body(&credentials).dispatch().await;
+        let token = writer.post("/api/v1/auth/login").header(ContentType::JSON).body(&credentials).dispatch().await
+            .into_json::<LoginResponse>().await.unwrap()
+            .session_token;
+        let add_item = |description: String| {
+            let request = writer.post("/api/v1/todos")
+                .header(ContentType::JSON)
+                .header(bearer(&token))
+                .body(json!({ "description": description }).to_string());
+            async move { request.dispatch().await.into_json::<TodoItem>().await.unwrap() }
+        };
+
+        let mut events = SseReader::new(reader.get("/api/v1/events").header(bearer(&token)).dispatch().await);
+        let item = add_item("Written elsewhere".to_string()).await;
+        let (_, event, data) = events.next_event().await;
+        assert_eq!(event, "created");
+        assert_eq!(serde_json::from_str::<TodoItem>(&data).unwrap(), item);
+
+        // Too long for a NOTIFY payload, so the listener loads it
+        let long = add_item("x".repeat(10_000)).await;
+        let (_, event, data) = events.next_event().await;
+        assert_eq!(event, "created");
+        assert_eq!(serde_json::from_str::<TodoItem>(&data).unwrap(), long);
+
+        // Kill the reader's listener connection; its streams are told to
+        // start over once it is back
+        let listener_name = reader.rocket().state::<EventBus>().unwrap().listener_name();
+        let pool = reader.rocket().state::<PgPool>().unwrap().clone();
+        rocket::tokio::task::spawn_blocking(move || {
+            let mut conn = pool.get().unwrap();
+            diesel::sql_query("SELECT pg_terminate_backend(pid) FROM pg_stat_activity WHERE application_name = $1")
+                .bind::<diesel::sql_types::Text, _>(listener_name)
+                .execute(&mut conn)
+                .unwrap()
+        }).await.unwrap();
+        assert_eq!(events.next_event().await.1, "resync");
+
+        writer.put(format!("/api/v1/todos/{}/complete", item.id)).header(bearer(&token)).dispatch().await;
+        let (_, event, data) = events.next_event().await;
+        assert_eq!(event, "completed");
+        assert_eq!(serde_json::from_str::<TodoItem>(&data).unwrap().id, item.id);
+
+        // The listener goes away with its server
+        let writer_listener = writer.rocket().state::<EventBus>().unwrap().listener_name();
+        writer.rocket().shutdown().notify();
+        let pool = reader.rocket().state::<PgPool>().unwrap().clone();
+        let mut closed = false;
+        for _ in 0..50 {
+            let (pool, name) = (pool.clone(), writer_listener.clone());
+            let open = rocket::tokio::task::spawn_blocking(move || {
+                let mut conn = pool.get().unwrap();
+                diesel::sql_query("SELECT pid FROM pg_stat_activity WHERE application_name = $1")
+                    .bind::<diesel::sql_types::Text, _>(name)
+                    .execute(&mut conn)
+                    .unwrap()
+            }).await.unwrap();
+            if open == 0 {
+                closed = true;
+                break;
+            }
+            rocket::tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(closed, "Event listener outlived the server");
+    }
+
+    // --- WebSocket Tests ---
+    type WebSocket = tokio_tungstenite::WebSocketStream<rocket::tokio::net::TcpStream>;
+
+    async fn ws_send(socket: &mut WebSocket, message: serde_json::Value) {
+        use rocket::futures::SinkExt;
+        socket.send(tokio_tungstenite::tungstenite::Message::Text(message.to_string())).await.unwrap();
+    }
+
+    // The next JSON message, or `{"close": <code>}` once the server closes
+    async fn ws_receive(socket: &mut WebSocket) -> serde_json::Value {
+        use rocket::futures::StreamExt;
+        use tokio_tungstenite::tungstenite::Message;
+        loop {
+            let message = rocket::tokio::time::timeout(std::time::Duration::from_secs(10), socket.next())
+                .await
+                .expect("no message within 10s");
+            match message.expect("socket ended").unwrap() {
+                Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                Message::Close(frame) => return json!({ "close": frame.map(|frame| u16::from(frame.code)) }),
+                _ => {}
+            }
+        }
+    }
+
+    // Commands and change events share one connection to a running server
+    #[rocket::async_test]
+    async fn test_websocket_commands_and_events() {
+        use rocket::fairing::AdHoc;
+        use rocket::local::asynchronous::Client as AsyncClient;
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+        use tokio_tungstenite::tungstenite::Error as WsError;
+
+        // Built first, as it loads .env for the figment
+        let server = todo_backend::rocket_instance();
+        let figment = todo_backend::config::figment()
+            .merge(("port", 0))
+            .merge(("shutdown.ctrlc", false))
+            .merge(("rate_limit.user", json!({ "burst": 20, "per_minute": 1 })));
+        let (launched, port) = rocket::tokio::sync::oneshot::channel();
+        let server = server
+            .configure(figment.clone())
+            .attach(AdHoc::on_liftoff("Report Port", move |rocket| Box::pin(async move {
+                let _ = launched.send((rocket.config().port, rocket.shutdown()));
+            })));
+        rocket::tokio::spawn(server.launch());
+        let (port, shutdown) = port.await.unwrap();
+
+        // Sessions live in the database, so any instance can issue them
+        let client = AsyncClient::untracked(todo_backend::rocket_instance().configure(figment)).await
+            .expect("valid rocket instance");
+        let sign_up = |name: &str| {
+            let credentials = json!({ "username": format!("{}_{}", name, Uuid::new_v4()), "password": "password123" }).to_string();
+            let client = &client;
+            async move {
+                client.post("/api/v1/auth/register").header(ContentType::JSON).body(&credentials).dispatch().await;
+                client.post("/api/v1/auth/login").header(ContentType::JSON).body(&credentials).dispatch().await
+                    .into_json::<LoginResponse>().await.unwrap()
+                    .session_token
+            }
+        };
+        let token = sign_up("testuser_ws").await;
+
+        let connect = |token: Option<&str>, origin: Option<String>| {
+            let mut request = format!("ws://127.0.0.1:{}/api/v1/ws", port).into_client_request().unwrap();
+            if let Some(token) = token {
+                request.headers_mut().insert("Authorization", format!("Bearer {}", token).parse().unwrap());
+            }
+            if let Some(origin) = origin {
+                request.headers_mut().insert("Origin", origin.parse().unwrap());
+            }
+            async move {
+                let stream = rocket::tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                tokio_tungstenite::client_async(request, stream).await.map(|(socket, _)| socket)
+            }
+        };
+        let rejected_with = |result: Result<WebSocket, WsError>| match result {
+            Err(WsError::Http(response)) => response.status().as_u16(),
+            Ok(_) => panic!("handshake succeeded"),
+            Err(e) => panic!("handshake failed: {}", e),
+        };
+        assert_eq!(rejected_with(connect(None, None).await), 401);
+        assert_eq!(rejected_with(connect(Some(&token), Some("https://elsewhere.example".into())).await), 403);
+        // Plain HTTP requests are refused too
+        let response = client.get("/api/v1/ws").header(bearer(&token)).dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let mut socket = connect(Some(&token), Some(format!("http://127.0.0.1:{}", port))).await.unwrap();
+        ws_send(&mut socket, json!({ "type": "subscribe", "id": 1 })).await;
+        assert_eq!(ws_receive(&mut socket).await, json!({ "type": "result", "id": 1, "result": [] }));
+
+        // The reply and the event for the new item may come in either order
+        ws_send(&mut socket, json!({ "type": "create", "id": "a", "description": "Over the socket" })).await;
+        let (first, second) = (ws_receive(&mut socket).await, ws_receive(&mut socket).await);
+        let (result, event) = if first["type"] == "result" { (first, second) } else { (second, first) };
+        assert_eq!(result["id"], "a");
+        let item = serde_json::from_value::<TodoItem>(result["result"].clone()).unwrap();
+        assert_eq!(item.description, "Over the socket");
+        assert_eq!(event["type"], "event");
+        assert_eq!(event["event"], "created");
+        assert_eq!(serde_json::from_value::<TodoItem>(event["item"].clone()).unwrap(), item);
+
+        // Changes made through other instances are pushed as well
+        client.put(format!("/api/v1/todos/{}", item.id))
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "Renamed over REST" }).to_string())
+            .dispatch().await;
+        let event = ws_receive(&mut socket).await;
+        assert_eq!((event["event"].as_str(), event["item"]["description"].as_str()), (Some("updated"), Some("Renamed over REST")));
+
+        ws_send(&mut socket, json!({ "type": "unsubscribe", "id": 2 })).await;
+        assert_eq!(ws_receive(&mut socket).await["id"], 2);
+        // A version works like If-Match; the item was renamed since it was created
+        ws_send(&mut socket, json!({ "type": "complete", "id": 3, "item_id": item.id.to_string(), "version": item.version })).await;
+        let reply = ws_receive(&mut socket).await;
+        assert_eq!((reply["id"].as_i64(), reply["error"]["error"].as_str()), (Some(3), Some("precondition_failed")));
+        ws_send(&mut socket, json!({ "type": "complete", "id": 3, "item_id": item.id.to_string(), "version": item.version + 1 })).await;
+        let reply = ws_receive(&mut socket).await;
+        assert_eq!(reply["id"], 3);
+        assert_eq!(reply["result"]["completed"], true);
+        assert!(reply["result"]["undo_token"].is_string());
+
+        // Errors carry the same codes as the REST API
+        ws_send(&mut socket, json!({ "type": "delete", "id": 4, "item_id": "not-a-uuid" })).await;
+        let reply = ws_receive(&mut socket).await;
+        assert_eq!((reply["type"].as_str(), reply["id"].as_i64()), (Some("error"), Some(4)));
+        assert_eq!(reply["error"]["error"], "invalid_uuid");
+        ws_send(&mut socket, json!({ "type": "explode", "id": 5 })).await;
+        assert_eq!(ws_receive(&mut socket).await["error"]["error"], "invalid_message");
+        {
+            use rocket::futures::SinkExt;
+            socket.send(tokio_tungstenite::tungstenite::Message::Text("{".into())).await.unwrap();
+        }
+        let reply = ws_receive(&mut socket).await;
+        assert_eq!((reply["id"].clone(), reply["error"]["error"].as_str()), (serde_json::Value::Null, Some("invalid_message")));
+
+        // Logging out ends the socket's authorization too
+        client.post("/api/v1/auth/logout")
+            .cookie(rocket::http::Cookie::new("session_token", token.clone()))
+            .dispatch().await;
+        ws_send(&mut socket, json!({ "type": "delete", "id": 6, "item_id": item.id.to_string() })).await;
+        let reply = ws_receive(&mut socket).await;
+        assert_eq!((reply["id"].as_i64(), reply["error"]["error"].as_str()), (Some(6), Some("invalid_token")));
+        assert_eq!(ws_receive(&mut socket).await, json!({ "close": 1008 }));
+
+        // Commands count towards the user's rate limit, after the handshake
+        let mut socket = connect(Some(&sign_up("testuser_ws_limited").await), None).await.unwrap();
+        for id in 0..19 {
+            ws_send(&mut socket, json!({ "type": "unsubscribe", "id": id })).await;
+            assert_eq!(ws_receive(&mut socket).await["type"], "result");
+        }
+        ws_send(&mut socket, json!({ "type": "unsubscribe", "id": 19 })).await;
+        let reply = ws_receive(&mut socket).await;
+        assert_eq!((reply["id"].as_i64(), reply["error"]["error"].as_str()), (Some(19), Some("rate_limited")));
+
+        shutdown.notify();
+    }
+
+    // --- Concurrency Tests ---
+    // Database work runs on the blocking pool, so a request stuck in Postgres
+    // must not hold up other requests. The async test runtime has a single
+    // worker thread, which a blocking handler would stall completely.
+    #[rocket::async_test]
+    async fn test_blocked_query_does_not_stall_other_requests() {
+        use diesel::prelude::*;
+        use diesel::sql_types::{BigInt, Integer};
+        use rocket::local::asynchronous::Client as AsyncClient;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let client = AsyncClient::tracked(todo_backend::rocket_instance()).await.expect("valid rocket instance");
+        let username = format!("testuser_concurrency_{}", Uuid::new_v4());
+        let credentials = json!({ "username": username, "password": "password123" }).to_string();
+        client.post("/auth/register").header(ContentType::JSON).body(&credentials).dispatch().await;
+        let token = client.post("/auth/login").header(ContentType::JSON).body(&credentials).dispatch().await
+            .into_json::<LoginResponse>().await.unwrap()
+            .session_token;
+        let item = client.post("/api/todos")
+            .header(ContentType::JSON)
+            .header(bearer(&token))
+            .body(json!({ "description": "Locked item" }).to_string())
+            .dispatch().await
+            .into_json::<TodoItem>().await.unwrap();
+
+        // Hold a row lock from another connection so completing the item waits in Postgres
+        let pool = client.rocket().state::<PgPool>().unwrap().clone();
+        let locker_pool = pool.clone();
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let locker = std::thread::spawn(move || {
+            let mut conn = locker_pool.get().unwrap();
+            conn.transaction::<_, diesel::result::Error, _>(|conn| {
+                diesel::sql_query("SELECT id FROM todo_items WHERE id = $1 FOR UPDATE")
+                    .bind::<diesel::sql_types::Uuid, _>(item.id)
+                    .execute(conn)?;
+                let pid = diesel::select(diesel::dsl::sql::<Integer>("pg_backend_pid()")).get_result::<i32>(conn)?;
+                locked_tx.send(pid).unwrap();
+                // Bounded, so a regression fails the assertions instead of hanging
+                let _ = release_rx.recv_timeout(Duration::from_secs(5));
+                Ok(())
+            }).unwrap();
+        });
+        let locker_pid = locked_rx.recv().unwrap();
+
+        let completed = AtomicBool::new(false);
+        let blocked = async {
+            let response = client.put(format!("/api/todos/{}/complete", item.id)).header(bearer(&token)).dispatch().await;
+            completed.store(true, Ordering::SeqCst);
+            response.status()
+        };
+        let others = async {
+            // Wait until the complete request is actually waiting on the lock
+            while !completed.load(Ordering::SeqCst) {
+                let pool = pool.clone();
+                let waiting = rocket::tokio::task::spawn_blocking(move || {
+                    let mut conn = pool.get().unwrap();
+                    diesel::select(
+                        diesel::dsl::sql::<BigInt>("(SELECT count(*) FROM pg_stat_activity WHERE ")
+                            .bind::<Integer, _>(locker_pid)
+                            .sql(" = ANY(pg_blocking_pids(pid)))"),
+                    )
+                    .get_result::<i64>(&mut conn)
+                    .unwrap()
+                }).await.unwrap();
+                if waiting > 0 {
+                    break;
+                }
+                rocket::tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+
+            let requests = (0..10).map(|_| client.get("/api/todos/count").header(bearer(&token)).dispatch());
+            let responses = rocket::futures::future::join_all(requests).await;
+            let finished_first = !completed.load(Ordering::SeqCst);
+            let _ = release_tx.send(());
+            (responses.iter().all(|response| response.status() == Status::Ok), finished_first)
+        };
+
+        let (blocked_status, (all_ok, finished_first)) = rocket::futures::future::join(blocked, others).await;
+        locker.join().unwrap();
+        assert_eq!(blocked_status, Status::Ok);
+        assert!(all_ok);
+        assert!(finished_first, "other requests had to wait for the blocked query");
+    }
 }